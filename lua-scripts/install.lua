@@ -28,7 +28,7 @@ if err ~= nil then
     return
 end
 
-for _, v in ipairs({ "update.lua", "install.lua", "websocket.lua", "move.lua", "inventory.lua", "task.lua", "util.lua", "test_task.lua"}) do
+for _, v in ipairs({ "update.lua", "install.lua", "websocket.lua", "sandbox.lua", "move.lua", "inventory.lua", "task.lua", "util.lua", "test_task.lua", "test_sandbox.lua"}) do
     err = download_file(remote_url .. "/" .. v, "/" .. v)
     if err ~= nil then
         error(err)
@@ -39,7 +39,7 @@ end
 fs.delete("/tasks")
 fs.makeDir("/tasks")
 
-for _, v in ipairs({ "fell.lua", "fell_inter.lua", "first_tree.lua", "refuel_logs.lua" }) do
+for _, v in ipairs({ "fell.lua", "fell_inter.lua", "first_tree.lua", "refuel_logs.lua", "refuel.lua", "inspect.lua", "plant.lua", "deposit.lua", "inspect_around.lua", "place_torch.lua", "place_down.lua", "restock.lua", "craft.lua" }) do
     err = download_file(remote_url .. "/tasks/" .. v, "/tasks/" .. v)
     if err ~= nil then
         error(err)
@@ -51,4 +51,27 @@ function inventory:update()
     return delta
 end
 
+--- All 16 slots, empty ones as an empty object. Also resets what update() compares against
+function inventory:full()
+    local full = {}
+    for i=1,16 do
+        local c = turtle.getItemCount(i)
+        if c > 0 then
+            local content = turtle.getItemDetail(i, false)
+            self.slots[i] = {
+                name=content.name,
+                count=c
+            }
+            full[i] = {
+                n=content.name,
+                c=c
+            }
+        else
+            self.slots[i] = nil
+            full[i] = self.noChange
+        end
+    end
+    return full
+end
+
 return inventory
@@ -273,7 +273,7 @@ do
             if type(f) == "function" then
                 local success, err = f(pos, k)
                 if not success then
-                    return false, "Could not complete move "..c..k.." due to \""..err.."\"", i
+                    return false, "Could not complete move "..c..k.." due to \""..err.."\"", i, err
                 end
             else
                 return false, "Unknown command "..c, i
@@ -0,0 +1,40 @@
+-- The environment eval code runs in: the basics of lua and only the APIs the server allows,
+-- either whole ("turtle") or single functions ("os.getComputerLabel"). Tables are copied, so the
+-- code can not change what everything else on the turtle uses.
+
+local function copy(source)
+    local new = {}
+    for k, v in pairs(source) do
+        new[k] = v
+    end
+    return new
+end
+
+return function(allow)
+    local env = {
+        print=print, pairs=pairs, ipairs=ipairs, next=next, select=select, type=type,
+        tostring=tostring, tonumber=tonumber, pcall=pcall, error=error, unpack=table.unpack,
+        math=copy(math), string=copy(string), table=copy(table),
+    }
+    for _, name in ipairs(allow or {}) do
+        local source, target = _ENV, env
+        local parts = {}
+        for part in string.gmatch(name, "[^.]+") do
+            table.insert(parts, part)
+        end
+        for i, part in ipairs(parts) do
+            source = type(source) == "table" and source[part] or nil
+            if source == nil then
+                break
+            elseif i == #parts then
+                target[part] = type(source) == "table" and copy(source) or source
+            else
+                if type(target[part]) ~= "table" then
+                    target[part] = {}
+                end
+                target = target[part]
+            end
+        end
+    end
+    return env
+end
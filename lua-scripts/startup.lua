@@ -2,23 +2,37 @@ dofile("update.lua")
 
 local conf_path = ".cfg"
 
--- TODO make id functional
-local id = 1
+--local remote = "replicca.mc.nielsoverkamp.com/api"
+local remote = "localhost:17576"
+
+local id, secret
 if fs.exists(conf_path) then
     local f = fs.open(conf_path, "r")
-    id = f.readLine()
-    id = tonumber(id)
+    id = tonumber(f.readLine())
+    secret = f.readLine()
+    f.close()
 end
 
-if id == nil then
+-- An id without a secret is from before the server handed out secrets, it won't be accepted
+if id == nil or secret == nil then
+    id, secret = nil, nil
     local r = http.get("http://"..remote .. "/newId")
-    id = r.readAll()
-    id = tonumber(id)
+    if r ~= nil then
+        id = tonumber(r.readLine())
+        secret = r.readLine()
+        r.close()
+    end
+    if id ~= nil and secret ~= nil then
+        local f = fs.open(conf_path, "w")
+        f.writeLine(tostring(id))
+        f.writeLine(secret)
+        f.close()
+    end
 end
 
-if id == nil then
+if id == nil or secret == nil then
     error("Could not find local id file and could not get new one from remote")
     return
 end
 
-require("websocket")()
+require("websocket")(id, secret)
@@ -48,17 +48,44 @@ function task:wrap(api)
     return wrapped
 end
 
-function task:task_question(q)
-    local mid = self:send_event("task_question", q)
+--- Asks the server, q names the question and payload tells what the answer depends on.
+--- The answer is typed per question, see the task definitions on the server.
+function task:task_question(q, payload)
+    local mid = self:send_event("task_question", { q = q, b = payload })
     return self:pull_event("task_answer",  mid)
 end
 
 local t = require("move")
 local wt = task:wrap(t)
 
+--- Inspects the block in front ("f"), above ("u") or below ("d") and reports it to the server,
+--- so it ends up in the world map. Returns the same as turtle.inspect
+function task:inspect(pos, side)
+    local coordinate = { x = pos.coordinate.x, y = pos.coordinate.y, z = pos.coordinate.z }
+    local isBlock, res
+    if side == "u" then
+        isBlock, res = turtle.inspectUp()
+        coordinate.y = coordinate.y + 1
+    elseif side == "d" then
+        isBlock, res = turtle.inspectDown()
+        coordinate.y = coordinate.y - 1
+    else
+        isBlock, res = turtle.inspect()
+        local ahead = { coordinate = coordinate, direction = pos.direction }
+        t.move_pos_horizontal(ahead, 1)
+    end
+    local name
+    if isBlock then
+        name = res.name
+    end
+    self:send_event("block_update", { coordinate = coordinate, name = name })
+    return isBlock, res
+end
+
 local util = require("util"):new(wt)
 
 local inventory = require("inventory"):new()
+task.inventory = inventory
 
 function task:load(name, pos, taskArgs)
     local f = dofile("tasks/" .. name .. ".lua")
@@ -90,7 +117,17 @@ function task:load(name, pos, taskArgs)
 end
 
 function task:execute(name, pos, taskArgs, ...)
-    local success = self:load(name, pos, taskArgs)()
+    local success
+    local function run()
+        success = self:load(name, pos, taskArgs)()
+    end
+    -- The server can cancel the task at any moment, e.g. when an operator preempts it
+    local function cancel()
+        self:pull_event("task_cancel", self.cid)
+        print("task cancelled by server")
+        success = false
+    end
+    parallel.waitForAny(run, cancel)
     if success then
         local mid = self:send_event("task_finish")
         self:pull_event("task_finish_response", mid)
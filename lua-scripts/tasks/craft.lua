@@ -0,0 +1,80 @@
+---
+--- Lays out the crafting grid and crafts.
+--- arg is { stash = { { slot = s, count = c } }, moves = { { from = f, to = t, count = c } }, slot = s }
+--- Stashed items go into the inventory in front, e.g. a chest, which has to be empty so that all
+--- of it can be taken back after crafting. Fails when there is no empty inventory to stash in, or
+--- anything could not be stashed, moved, crafted or taken back.
+---
+
+local function total()
+    local count = 0
+    for slot = 1, 16 do
+        count = count + turtle.getItemCount(slot)
+    end
+    return count
+end
+
+-- Whether the inventory in front is empty, turtles can only take back items in the order it lists them
+local function emptyInventoryInFront()
+    local front = peripheral.wrap("front")
+    if front == nil or front.list == nil then
+        return false, "there is no inventory in front to stash items in"
+    end
+    if next(front.list()) ~= nil then
+        return false, "the inventory in front is not empty, stashed items could not be told apart"
+    end
+    return true
+end
+
+return function(_, arg)
+    if #arg.stash > 0 then
+        local empty, reason = emptyInventoryInFront()
+        if not empty then
+            error(reason)
+        end
+    end
+    local stashed = 0
+    local failure
+    for _, item in ipairs(arg.stash) do
+        turtle.select(item.slot)
+        local before = turtle.getItemCount(item.slot)
+        turtle.drop(item.count)
+        local dropped = before - turtle.getItemCount(item.slot)
+        stashed = stashed + dropped
+        if dropped ~= item.count then
+            failure = "could only stash " .. dropped .. " of " .. item.count .. " items from slot " .. item.slot
+            break
+        end
+    end
+    if not failure then
+        for _, move in ipairs(arg.moves) do
+            turtle.select(move.from)
+            if not turtle.transferTo(move.to, move.count) then
+                failure = "could not move " .. move.count .. " items from slot " .. move.from .. " to " .. move.to
+                break
+            end
+        end
+    end
+    if not failure then
+        turtle.select(arg.slot)
+        local crafted, reason = turtle.craft()
+        if not crafted then
+            failure = "could not craft: " .. tostring(reason)
+        end
+    end
+    while stashed > 0 do
+        local before = total()
+        if not turtle.suck(math.min(stashed, 64)) then
+            break
+        end
+        stashed = stashed - (total() - before)
+    end
+    if stashed > 0 and not failure then
+        failure = "could not take back " .. stashed .. " stashed items"
+    end
+    turtle.select(1)
+    task:send_event("inventory_update", inventory:update())
+    if failure then
+        error(failure)
+    end
+end
@@ -0,0 +1,12 @@
+---
+--- Drops items into the inventory in front, e.g. a chest. arg is a list of { slot = s, count = c }
+---
+
+return function(_, arg)
+    for _, item in ipairs(arg) do
+        turtle.select(item.slot)
+        turtle.drop(item.count)
+    end
+    turtle.select(1)
+    task:send_event("inventory_update", inventory:update())
+end
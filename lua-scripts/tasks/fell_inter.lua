@@ -5,7 +5,7 @@
 return function(pos)
     local h = 0
     while true do
-        local isBlock, res = turtle.inspectUp()
+        local isBlock, res = task:inspect(pos, "u")
         if not isBlock or not res.tags["minecraft:logs"] then
             break
         end
@@ -35,7 +35,7 @@ return function(pos)
     util:spiral(5, true, cutAction, pos)
 
     task:send_event("inventory_update", inventory:update())
-    local replant, wait, saplingSlot = table.unpack(task:task_question("replant"))
+    local replant, wait, saplingSlot = table.unpack(task:task_question("replant", { position = pos }))
     if replant then
         wt.r(pos)
         wt.mf(pos, 2)
@@ -0,0 +1,7 @@
+---
+--- Reports the block in front to the server, so it ends up in the world map
+---
+
+return function(pos)
+    task:inspect(pos, "f")
+end
@@ -0,0 +1,15 @@
+---
+--- Reports the blocks above, below, in front and to both sides to the server, then faces the
+--- way it did before. The back is where the turtle came from.
+---
+
+return function(pos)
+    task:inspect(pos, "u")
+    task:inspect(pos, "d")
+    task:inspect(pos, "f")
+    wt.l(pos)
+    task:inspect(pos, "f")
+    wt.r(pos, 2)
+    task:inspect(pos, "f")
+    wt.l(pos)
+end
@@ -0,0 +1,11 @@
+---
+--- Places a block from a slot below the turtle, arg is { slot = s }
+---
+
+return function(pos, arg)
+    turtle.select(arg.slot)
+    turtle.placeDown()
+    turtle.select(1)
+    task:inspect(pos, "d")
+    task:send_event("inventory_update", inventory:update())
+end
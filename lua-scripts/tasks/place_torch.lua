@@ -0,0 +1,15 @@
+---
+--- Digs a niche into the wall to the left and puts a torch in it, out of the way of the tunnel.
+--- arg is { slot = s }
+---
+
+return function(pos, arg)
+    wt.l(pos)
+    turtle.dig()
+    turtle.select(arg.slot)
+    turtle.place()
+    turtle.select(1)
+    task:inspect(pos, "f")
+    wt.r(pos)
+    task:send_event("inventory_update", inventory:update())
+end
@@ -0,0 +1,12 @@
+---
+--- Picks up the drops lying in front and places a sapling there, arg is { slot = s }
+---
+
+return function(pos, arg)
+    while turtle.suck() do end
+    turtle.select(arg.slot)
+    turtle.place()
+    turtle.select(1)
+    task:inspect(pos, "f")
+    task:send_event("inventory_update", inventory:update())
+end
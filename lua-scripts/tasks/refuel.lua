@@ -0,0 +1,12 @@
+---
+--- Burns items as fuel, arg is a list of { slot = s, count = c }
+---
+
+return function(_, arg)
+    for _, fuel in ipairs(arg) do
+        turtle.select(fuel.slot)
+        turtle.refuel(fuel.count)
+    end
+    turtle.select(1)
+    task:send_event("inventory_update", inventory:update())
+end
@@ -0,0 +1,64 @@
+---
+--- Takes one item from the inventory in front, e.g. a chest, until the turtle has `count` of it,
+--- is full or the inventory has no more. arg is { item = name, count = c }
+--- turtle.suck always takes from the first slot that is not empty, so each stack of the item is
+--- moved to the first slot, and what was there is held by the turtle meanwhile. Other items stay.
+---
+
+local function count(name)
+    local total = 0
+    for slot = 1, 16 do
+        local item = turtle.getItemDetail(slot)
+        if item and item.name == name then
+            total = total + item.count
+        end
+    end
+    return total
+end
+
+local function emptySlot()
+    for slot = 1, 16 do
+        if turtle.getItemCount(slot) == 0 then
+            return slot
+        end
+    end
+end
+
+return function(_, arg)
+    local front = peripheral.wrap("front")
+    if front == nil or front.list == nil then
+        error("there is no inventory in front to restock from")
+    end
+    local name = peripheral.getName(front)
+    local items = front.list()
+    for slot = 1, front.size() do
+        local item = items[slot]
+        local missing = arg.count - count(arg.item)
+        if missing <= 0 or emptySlot() == nil then
+            break
+        end
+        if item and item.name == arg.item then
+            local held
+            if slot ~= 1 then
+                if front.getItemDetail(1) ~= nil then
+                    held = emptySlot()
+                    turtle.select(held)
+                    turtle.suck()
+                end
+                front.pushItems(name, slot, nil, 1)
+            end
+            local target = emptySlot()
+            if target ~= nil then
+                turtle.select(target)
+                turtle.suck(math.min(missing, item.count))
+            end
+            if held then
+                -- Goes back into the slot the item came from, or the first one again
+                turtle.select(held)
+                turtle.drop()
+            end
+        end
+    end
+    turtle.select(1)
+    task:send_event("inventory_update", inventory:update())
+end
@@ -0,0 +1,26 @@
+-- Evaluates code that changes the APIs it is given, like the server may send, and checks that
+-- nothing outside of the sandbox changed
+
+local sandbox = require("sandbox")
+
+local function eval(code, allow)
+    local body = loadstring(code)
+    setfenv(body, sandbox(allow))
+    local res = table.pack(pcall(body))
+    if not res[1] then
+        error("Eval failed: " .. tostring(res[2]))
+    end
+end
+
+local floor, rep, insert, forward = math.floor, string.rep, table.insert, turtle.forward
+eval("math.floor = nil; string.rep = nil; table.insert = nil; turtle.forward = nil", { "turtle" })
+assert(math.floor == floor, "math.floor leaked out of the sandbox")
+assert(string.rep == rep, "string.rep leaked out of the sandbox")
+assert(("x"):rep(2) == "xx", "string methods leaked out of the sandbox")
+assert(table.insert == insert, "table.insert leaked out of the sandbox")
+assert(turtle.forward == forward, "turtle.forward leaked out of the sandbox")
+
+eval("os.getComputerLabel = nil; os.reboot = print", { "os.getComputerLabel" })
+assert(os.getComputerLabel ~= nil and os.reboot ~= print, "os leaked out of the sandbox")
+
+print("sandbox ok")
@@ -1,7 +1,8 @@
-return function ()
+return function (id, secret)
     local proto_task = require("task")
     local json = require("json")
     local t = require("move")
+    local sandbox = require("sandbox")
 
     --local remote = "replicca.mc.nielsoverkamp.com/api"
     local remote = "localhost:17576"
@@ -11,15 +12,18 @@ return function ()
         TASK_EVENT="TASK_EVENT",
     }
 
+    -- How many sent messages are kept for resending until the server acknowledges them
+    local RESEND_BUFFER = 64
+
     local COMMANDS = {
         EVAL="EVAL",
         TASK="TASK",
         MOVE="MOVE",
+        STATE="STATE",
     }
 
     local function connect(url)
-        local id = 1
-        local ws, err = http.websocket("ws://"..url .. "/ws/"..id)
+        local ws, err = http.websocket("ws://"..url .. "/ws/"..id, { ["X-Turtle-Secret"] = secret })
 
         if not ws then
             error(err)
@@ -35,18 +39,28 @@ return function ()
                                 -- This should however not happen, and if it does it should not have bad consequences
                                 -- *knock knock*
 
+    -- Every message has a mid that counts up. Both sides acknowledge what they received and drop
+    -- messages they have seen before, so everything that was not acknowledged can be sent again
+    -- after a reconnect. A new session means the other side restarted and counts from the start,
+    -- then what it did not acknowledge is dropped, it was meant for the server that is gone.
     ws = {
         _socket = connect(remote),
         _mid_counter = 0,
+        _session = os.epoch("utc"),
+        _unacked = {},
+        _last_received = nil,
+        _server_session = nil,
     }
 
-    function ws:sendBlocking(msg)
-        local mid = self._mid_counter
-        self._mid_counter = mid + 1
-        msg.mid = mid
-        local data = json.encode(msg)
+    -- Tells the server what we received, reconnecting until it works. What the server missed is
+    -- sent again once its HELLO says it is the same server.
+    function ws:resync()
         repeat
-            local status = pcall(function () return self._socket.send(data) end)
+            local status = pcall(function ()
+                self._socket.send(json.encode({
+                    c="hello", session=self._session, ack=self._last_received, ack_session=self._server_session,
+                }))
+            end)
             if not status then
                 print("Lost connection, reconnecting in 1 second...")
                 sleep(1)
@@ -54,27 +68,103 @@ return function ()
                 self._socket = connect(remote)
             end
         until status
+    end
+
+    function ws:reconnect()
+        pcall(function () self._socket.close() end)
+        self._socket = connect(remote)
+        self:resync()
+    end
+
+    -- Handles the HELLO of the server: sends what it did not acknowledge yet, unless the server
+    -- restarted and would take it for messages of its own session
+    function ws:greeted(msg)
+        if msg.session ~= self._server_session then
+            if self._server_session ~= nil then
+                print("Server restarted, dropping "..#self._unacked.." unacknowledged messages")
+                self._unacked = {}
+            end
+            self._server_session = msg.session
+            self._last_received = nil
+        end
+        -- The ack belongs to the session we had when the server last heard of us
+        if msg.ack_session == self._session then
+            self:acknowledged(msg.ack)
+        end
+        local status = pcall(function ()
+            for _, message in ipairs(self._unacked) do
+                self._socket.send(message.data)
+            end
+        end)
+        if not status then
+            print("Lost connection, reconnecting...")
+            self:reconnect()
+        end
+    end
+
+    function ws:acknowledged(ack)
+        if ack == nil then
+            return
+        end
+        while #self._unacked > 0 and self._unacked[1].mid <= ack do
+            table.remove(self._unacked, 1)
+        end
+    end
+
+    function ws:sendBlocking(msg)
+        local mid = self._mid_counter
+        self._mid_counter = mid + 1
+        msg.mid = mid
+        local data = json.encode(msg)
+        table.insert(self._unacked, { mid=mid, data=data })
+        if #self._unacked > RESEND_BUFFER then
+            print("WARNING: Resend buffer is full, message "..self._unacked[1].mid.." will not be resent")
+            table.remove(self._unacked, 1)
+        end
+        local status = pcall(function () return self._socket.send(data) end)
+        if not status then
+            print("Lost connection, reconnecting in 1 second...")
+            sleep(1)
+            -- Resends this message as well
+            self:reconnect()
+        end
         return mid
     end
 
+    -- Receives the next message that was not received before, handling acknowledgements on the way
     function ws:receiveBlocking()
         while true do
             local status, res = pcall(function() return self._socket.receive() end)
             print("received", status, res)
             if status then
                 if res ~= nil then
-                    return json.decode(res)
+                    local msg = json.decode(res)
+                    if msg.c == "ACK" then
+                        self:acknowledged(msg.ack)
+                    elseif msg.c == "HELLO" then
+                        self:greeted(msg)
+                    else
+                        local duplicate = msg.mid ~= nil and self._last_received ~= nil and msg.mid <= self._last_received
+                        if not duplicate and msg.mid ~= nil then
+                            self._last_received = msg.mid
+                        end
+                        pcall(function () self._socket.send(json.encode({ c="ack", ack=self._last_received })) end)
+                        if not duplicate then
+                            return msg
+                        end
+                    end
                 else
                     -- Timeout, try again
                 end
             else
                 print("Lost connection, reconnecting...")
-                self._socket.close()
-                self._socket = connect(remote)
+                self:reconnect()
             end
         end
     end
 
+    ws:resync()
+
     proto_task.ws = ws
 
     local function websocketListener()
@@ -108,13 +198,13 @@ return function ()
             print("executor", command.c)
 
             if command.c == COMMANDS.EVAL then
-                local body = loadstring(command.b)
+                local body = loadstring(command.b.code)
                 if body == nil then
-                    local err = "Error: Could not parse: "..command.b
+                    local err = "Error: Could not parse: "..command.b.code
                     print(err)
                     ws:sendBlocking({ cid=command.cid, c="eval_response", b=err})
                 else
-                    setfenv(body, _ENV)
+                    setfenv(body, sandbox(command.b.allow))
                     local res = table.pack(pcall(body))
                     if res[1] then
                         print(table.unpack(res, 2, #res))
@@ -132,12 +222,25 @@ return function ()
                 end
                 print(parallel.waitForAny(execute, executor))
             elseif command.c == COMMANDS.MOVE then
-                local success, err, completed = t.runString(pos, command.b)
+                local success, err, completed, reason = t.runString(pos, command.b)
                 local body
                 if not success then
-                    body = {e=err, c=completed}
+                    body = {e=err, c=completed, r=reason}
                 end
                 ws:sendBlocking({ cid=command.cid, c="move_response", b=body})
+            elseif command.c == COMMANDS.STATE then
+                local fuel = turtle.getFuelLevel()
+                if fuel == "unlimited" then
+                    -- Fuel is disabled on the server, report more than any task needs
+                    fuel = 2147483647
+                end
+                local state = {
+                    label = os.getComputerLabel() or "",
+                    position = pos,
+                    fuel_level = fuel,
+                    inventory = proto_task.inventory:full(),
+                }
+                ws:sendBlocking({ cid=command.cid, c="state_update", b=state})
             else
                 print("Unknown command "..command.c)
             end
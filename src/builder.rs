@@ -0,0 +1,351 @@
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::time::Duration;
+use std::{env, fs, io};
+
+use json::JsonValue;
+
+use crate::decode::{DecodeError, expect_array, expect_bool, expect_object, expect_str, field, field_with};
+use crate::executor::TaskExecutor;
+use crate::fuel;
+use crate::maneuver::{Maneuver, ManeuverStep, Move};
+use crate::schematic::Schematic;
+use crate::task_registry::Task;
+use crate::turtle::{Coordinate, Position};
+use crate::world_map::KnownBlock;
+
+/// How long to wait at the chest for materials before looking again
+const MATERIAL_WAIT: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug)]
+pub struct Placement {
+    pub coordinate: Coordinate,
+    pub block: String,
+}
+
+/// Builds a `Schematic` with its origin right in front of `anchor`, `x` to the right of it and
+/// `z` ahead. Layers go from the bottom up and the turtle places every block from above, in
+/// serpentine rows. Materials come from the chest right behind `anchor`.
+/// The schematic is read from the file at `BUILD_SCHEMATIC`. Which blocks were placed is stored
+/// as json in the file at `BUILD_FILE` (default `build_{id}.json`, with the id of the turtle), so
+/// the build resumes where it stopped.
+pub struct Build {
+    /// Where the build is saved, `None` keeps it in memory only
+    path: Option<String>,
+    schematic_path: String,
+    pub anchor: Position,
+    /// Blocks in placing order
+    pub placements: Vec<Placement>,
+    /// Whether each of `placements` is done
+    pub placed: Vec<bool>,
+}
+
+impl Build {
+    pub fn new(schematic_path: &str, schematic: &Schematic, anchor: Position) -> Self {
+        let mut placements = Vec::new();
+        for y in 0..schematic.height {
+            for z in 0..schematic.length {
+                for i in 0..schematic.width {
+                    let x = if z % 2 == 0 { i } else { schematic.width - 1 - i };
+                    if let Some(block) = schematic.get(x, y, z) {
+                        let mut position = anchor.clone();
+                        position.move_horizontal(1 + z as i64);
+                        position.turn(1);
+                        position.move_horizontal(x as i64);
+                        let coordinate = position.coordinate().delta(0, y as i64, 0);
+                        placements.push(Placement { coordinate, block: block.to_owned() });
+                    }
+                }
+            }
+        }
+        let placed = vec![false; placements.len()];
+        Self { path: None, schematic_path: schematic_path.to_owned(), anchor, placements, placed }
+    }
+
+    /// Loads the build of the turtle, or starts the schematic at `BUILD_SCHEMATIC` at `anchor` if
+    /// it has none yet
+    pub fn load(id: u32, anchor: &Position) -> io::Result<Self> {
+        let path = env::var("BUILD_FILE").unwrap_or(String::from("build_{id}.json"))
+            .replace("{id}", id.to_string().as_str());
+        Self::open(path, anchor, env::var("BUILD_SCHEMATIC").ok().as_deref())
+    }
+
+    /// Loads the build from the file, or starts the schematic at `schematic_path` that is saved
+    /// there
+    pub fn open(path: String, anchor: &Position, schematic_path: Option<&str>) -> io::Result<Self> {
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, format!("Could not parse build file {}: {}", path, e));
+        let mut build = match fs::read_to_string(&path) {
+            Ok(s) => json::parse(s.as_str())
+                .map_err(|e| e.to_string())
+                .and_then(|jv| Self::decode(&jv))
+                .map_err(invalid)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let schematic_path = schematic_path
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "BUILD_SCHEMATIC is not set"))?;
+                Self::new(schematic_path, &Schematic::load(schematic_path)?, anchor.clone())
+            }
+            Err(e) => return Err(e),
+        };
+        build.path = Some(path);
+        Ok(build)
+    }
+
+    /// Plans the schematic again, the placed blocks have to match up with it
+    fn decode(jv: &JsonValue) -> Result<Self, String> {
+        let decode = || -> Result<(&str, Position, Vec<bool>), DecodeError> {
+            expect_object(jv)?;
+            let placed = field_with(jv, "placed", expect_array)?.iter().enumerate()
+                .map(|(i, p)| expect_bool(p).map_err(|e| e.at_index(i).at("placed")))
+                .collect::<Result<Vec<bool>, DecodeError>>()?;
+            Ok((field_with(jv, "schematic", expect_str)?, field(jv, "anchor")?, placed))
+        };
+        let (schematic_path, anchor, placed) = decode().map_err(|e| e.to_string())?;
+        let schematic = Schematic::load(schematic_path).map_err(|e| e.to_string())?;
+        let build = Self::new(schematic_path, &schematic, anchor);
+        if placed.len() != build.placements.len() {
+            return Err(format!("Schematic {} has {} blocks, the build {}", schematic_path, build.placements.len(), placed.len()));
+        }
+        Ok(Self { placed, ..build })
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let jv = json::object! {
+            schematic: self.schematic_path.as_str(),
+            anchor: &self.anchor,
+            placed: self.placed.clone(),
+        };
+        fs::write(path, json::stringify(jv))
+    }
+
+    /// Blocks still to be placed, by name
+    pub fn materials(&self) -> BTreeMap<String, u32> {
+        let mut materials = BTreeMap::new();
+        for (placement, _) in self.placements.iter().zip(&self.placed).filter(|(_, placed)| !**placed) {
+            *materials.entry(placement.block.clone()).or_insert(0) += 1;
+        }
+        materials
+    }
+
+    /// What was built so far, which routes should not dig through
+    fn avoid(&self) -> HashSet<Coordinate> {
+        self.placements.iter().zip(&self.placed)
+            .filter(|(_, placed)| **placed)
+            .map(|(p, _)| p.coordinate)
+            .collect()
+    }
+
+    /// Places every block that is not placed yet, fetching materials from the chest whenever
+    /// the turtle runs out. Ends back at the anchor.
+    pub fn run(&mut self, executor: &mut TaskExecutor) -> Result<(), Box<dyn Error>> {
+        executor.refresh_state()??;
+        println!("Building {} with {:?}", self.schematic_path, self.materials());
+        for index in 0..self.placements.len() {
+            if self.placed[index] {
+                continue;
+            }
+            self.place(executor, index)?;
+            self.placed[index] = true;
+            if let Err(e) = self.save() {
+                eprintln!("Could not save build: {}", e);
+            }
+        }
+        println!("Built {}", self.schematic_path);
+        executor.go_to_avoiding(self.anchor.coordinate(), Some(self.anchor.direction()), self.avoid())??;
+        Ok(())
+    }
+
+    fn is_placed(executor: &TaskExecutor, placement: &Placement) -> bool {
+        executor.world().get(placement.coordinate).and_then(KnownBlock::name) == Some(placement.block.as_str())
+    }
+
+    /// Places the block from above. A block that is already there, e.g. because the connection
+    /// dropped after placing it, counts as placed. Something else in the way is dug out first.
+    fn place(&mut self, executor: &mut TaskExecutor, index: usize) -> Result<(), Box<dyn Error>> {
+        let placement = self.placements[index].clone();
+        if Self::is_placed(executor, &placement) {
+            return Ok(());
+        }
+        for attempt in 0..2 {
+            let slot = loop {
+                match executor.turtle.inventory.find(|i| i.name == placement.block && i.count > 0) {
+                    Some((_, slot)) => break slot,
+                    None => self.restock(executor, &placement.block)?,
+                }
+            };
+            let above = placement.coordinate.delta(0, 1, 0);
+            if let Err(e) = executor.go_to_avoiding(above, None, self.avoid())? {
+                return Err(format!("Could not get above {:?}: {}", placement.coordinate, e).into());
+            }
+            if attempt > 0 {
+                let mut clear = Maneuver::new();
+                clear.push(ManeuverStep::new(Move::Down, true, 1));
+                clear.push(ManeuverStep::new(Move::Up, false, 1));
+                if let Err((e, _)) = executor.run_maneuver(&clear)? {
+                    return Err(format!("Could not clear {:?}: {}", placement.coordinate, e).into());
+                }
+            }
+            executor.execute(Task::place_down(slot), TaskExecutor::default_event_handler, TaskExecutor::null_question_handler)?;
+            if Self::is_placed(executor, &placement) {
+                return Ok(());
+            }
+        }
+        Err(format!("Could not place {} at {:?}", placement.block, placement.coordinate).into())
+    }
+
+    /// Goes to the chest and fetches as much of `block` as the rest of the build needs.
+    /// Everything else is put into the chest first to make room, the turtle only takes `block`
+    /// back out. Waits there until the chest has some.
+    fn restock(&mut self, executor: &mut TaskExecutor, block: &str) -> Result<(), Box<dyn Error>> {
+        let chest_side = self.anchor.direction().turn(2);
+        if let Err(e) = executor.go_to_avoiding(self.anchor.coordinate(), Some(chest_side), self.avoid())? {
+            return Err(format!("Could not get to the chest: {}", e).into());
+        }
+        let needed = self.materials().get(block).copied().unwrap_or(0);
+        let mut only_block = BTreeMap::new();
+        only_block.insert(block.to_owned(), needed);
+        loop {
+            executor.refresh_state()??;
+            self.unload(executor, &only_block)?;
+            if !executor.execute(Task::restock(block, needed.max(1)), TaskExecutor::default_event_handler, TaskExecutor::null_question_handler)? {
+                return Err("Could not restock from the chest".into());
+            }
+            if executor.turtle.inventory.find(|i| i.name == block && i.count > 0).is_some() {
+                return Ok(());
+            }
+            println!("Build needs {} {}, waiting at the chest", needed, block);
+            executor.serve_requests_for(MATERIAL_WAIT)?;
+        }
+    }
+
+    /// Drops everything into the chest but fuel and the materials still needed
+    fn unload(&self, executor: &mut TaskExecutor, needed: &BTreeMap<String, u32>) -> Result<(), Box<dyn Error>> {
+        let mut keep = needed.clone();
+        let mut items = Vec::new();
+        for (item, slot) in executor.turtle.inventory.item_iter() {
+            if fuel::fuel_value(item.name.as_str()).is_some() && !needed.contains_key(&item.name) {
+                continue;
+            }
+            let wanted = keep.get_mut(&item.name).map_or(0, |n| {
+                let kept = (*n).min(item.count as u32);
+                *n -= kept;
+                kept
+            });
+            if wanted < item.count as u32 {
+                items.push((slot, item.count - wanted as u8));
+            }
+        }
+        if !items.is_empty() && !executor.execute(Task::deposit(&items), TaskExecutor::default_event_handler, TaskExecutor::null_question_handler)? {
+            return Err("Could not unload into the chest".into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use crate::schematic::Schematic;
+    use crate::simulator::{Block, World};
+    use crate::simulator::testing::{finish, start_with};
+    use crate::turtle::{Coordinate, Item, Position};
+
+    use super::*;
+
+    #[test]
+    fn build_places_a_schematic_and_restocks_from_the_chest() {
+        let layers = json::object! {
+            palette: { "#": "minecraft:cobblestone", "P": "minecraft:oak_planks" },
+            layers: [["#P#", "P.P"], ["#.#"]],
+        };
+        let schematic = Schematic::try_from(&layers).unwrap();
+
+        let mut world = World::new();
+        world.fill(Coordinate::new(-2, -1, -4), Coordinate::new(4, -1, 2), "minecraft:stone");
+        let mut chest = Block::new("minecraft:chest");
+        // More than the turtle can carry in front of the planks
+        for _ in 0..20 {
+            chest.contents.push(Item { count: 64, name: "minecraft:dirt".to_owned() });
+        }
+        chest.contents.push(Item { count: 10, name: "minecraft:oak_planks".to_owned() });
+        world.set_block(Coordinate::new(0, 0, 1), Some(chest));
+        // Placed before the connection dropped, and something in the way
+        world.set_block(Coordinate::new(0, 0, -1), Some(Block::new("minecraft:cobblestone")));
+        world.set_block(Coordinate::new(2, 0, -2), Some(Block::new("minecraft:dirt")));
+        let (mut executor, handle) = start_with(world, 1000, |t| {
+            t.inventory[0] = Some(Item { count: 5, name: "minecraft:gravel".to_owned() });
+            t.inventory[1] = Some(Item { count: 3, name: "minecraft:cobblestone".to_owned() });
+        });
+        let mut build = Build::new("test.json", &schematic, Position::default());
+        assert_eq!(build.materials().get("minecraft:cobblestone"), Some(&4));
+        assert_eq!(build.materials().get("minecraft:oak_planks"), Some(&3));
+
+        build.run(&mut executor).unwrap();
+        assert!(build.placed.iter().all(|p| *p));
+        assert!(build.materials().is_empty());
+        assert_eq!(executor.turtle.position.coordinate(), Coordinate::new(0, 0, 0));
+
+        let turtle = finish(executor, handle);
+        for placement in build.placements.iter() {
+            assert_eq!(turtle.world.block(placement.coordinate).map(|b| b.name.as_str()), Some(placement.block.as_str()));
+        }
+        assert!(turtle.world.block(Coordinate::new(1, 0, -2)).is_none());
+        assert!(turtle.world.block(Coordinate::new(1, 1, -1)).is_none());
+        assert_eq!(turtle.item_count("minecraft:cobblestone"), 0);
+        assert_eq!(turtle.item_count("minecraft:oak_planks"), 0);
+        let chest = turtle.world.block(Coordinate::new(0, 0, 1)).unwrap();
+        let count = |name: &str| chest.contents.iter().filter(|i| i.name == name).map(|i| i.count as u32).sum::<u32>();
+        assert_eq!(count("minecraft:oak_planks"), 7);
+        assert_eq!(count("minecraft:gravel"), 5);
+        // The dirt in front of the planks stayed in the chest
+        assert_eq!(count("minecraft:dirt"), 20 * 64);
+    }
+
+    #[test]
+    fn build_resumes_from_its_file() {
+        let schematic_path = std::env::temp_dir().join(format!("build_resume_{}_schematic.json", std::process::id()));
+        let schematic_path = schematic_path.to_str().unwrap().to_owned();
+        let layers = json::object! {
+            palette: { "#": "minecraft:cobblestone" },
+            layers: [["###", "#.#"]],
+        };
+        std::fs::write(&schematic_path, json::stringify(layers)).unwrap();
+        let path = std::env::temp_dir().join(format!("build_resume_{}.json", std::process::id()));
+        let path = path.to_str().unwrap().to_owned();
+        let _ = std::fs::remove_file(&path);
+        let mut build = Build::open(path.clone(), &Position::default(), Some(schematic_path.as_str())).unwrap();
+        build.placed[0] = true;
+        build.placed[1] = true;
+        build.save().unwrap();
+
+        // The first two blocks were placed before the turtle stopped
+        let mut world = World::new();
+        world.fill(Coordinate::new(-2, -1, -4), Coordinate::new(4, -1, 2), "minecraft:stone");
+        for placement in build.placements.iter().take(2) {
+            world.set_block(placement.coordinate, Some(Block::new(placement.block.as_str())));
+        }
+        world.set_block(Coordinate::new(0, 0, 1), Some(Block::new("minecraft:chest")));
+        let (mut executor, handle) = start_with(world, 1000, |t| {
+            t.inventory[0] = Some(Item { count: 3, name: "minecraft:cobblestone".to_owned() });
+        });
+        let mut build = Build::open(path.clone(), &Position::default(), None).unwrap();
+        assert_eq!(build.placed, vec![true, true, false, false, false]);
+        assert_eq!(build.materials().get("minecraft:cobblestone"), Some(&3));
+
+        build.run(&mut executor).unwrap();
+        let saved = Build::open(path.clone(), &Position::default(), None).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&schematic_path).unwrap();
+        assert!(saved.placed.iter().all(|p| *p));
+
+        let turtle = finish(executor, handle);
+        for placement in build.placements.iter() {
+            assert_eq!(turtle.world.block(placement.coordinate).map(|b| b.name.as_str()), Some(placement.block.as_str()));
+        }
+        assert_eq!(turtle.item_count("minecraft:cobblestone"), 0);
+    }
+}
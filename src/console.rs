@@ -1,16 +1,25 @@
+use std::env;
 use std::str::{FromStr, SplitWhitespace};
+use std::sync::mpsc;
 use std::thread;
 use std::thread::JoinHandle;
 use std::io::{stdin, stdout, Write};
-use crate::TurtleList;
-use crate::turtle_websocket::{Command, UpEvent};
-use crate::executor::TaskExecutor;
-use crate::executor::Task;
+use crate::executor::{ExecutorCommand, ExecutorRequest, ExecutorResponse, TaskExecutor};
+use crate::fleet::Fleet;
+use crate::task_registry::ReplantAnswer;
+use crate::turtle_websocket::TaskQuestion;
+use crate::maneuver::{Maneuver, ManeuverError};
+use crate::turtle::{Coordinate, Direction};
+use std::convert::TryFrom;
 use json::JsonValue;
+use std::time::Duration;
+
+/// How often the console checks for questions while it waits for a turtle
+const QUESTION_POLL_INTERVAL: Duration = Duration::from_millis(500);
 
 
 pub enum ConsoleCommand {
-    Eval, Task, Move, Turtle, List, Exit
+    Eval, Task, Tasks, Move, GoTo, Turtle, List, Find, Exit
 }
 
 impl FromStr for ConsoleCommand {
@@ -20,16 +29,21 @@ impl FromStr for ConsoleCommand {
         match s.to_lowercase().as_str() {
             "eval" => Ok(ConsoleCommand::Eval),
             "task" => Ok(ConsoleCommand::Task),
+            "tasks" => Ok(ConsoleCommand::Tasks),
             "move" => Ok(ConsoleCommand::Move),
+            "goto" => Ok(ConsoleCommand::GoTo),
             "turtle" => Ok(ConsoleCommand::Turtle),
             "list" => Ok(ConsoleCommand::List),
+            "find" => Ok(ConsoleCommand::Find),
             "exit" => Ok(ConsoleCommand::Exit),
             _ => Err(())
         }
     }
 }
 
-pub fn spawn_console(mut turtles: TurtleList) -> Result<JoinHandle<()>, Box<dyn std::error::Error>> {
+/// Reads commands from stdin. Commands for the selected turtle are queued until its runner is
+/// between tasks, or preempt the running task when suffixed with `!` (e.g. `move! f3`).
+pub fn spawn_console(fleet: Fleet) -> Result<JoinHandle<()>, Box<dyn std::error::Error>> {
     Ok(thread::spawn(move || {
         let stdin = stdin();
         let mut selected = None;
@@ -44,12 +58,16 @@ pub fn spawn_console(mut turtles: TurtleList) -> Result<JoinHandle<()>, Box<dyn
                 Ok(_) => {
                     let mut input = input.split_whitespace();
                     if let Some(s) = input.next() {
+                        let (s, preempt) = match s.strip_suffix('!') {
+                            Some(s) => (s, true),
+                            None => (s, false),
+                        };
                         match s.parse() {
                             Ok(ConsoleCommand::Exit) => {
                                 break;
                             }
                             Ok(c) => {
-                                match parse_command(c, input, &mut turtles, selected) {
+                                match parse_command(c, input, &fleet, selected, preempt) {
                                     Ok(s) => selected = s,
                                     Err(e) => eprintln!("Error: {}", e),
                                 }
@@ -64,121 +82,184 @@ pub fn spawn_console(mut turtles: TurtleList) -> Result<JoinHandle<()>, Box<dyn
     }))
 }
 
-fn stdin_question_handler(question: String, _: &mut TaskExecutor) -> JsonValue {
-    match question.as_str() {
+/// Asks about replanting in plain words, other questions reach the console as escalated questions
+fn stdin_question_handler(question: &TaskQuestion, executor: &mut TaskExecutor) -> Option<JsonValue> {
+    match question.code.as_str() {
         "replant" => {
             println!("Replant tree? [Y/n]");
             stdout().flush().unwrap();
             let mut s = String::new();
             stdin().read_line(&mut s).unwrap();
-            match s.as_str() {
-                "n" => JsonValue::Boolean(false),
-                _ => JsonValue::Boolean(true)
-            }
+            let sapling = executor.turtle.inventory.find(|i| i.name.ends_with("sapling") && i.count > 0);
+            Some(match (s.trim(), sapling) {
+                ("n", _) => ReplantAnswer::skip(),
+                (_, Some((_, sapling_slot))) => (&ReplantAnswer { wait: false, sapling_slot }).into(),
+                (_, None) => {
+                    println!("No sapling to replant");
+                    ReplantAnswer::skip()
+                }
+            })
         },
-        _ => {
-            println!("Unknown question {}", question);
-            JsonValue::Null
+        _ => None,
+    }
+}
+
+/// Lets the operator answer the questions the task of the turtle could not answer itself
+fn answer_questions(fleet: &Fleet, id: u32) {
+    for (question_id, question) in fleet.questions(id) {
+        let task = fleet.get(id).and_then(|r| r.current_task);
+        let expected = task.as_deref().and_then(|t| fleet.tasks().question(t, question.code.as_str()))
+            .map_or(String::new(), |q| format!(" with {}", JsonValue::from(&q.answer)));
+        println!("Turtle {} asks {}, answer{} as json:", id, question, expected);
+        stdout().flush().unwrap();
+        let mut s = String::new();
+        stdin().read_line(&mut s).unwrap();
+        let answered = json::parse(s.trim()).map_err(|e| e.to_string())
+            .and_then(|answer| {
+                fleet.check_answer(id, question_id, &answer)?;
+                fleet.answer(id, question_id, answer)
+            });
+        if let Err(e) = answered {
+            eprintln!("Error: {}", e);
         }
     }
 }
 
-pub fn parse_command(command: ConsoleCommand, mut input: SplitWhitespace, turtles: &mut TurtleList, selected: Option<usize>) -> Result<Option<usize>, String> {
+fn request(fleet: &Fleet, selected: Option<u32>, command: ExecutorCommand, preempt: bool) -> Result<ExecutorResponse, String> {
+    let id = selected.ok_or_else(|| "No turtle selected".to_string())?;
+    let (reply, response) = mpsc::channel();
+    if let Some(task) = fleet.get(id).and_then(|r| r.current_task) {
+        if preempt {
+            println!("Cancelling task {} of turtle {}", task, id);
+        } else {
+            println!("Waiting for turtle {} to finish task {}", id, task);
+        }
+    }
+    fleet.request(id, ExecutorRequest { command, preempt, reply })?;
+    loop {
+        match response.recv_timeout(QUESTION_POLL_INTERVAL) {
+            Ok(response) => return response,
+            Err(mpsc::RecvTimeoutError::Timeout) => answer_questions(fleet, id),
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Err(format!("Executor of turtle {} stopped before responding", id)),
+        }
+    }
+}
+
+pub fn parse_command(command: ConsoleCommand, mut input: SplitWhitespace, fleet: &Fleet, selected: Option<u32>, preempt: bool) -> Result<Option<u32>, String> {
     match command {
         ConsoleCommand::Eval => {
-            if selected.is_none() {
-                return Err("No turtle selected".to_string())
-            }
-
             let first = input.next();
 
             let mut body;
             if let Some(s) = first {
                 body = String::from(s);
                 input.for_each(|w| {
-                    body.push_str(" ");
+                    body.push(' ');
                     body.push_str(w);
                 });
             } else {
                 body = String::new();
             }
 
-            let turtle_lock = turtles.lock();
-            let mut turtle_lock = turtle_lock.unwrap();
-            let exec: &mut TaskExecutor = turtle_lock.get_mut(selected.unwrap()).unwrap();
-
-            exec.connection.send_command(Command::Eval(body));
-            match exec.connection.receive_event().unwrap().0 {
-                UpEvent::EvalResponse(jv) => println!("{}", jv),
-                _ => {}
+            let by = env::var("USER").map_or(String::from("console"), |u| format!("console ({})", u));
+            if let ExecutorResponse::Eval(response) = request(fleet, selected, ExecutorCommand::Eval { code: body, by }, preempt)? {
+                println!("{}", response);
             }
             Ok(selected)
 
         }
         ConsoleCommand::Task => {
-            let task = input.next().map(Task::from_code);
-            if task.is_none() {
-                return Err("Task requires 1 argument".to_string())
-            }
-            let turtle_lock = turtles.lock();
-            let mut turtle_lock = turtle_lock.unwrap();
-            let exec: &mut TaskExecutor = turtle_lock.get_mut(selected.unwrap()).unwrap();
-
-            match task.unwrap() {
-                Task::Anon(_) => Err("Anonymous tasks not supported".to_string()),
-                e => exec.execute(e, TaskExecutor::default_event_handler, stdin_question_handler)
-                    .and_then(|success| {
-                        if success {
-                            Ok(selected)
-                        } else {
-                            Err("Task failed".into())
-                        }
-                    })
-                    .map_err(|e| format!("Error occurred at sending task: {}", e))
+            let code = match input.next() {
+                Some(code) => code.to_lowercase(),
+                None => return Err("Task requires 1 argument".to_string()),
+            };
+            // Anything after the code is the json arguments, e.g. `task refuel_logs {"slot": 1, "count": 2}`
+            let arguments = input.collect::<Vec<&str>>().join(" ");
+            let arguments = if arguments.is_empty() {
+                JsonValue::Null
+            } else {
+                json::parse(arguments.as_str()).map_err(|e| format!("Invalid task arguments: {}", e))?
+            };
+            let task = fleet.tasks().build(code.as_str(), arguments)?;
+
+            match request(fleet, selected, ExecutorCommand::Task(task, stdin_question_handler), preempt)? {
+                ExecutorResponse::Task(true) => Ok(selected),
+                ExecutorResponse::Task(false) => Err("Task failed".into()),
+                _ => Err("Expected task response".into()),
             }
         }
         ConsoleCommand::Move => {
-            let s: String = input.into_iter().collect();
-            let turtle_lock = turtles.lock();
-            let mut turtle_lock = turtle_lock.unwrap();
-            let exec: &mut TaskExecutor = turtle_lock.get_mut(selected.unwrap()).unwrap();
-
-            exec.connection.send_command(Command::Move(s.clone()));
-            match exec.connection.receive_event().unwrap().0 {
-                UpEvent::MoveResponse(Ok(())) => {
+            let maneuver: Maneuver = input.collect::<String>().parse().map_err(|e: ManeuverError| e.to_string())?;
+
+            match request(fleet, selected, ExecutorCommand::Move(maneuver.clone()), preempt)? {
+                ExecutorResponse::Move(Ok(())) => {
                     println!("finished!");
                     Ok(selected)
                 },
-                UpEvent::MoveResponse(Err((err, completed))) => {
+                ExecutorResponse::Move(Err((err, index))) => {
                     println!("Error during move: {}", err);
-                    let mut chars = s.chars();
-                    println!("completed:\t{}", chars.by_ref().take(completed-1).collect::<String>());
-                    println!("not completed:\t{}", chars.collect::<String>());
+                    let (completed, remaining) = maneuver.split_at_index(index);
+                    println!("completed:\t{}", completed);
+                    println!("not completed:\t{}", remaining);
                     Ok(selected)
                 }
-                r => Err(format!("Expected move response, got {:?}", r))
+                _ => Err("Expected move response".into()),
             }
 
         }
+        ConsoleCommand::GoTo => {
+            let mut coordinates = [0i64; 3];
+            for (i, c) in coordinates.iter_mut().enumerate() {
+                *c = input.next()
+                    .ok_or_else(|| "Goto requires at least 3 arguments".to_string())?
+                    .parse().map_err(|_| format!("Expected an integer argument at position {}", i + 1))?;
+            }
+            let [x, y, z] = coordinates;
+            let facing = match input.next() {
+                Some(d) => Some(Direction::try_from(&JsonValue::from(d)).map_err(|e| e.to_string())?),
+                None => None,
+            };
+
+            match request(fleet, selected, ExecutorCommand::GoTo(Coordinate::new(x, y, z), facing), preempt)? {
+                ExecutorResponse::GoTo(Ok(())) => {
+                    println!("arrived!");
+                    Ok(selected)
+                }
+                ExecutorResponse::GoTo(Err(e)) => Err(e.to_string()),
+                _ => Err("Expected go to response".into()),
+            }
+        }
         ConsoleCommand::Turtle => {
             let s = match input.next() {
                 None => Err("Turtle requires 1 argument".to_string()),
                 Some(arg1) => arg1.parse().map_err(|_| "Expected an integer argument at position 1".to_string()),
             }?;
-            let turtle_count = turtles.lock().unwrap().len();
-            if s >= turtle_count {
-                return Err(format!("Only {} turtles connected, {} is too high", turtle_count, s));
+            if fleet.get(s).is_none() {
+                Err(format!("No turtle with id {} has connected", s))
             } else {
-                return Ok(Some(s));
+                Ok(Some(s))
             }
         }
+        ConsoleCommand::Tasks => {
+            fleet.tasks().list()
+                .for_each(|t| println!("{}\t{}\targuments: {}", t.code(), t.description(), JsonValue::from(&t.arguments())));
+            Ok(selected)
+        }
         ConsoleCommand::List => {
-            turtles.lock().unwrap().iter()
-                .map(|t| &t.turtle.label)
-                .zip(0..)
-                .for_each(|(l, i)| println!("{}: {}", i, l));
+            fleet.list().iter()
+                .for_each(|r| println!("{}: {}\t{}\t{}", r.id, r.state.label, r.status.code(),
+                                       r.current_task.as_deref().unwrap_or("idle")));
             return Ok(selected)
         }
+        ConsoleCommand::Find => {
+            let name = input.next().ok_or_else(|| "Find requires 1 argument".to_string())?;
+            let world = fleet.world();
+            let mut found: Vec<_> = world.find(|b| b.name().is_some_and(|n| n.contains(name))).collect();
+            found.sort_by_key(|(c, _)| (c.x(), c.y(), c.z()));
+            found.iter()
+                .for_each(|(c, b)| println!("{} {} {}\t{}", c.x(), c.y(), c.z(), b.name().unwrap_or("?")));
+            Ok(selected)
+        }
         _ => {Err("Not implemented".to_string())}
     }
 }
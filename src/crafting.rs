@@ -0,0 +1,447 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+
+use json::JsonValue;
+
+use crate::decode::{DecodeError, expect_array, expect_object, expect_str, expect_u8, field_with};
+use crate::turtle::Inventory;
+
+const STACK_SIZE: u8 = 64;
+
+/// What goes into the crafting grid
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Ingredients {
+    /// Rows from the top, at most 3 by 3, `None` for empty cells
+    Shaped(Vec<Vec<Option<String>>>),
+    /// At most 9 items in any arrangement
+    Shapeless(Vec<String>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Recipe {
+    pub output: String,
+    /// Items one craft makes
+    pub count: u8,
+    pub ingredients: Ingredients,
+}
+
+impl Recipe {
+    /// A shaped recipe from rows of characters and what they stand for, spaces are empty cells
+    pub fn shaped(output: &str, count: u8, rows: &[&str], key: &[(char, &str)]) -> Self {
+        let key: HashMap<char, &str> = key.iter().copied().collect();
+        let rows = rows.iter()
+            .map(|row| row.chars().map(|c| key.get(&c).map(|name| name.to_string())).collect())
+            .collect();
+        Self { output: output.to_owned(), count, ingredients: Ingredients::Shaped(rows) }
+    }
+
+    pub fn shapeless(output: &str, count: u8, ingredients: &[&str]) -> Self {
+        let ingredients = ingredients.iter().map(|name| name.to_string()).collect();
+        Self { output: output.to_owned(), count, ingredients: Ingredients::Shapeless(ingredients) }
+    }
+
+    /// The grid slots (1-based) and the item for each. Shaped recipes go in the top left corner,
+    /// shapeless ones fill the grid row by row.
+    fn layout(&self) -> Result<Vec<(usize, &str)>, String> {
+        let slot = |x: usize, y: usize| Inventory::coord_to_slot(x as u8, y as u8) as usize + 1;
+        let layout: Vec<(usize, &str)> = match &self.ingredients {
+            Ingredients::Shaped(rows) => {
+                if rows.len() > 3 || rows.iter().any(|row| row.len() > 3) {
+                    return Err(format!("Recipe for {} is larger than 3x3", self.output));
+                }
+                rows.iter().enumerate()
+                    .flat_map(|(y, row)| row.iter().enumerate()
+                        .filter_map(move |(x, cell)| cell.as_deref().map(|name| (slot(x, y), name))))
+                    .collect()
+            }
+            Ingredients::Shapeless(items) => {
+                if items.len() > 9 {
+                    return Err(format!("Recipe for {} has more than 9 ingredients", self.output));
+                }
+                items.iter().enumerate().map(|(i, name)| (slot(i % 3, i / 3), name.as_str())).collect()
+            }
+        };
+        if layout.is_empty() {
+            return Err(format!("Recipe for {} has no ingredients", self.output));
+        }
+        Ok(layout)
+    }
+
+    /// How often the recipe can be crafted at once with what is in the inventory
+    pub fn max_crafts(&self, inventory: &Inventory) -> u8 {
+        let layout = match self.layout() {
+            Ok(layout) => layout,
+            Err(_) => return 0,
+        };
+        let mut cells: BTreeMap<&str, u32> = BTreeMap::new();
+        for (_, name) in layout.iter() {
+            *cells.entry(name).or_insert(0) += 1;
+        }
+        cells.iter()
+            .map(|(name, cells)| count(inventory, name) / cells)
+            .min()
+            .unwrap_or(0)
+            .min((STACK_SIZE / self.count.max(1)) as u32) as u8
+    }
+}
+
+fn count(inventory: &Inventory, name: &str) -> u32 {
+    inventory.find_all(|i| i.name == name).map(|(i, _)| i.count as u32).sum()
+}
+
+/// `{"output": name, "count": 1, "pattern": ["PPP", "P P", "PPP"], "key": {"P": name}}` for shaped
+/// recipes, `{"output": name, "count": 1, "ingredients": [name, ...]}` for shapeless ones. `count`
+/// defaults to 1.
+impl TryFrom<&JsonValue> for Recipe {
+    type Error = DecodeError;
+
+    fn try_from(jv: &JsonValue) -> Result<Self, Self::Error> {
+        expect_object(jv)?;
+        let output = field_with(jv, "output", expect_str)?;
+        let count = match &jv["count"] {
+            JsonValue::Null => 1,
+            count => expect_u8(count).map_err(|e| e.at("count"))?,
+        };
+        if jv.has_key("pattern") {
+            let rows = field_with(jv, "pattern", expect_array)?.iter().enumerate()
+                .map(|(i, row)| expect_str(row).map_err(|e| e.at_index(i).at("pattern")))
+                .collect::<Result<Vec<&str>, DecodeError>>()?;
+            let mut key = Vec::new();
+            for (c, name) in field_with(jv, "key", expect_object)?.iter() {
+                let c = match c.chars().collect::<Vec<char>>().as_slice() {
+                    [c] => *c,
+                    _ => return Err(DecodeError::expected("single character", &c.into()).at("key")),
+                };
+                key.push((c, expect_str(name).map_err(|e| e.at(&c.to_string()).at("key"))?));
+            }
+            Ok(Self::shaped(output, count, &rows, &key))
+        } else {
+            let ingredients = field_with(jv, "ingredients", expect_array)?.iter().enumerate()
+                .map(|(i, name)| expect_str(name).map_err(|e| e.at_index(i).at("ingredients")))
+                .collect::<Result<Vec<&str>, DecodeError>>()?;
+            Ok(Self::shapeless(output, count, &ingredients))
+        }
+    }
+}
+
+/// Move `count` items from one slot to another (1-based, like in lua)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Transfer {
+    pub from: usize,
+    pub to: usize,
+    pub count: u8,
+}
+
+/// How to get the inventory ready for crafting and craft
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CraftPlan {
+    /// `(slot, count)` to put into the inventory in front while crafting, because the turtle can
+    /// only craft with nothing but the recipe in its inventory. That inventory has to be empty, so
+    /// everything in it can be taken back.
+    pub stash: Vec<(usize, u8)>,
+    /// Lays out the grid after stashing
+    pub moves: Vec<Transfer>,
+    /// Where the result goes, empty when crafting
+    pub slot: usize,
+    pub crafts: u8,
+}
+
+/// Plans to craft the recipe `crafts` times: everything the grid does not need is stashed, then
+/// the ingredients are moved so every grid slot holds `crafts` of its item and nothing else does.
+/// Assumes items stack to 64.
+pub fn plan_craft(recipe: &Recipe, inventory: &Inventory, crafts: u8) -> Result<CraftPlan, String> {
+    let layout = recipe.layout()?;
+    if crafts == 0 || crafts > STACK_SIZE {
+        return Err(format!("Can not craft {} {} times at once", recipe.output, crafts));
+    }
+    let mut needed: BTreeMap<&str, u32> = BTreeMap::new();
+    for (_, name) in layout.iter() {
+        *needed.entry(name).or_insert(0) += crafts as u32;
+    }
+    for (name, count) in needed.iter() {
+        let has = self::count(inventory, name);
+        if has < *count {
+            return Err(format!("Needs {} {} to craft {} {} times, has {}", count, name, recipe.output, crafts, has));
+        }
+    }
+
+    let mut slots: Vec<Option<(String, u8)>> = vec![None; 17];
+    for (item, slot) in inventory.item_iter() {
+        slots[slot] = Some((item.name.clone(), item.count));
+    }
+    let target = |slot: usize| layout.iter().find(|(s, _)| *s == slot).map(|(_, name)| *name);
+
+    // Keep what the grid needs, taking from the slots where it goes first
+    let mut stash = Vec::new();
+    let mut keep = needed.clone();
+    let mut order: Vec<usize> = (1..=16).collect();
+    order.sort_by_key(|&slot| target(slot).is_none());
+    for slot in order {
+        if let Some((name, count)) = &mut slots[slot] {
+            let kept = keep.get_mut(name.as_str()).map_or(0, |k| {
+                let kept = (*k).min(*count as u32);
+                *k -= kept;
+                kept as u8
+            });
+            if kept < *count {
+                stash.push((slot, *count - kept));
+                *count = kept;
+            }
+            if *count == 0 {
+                slots[slot] = None;
+            }
+        }
+    }
+    stash.sort();
+
+    let mut moves = Vec::new();
+    let mut transfer = |slots: &mut Vec<Option<(String, u8)>>, from: usize, to: usize, count: u8| {
+        let name = slots[from].as_ref().unwrap().0.clone();
+        let source = slots[from].as_mut().unwrap();
+        source.1 -= count;
+        if source.1 == 0 {
+            slots[from] = None;
+        }
+        slots[to].get_or_insert((name, 0)).1 += count;
+        moves.push(Transfer { from, to, count });
+    };
+    // An empty slot outside the grid if there is one, the grid slots still to be laid out otherwise
+    let free_slot = |slots: &Vec<Option<(String, u8)>>, done: &[usize]| (1..=16)
+        .filter(|&s| slots[s].is_none() && !done.contains(&s))
+        .min_by_key(|&s| target(s).is_some());
+
+    let mut done = Vec::new();
+    for (slot, name) in layout.iter().copied() {
+        done.push(slot);
+        match &slots[slot] {
+            Some((other, count)) if other != name => {
+                let (count, to) = (*count, free_slot(&slots, &done).ok_or("No free slot")?);
+                transfer(&mut slots, slot, to, count);
+            }
+            Some((_, count)) if *count > crafts => {
+                let (count, to) = (*count - crafts, free_slot(&slots, &done).ok_or("No free slot")?);
+                transfer(&mut slots, slot, to, count);
+            }
+            _ => {}
+        }
+        while slots[slot].as_ref().map_or(0, |(_, count)| *count) < crafts {
+            let from = (1..=16)
+                .filter(|s| !done.contains(s))
+                .find(|&s| slots[s].as_ref().is_some_and(|(n, _)| n == name))
+                .ok_or_else(|| format!("Ran out of {} laying out the grid", name))?;
+            let missing = crafts - slots[slot].as_ref().map_or(0, |(_, count)| *count);
+            let count = missing.min(slots[from].as_ref().unwrap().1);
+            transfer(&mut slots, from, slot, count);
+        }
+    }
+
+    let slot = (1..=16).rev().find(|&s| slots[s].is_none()).ok_or("No slot left for the result")?;
+    Ok(CraftPlan { stash, moves, slot, crafts })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simulator::{Block, World};
+    use crate::simulator::testing::{finish, start_with};
+    use crate::turtle::{Coordinate, Item};
+
+    use super::*;
+
+    /// An inventory of `(slot, count, name)`, slots are 1-based
+    fn inventory(items: &[(usize, u8, &str)]) -> Inventory {
+        let mut slots = vec![JsonValue::Null; 16];
+        for &(slot, count, name) in items {
+            slots[slot - 1] = json::object! { c: count, n: name };
+        }
+        Inventory::try_from(&JsonValue::Array(slots)).unwrap()
+    }
+
+    fn chest() -> Recipe {
+        Recipe::shaped("minecraft:chest", 1, &["PPP", "P P", "PPP"], &[('P', "minecraft:oak_planks")])
+    }
+
+    fn torch() -> Recipe {
+        Recipe::shaped("minecraft:torch", 4, &["C", "S"], &[('C', "minecraft:coal"), ('S', "minecraft:stick")])
+    }
+
+    fn planks() -> Recipe {
+        Recipe::shaped("minecraft:oak_planks", 4, &["L"], &[('L', "minecraft:oak_log")])
+    }
+
+    #[test]
+    fn recipes_decode_from_json() {
+        let cases = [
+            (r#"{"output": "minecraft:chest", "pattern": ["PPP", "P P", "PPP"], "key": {"P": "minecraft:oak_planks"}}"#, chest()),
+            (r#"{"output": "minecraft:torch", "count": 4, "pattern": ["C", "S"], "key": {"C": "minecraft:coal", "S": "minecraft:stick"}}"#, torch()),
+            (r#"{"output": "minecraft:blue_wool", "ingredients": ["minecraft:blue_dye", "minecraft:white_wool"]}"#,
+             Recipe::shapeless("minecraft:blue_wool", 1, &["minecraft:blue_dye", "minecraft:white_wool"])),
+        ];
+        for (input, expected) in cases.iter() {
+            assert_eq!(&Recipe::try_from(&json::parse(input).unwrap()).unwrap(), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn bad_recipes_are_errors() {
+        let cases = [
+            (r#"{"pattern": ["P"], "key": {"P": "minecraft:oak_planks"}}"#, "output"),
+            (r#"{"output": "minecraft:chest", "count": 300, "ingredients": []}"#, "count"),
+            (r#"{"output": "minecraft:chest", "pattern": ["P", 1], "key": {}}"#, "pattern[1]"),
+            (r#"{"output": "minecraft:chest", "pattern": ["P"], "key": {"PP": "minecraft:oak_planks"}}"#, "key"),
+            (r#"{"output": "minecraft:chest", "pattern": ["P"], "key": {"P": 1}}"#, "key.P"),
+            (r#"{"output": "minecraft:chest", "ingredients": ["minecraft:oak_planks", null]}"#, "ingredients[1]"),
+            (r#"{"output": "minecraft:chest"}"#, "ingredients"),
+        ];
+        for (input, path) in cases.iter() {
+            let error = Recipe::try_from(&json::parse(input).unwrap()).unwrap_err();
+            assert!(error.to_string().contains(path), "{} should fail at {}, got {}", input, path, error);
+        }
+    }
+
+    #[test]
+    fn recipes_that_do_not_fit_the_grid_are_not_crafted() {
+        let planks = inventory(&[(1, 64, "minecraft:oak_planks")]);
+        let cases = [
+            (r#"{"output": "minecraft:chest", "pattern": ["P", "P", "P", "P"], "key": {"P": "minecraft:oak_planks"}}"#, "larger than 3x3"),
+            (r#"{"output": "minecraft:chest", "pattern": ["PPPP"], "key": {"P": "minecraft:oak_planks"}}"#, "larger than 3x3"),
+            (r#"{"output": "minecraft:chest", "ingredients": ["minecraft:oak_planks", "minecraft:oak_planks",
+                "minecraft:oak_planks", "minecraft:oak_planks", "minecraft:oak_planks", "minecraft:oak_planks",
+                "minecraft:oak_planks", "minecraft:oak_planks", "minecraft:oak_planks", "minecraft:oak_planks"]}"#, "more than 9"),
+            (r#"{"output": "minecraft:chest", "ingredients": []}"#, "no ingredients"),
+        ];
+        for (input, message) in cases.iter() {
+            let recipe = Recipe::try_from(&json::parse(input).unwrap()).unwrap();
+            assert_eq!(recipe.max_crafts(&planks), 0, "{}", input);
+            let error = plan_craft(&recipe, &planks, 1).unwrap_err();
+            assert!(error.contains(message), "{}: expected {}, got {}", input, message, error);
+        }
+    }
+
+    #[test]
+    fn max_crafts_counts_every_slot_and_one_stack_of_output() {
+        let cases = [
+            (chest(), vec![(1, 20, "minecraft:oak_planks")], 2),
+            (chest(), vec![(3, 5, "minecraft:oak_planks"), (16, 3, "minecraft:oak_planks")], 1),
+            (chest(), vec![(1, 7, "minecraft:oak_planks")], 0),
+            (torch(), vec![(1, 3, "minecraft:coal"), (2, 10, "minecraft:stick")], 3),
+            (torch(), vec![(2, 10, "minecraft:stick")], 0),
+            // 64 logs make 256 planks, but only one stack fits the result slot
+            (planks(), vec![(1, 64, "minecraft:oak_log")], 16),
+            (Recipe::shapeless("minecraft:blue_wool", 1, &["minecraft:blue_dye", "minecraft:blue_dye"]),
+             vec![(5, 9, "minecraft:blue_dye")], 4),
+        ];
+        for (recipe, items, expected) in cases.iter() {
+            assert_eq!(recipe.max_crafts(&inventory(items)), *expected, "{} with {:?}", recipe.output, items);
+        }
+    }
+
+    #[test]
+    fn plans_stash_what_the_grid_does_not_need_and_lay_it_out() {
+        let transfer = |from, to, count| Transfer { from, to, count };
+        let cases = [
+            (planks(), vec![(1, 5, "minecraft:oak_log"), (2, 5, "minecraft:dirt")], 3,
+             CraftPlan { stash: vec![(1, 2), (2, 5)], moves: vec![], slot: 16, crafts: 3 }),
+            // Sticks where the coal goes are moved out of the way first
+            (torch(), vec![(1, 2, "minecraft:stick"), (2, 2, "minecraft:coal")], 2,
+             CraftPlan { stash: vec![], moves: vec![transfer(1, 3, 2), transfer(2, 1, 2), transfer(3, 5, 2)], slot: 16, crafts: 2 }),
+            (Recipe::shapeless("minecraft:blue_wool", 1, &["minecraft:blue_dye", "minecraft:white_wool"]),
+             vec![(4, 1, "minecraft:white_wool"), (8, 1, "minecraft:blue_dye")], 1,
+             CraftPlan { stash: vec![], moves: vec![transfer(8, 1, 1), transfer(4, 2, 1)], slot: 16, crafts: 1 }),
+            // What a grid slot holds too much of goes out of the grid, and the other grid slots
+            // are filled from there
+            (chest(), vec![(1, 10, "minecraft:oak_planks"), (4, 10, "minecraft:oak_planks")], 1,
+             CraftPlan { stash: vec![(1, 2), (4, 10)], moves: vec![transfer(1, 4, 7), transfer(4, 2, 1), transfer(4, 3, 1), transfer(4, 5, 1),
+                                                                    transfer(4, 7, 1), transfer(4, 9, 1), transfer(4, 10, 1), transfer(4, 11, 1)],
+                         slot: 16, crafts: 1 }),
+        ];
+        for (recipe, items, crafts, expected) in cases.iter() {
+            assert_eq!(&plan_craft(recipe, &inventory(items), *crafts).unwrap(), expected, "{} with {:?}", recipe.output, items);
+        }
+    }
+
+    #[test]
+    fn impossible_plans_are_errors() {
+        let mut full = Vec::new();
+        for slot in 1..=8 {
+            full.push((slot, 1, "minecraft:stick"));
+            full.push((slot + 8, 1, "minecraft:coal"));
+        }
+        let cases = [
+            (torch(), vec![(1, 3, "minecraft:coal"), (2, 2, "minecraft:stick")], 3, "Needs 3 minecraft:stick"),
+            (torch(), vec![(1, 3, "minecraft:coal"), (2, 3, "minecraft:stick")], 0, "at once"),
+            (planks(), vec![(1, 64, "minecraft:oak_log"), (2, 64, "minecraft:oak_log")], 65, "at once"),
+            // Every slot holds something the grid needs, so the stick in the coal slot has nowhere to go
+            (torch(), full, 8, "No free slot"),
+        ];
+        for (recipe, items, crafts, message) in cases.iter() {
+            let error = plan_craft(recipe, &inventory(items), *crafts).unwrap_err();
+            assert!(error.contains(message), "{} {} times with {:?}: expected {}, got {}", recipe.output, crafts, items, message, error);
+        }
+    }
+
+    #[test]
+    fn craft_lays_out_the_grid_and_stashes_the_rest() {
+        let mut world = World::new();
+        world.set_block(Coordinate::new(0, 0, -1), Some(Block::new("minecraft:chest")));
+        let (mut executor, handle) = start_with(world, 1000, |t| {
+            t.inventory[0] = Some(Item { count: 10, name: "minecraft:dirt".to_owned() });
+            t.inventory[1] = Some(Item { count: 20, name: "minecraft:oak_planks".to_owned() });
+            t.inventory[4] = Some(Item { count: 5, name: "minecraft:oak_planks".to_owned() });
+            // In the middle of the grid, where the chest has a hole
+            t.inventory[5] = Some(Item { count: 3, name: "minecraft:cobblestone".to_owned() });
+            t.inventory[13] = Some(Item { count: 10, name: "minecraft:oak_planks".to_owned() });
+        });
+        let chest = Recipe::shaped("minecraft:chest", 1, &["PPP", "P P", "PPP"], &[('P', "minecraft:oak_planks")]);
+
+        executor.refresh_state().unwrap().unwrap();
+        assert_eq!(chest.max_crafts(&executor.turtle.inventory), 4);
+        assert!(executor.craft(&chest, Some(5)).unwrap().is_err());
+        assert_eq!(executor.craft(&chest, None).unwrap(), Ok(4));
+        let count = |name: &str| executor.turtle.inventory.find_all(|i| i.name == name).map(|(i, _)| i.count as u32).sum::<u32>();
+        assert_eq!(count("minecraft:chest"), 4);
+        assert_eq!(count("minecraft:oak_planks"), 35 - 4 * 8);
+
+        let turtle = finish(executor, handle);
+        assert_eq!(turtle.item_count("minecraft:chest"), 4);
+        assert_eq!(turtle.item_count("minecraft:dirt"), 10);
+        assert_eq!(turtle.item_count("minecraft:cobblestone"), 3);
+        assert!(turtle.world.block(Coordinate::new(0, 0, -1)).unwrap().contents.is_empty());
+    }
+
+    #[test]
+    fn failed_craft_takes_back_what_it_stashed() {
+        let mut world = World::new();
+        world.set_block(Coordinate::new(0, 0, -1), Some(Block::new("minecraft:chest")));
+        let (mut executor, handle) = start_with(world, 1000, |t| {
+            t.inventory[0] = Some(Item { count: 4, name: "minecraft:oak_planks".to_owned() });
+            t.inventory[1] = Some(Item { count: 10, name: "minecraft:dirt".to_owned() });
+        });
+        // The simulator only knows planks and chests
+        let table = Recipe::shaped("minecraft:crafting_table", 1, &["PP", "PP"], &[('P', "minecraft:oak_planks")]);
+
+        assert!(executor.craft(&table, Some(1)).unwrap().is_err());
+        let turtle = finish(executor, handle);
+        assert_eq!(turtle.item_count("minecraft:oak_planks"), 4);
+        assert_eq!(turtle.item_count("minecraft:dirt"), 10);
+        assert!(turtle.world.block(Coordinate::new(0, 0, -1)).unwrap().contents.is_empty());
+    }
+
+    #[test]
+    fn craft_stashes_only_into_an_empty_inventory() {
+        let mut world = World::new();
+        let mut chest = Block::new("minecraft:chest");
+        chest.contents.push(Item { count: 5, name: "minecraft:dirt".to_owned() });
+        world.set_block(Coordinate::new(0, 0, -1), Some(chest));
+        let (mut executor, handle) = start_with(world, 1000, |t| {
+            t.inventory[0] = Some(Item { count: 8, name: "minecraft:oak_planks".to_owned() });
+            t.inventory[1] = Some(Item { count: 10, name: "minecraft:dirt".to_owned() });
+        });
+        let chest = Recipe::shaped("minecraft:chest", 1, &["PPP", "P P", "PPP"], &[('P', "minecraft:oak_planks")]);
+
+        assert!(executor.craft(&chest, Some(1)).unwrap().is_err());
+        let turtle = finish(executor, handle);
+        assert_eq!(turtle.inventory[1].as_ref().map(|i| i.count), Some(10));
+        assert_eq!(turtle.item_count("minecraft:chest"), 0);
+        assert_eq!(turtle.world.block(Coordinate::new(0, 0, -1)).unwrap().contents.len(), 1);
+    }
+}
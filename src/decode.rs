@@ -0,0 +1,213 @@
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+
+use json::JsonValue;
+
+/// Error produced when a json message from a turtle does not have the expected shape.
+/// Keeps track of the path into the message at which decoding failed.
+#[derive(Debug)]
+pub struct DecodeError {
+    path: Vec<String>,
+    kind: DecodeErrorKind,
+}
+
+#[derive(Debug)]
+pub enum DecodeErrorKind {
+    Expected(&'static str, String),
+    UnknownCode(&'static str, String),
+    Length(usize, usize),
+}
+
+impl DecodeError {
+    pub fn new(kind: DecodeErrorKind) -> Self {
+        Self { path: Vec::new(), kind }
+    }
+
+    pub fn expected(what: &'static str, got: &JsonValue) -> Self {
+        Self::new(DecodeErrorKind::Expected(what, got.dump()))
+    }
+
+    pub fn unknown_code(what: &'static str, code: &str) -> Self {
+        Self::new(DecodeErrorKind::UnknownCode(what, code.to_owned()))
+    }
+
+    pub fn length(expected: usize, got: usize) -> Self {
+        Self::new(DecodeErrorKind::Length(expected, got))
+    }
+
+    /// Prefixes the path of this error with an object key
+    pub fn at(mut self, key: &str) -> Self {
+        self.path.insert(0, format!(".{}", key));
+        self
+    }
+
+    /// Prefixes the path of this error with an array index
+    pub fn at_index(mut self, index: usize) -> Self {
+        self.path.insert(0, format!("[{}]", index));
+        self
+    }
+
+    pub fn path(&self) -> String {
+        format!("${}", self.path.concat())
+    }
+
+    pub fn kind(&self) -> &DecodeErrorKind {
+        &self.kind
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "at {}: ", self.path())?;
+        match &self.kind {
+            DecodeErrorKind::Expected(what, got) => write!(f, "expected {}, got {}", what, got),
+            DecodeErrorKind::UnknownCode(what, code) => write!(f, "unknown {} {}", what, code),
+            DecodeErrorKind::Length(expected, got) => write!(f, "expected {} entries, got {}", expected, got),
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+pub fn expect_object(jv: &JsonValue) -> Result<&json::object::Object, DecodeError> {
+    match jv {
+        JsonValue::Object(o) => Ok(o),
+        _ => Err(DecodeError::expected("object", jv)),
+    }
+}
+
+pub fn expect_array(jv: &JsonValue) -> Result<&Vec<JsonValue>, DecodeError> {
+    match jv {
+        JsonValue::Array(v) => Ok(v),
+        _ => Err(DecodeError::expected("array", jv)),
+    }
+}
+
+pub fn expect_str(jv: &JsonValue) -> Result<&str, DecodeError> {
+    jv.as_str().ok_or_else(|| DecodeError::expected("string", jv))
+}
+
+pub fn expect_bool(jv: &JsonValue) -> Result<bool, DecodeError> {
+    jv.as_bool().ok_or_else(|| DecodeError::expected("boolean", jv))
+}
+
+pub fn expect_i64(jv: &JsonValue) -> Result<i64, DecodeError> {
+    jv.as_i64().ok_or_else(|| DecodeError::expected("integer", jv))
+}
+
+pub fn expect_u8(jv: &JsonValue) -> Result<u8, DecodeError> {
+    jv.as_u8().ok_or_else(|| DecodeError::expected("integer between 0 and 255", jv))
+}
+
+pub fn expect_u32(jv: &JsonValue) -> Result<u32, DecodeError> {
+    jv.as_u32().ok_or_else(|| DecodeError::expected("non negative integer", jv))
+}
+
+pub fn expect_u64(jv: &JsonValue) -> Result<u64, DecodeError> {
+    jv.as_u64().ok_or_else(|| DecodeError::expected("non negative integer", jv))
+}
+
+pub fn expect_usize(jv: &JsonValue) -> Result<usize, DecodeError> {
+    jv.as_usize().ok_or_else(|| DecodeError::expected("non negative integer", jv))
+}
+
+/// Decodes the value under `key` of a json object, adding `key` to the path of any error
+pub fn field<'a, T>(jv: &'a JsonValue, key: &str) -> Result<T, DecodeError>
+    where T: TryFrom<&'a JsonValue, Error=DecodeError> {
+    T::try_from(&jv[key]).map_err(|e| e.at(key))
+}
+
+/// Like `field`, but for primitive values that are decoded with one of the `expect_` functions
+pub fn field_with<'a, T, F>(jv: &'a JsonValue, key: &str, f: F) -> Result<T, DecodeError>
+    where F: FnOnce(&'a JsonValue) -> Result<T, DecodeError> {
+    f(&jv[key]).map_err(|e| e.at(key))
+}
+
+/// Decodes every entry of a json array of exactly 16 entries, as used for inventories
+pub fn slots<'a, T, F>(jv: &'a JsonValue, mut f: F) -> Result<[T; 16], DecodeError>
+    where F: FnMut(&'a JsonValue) -> Result<T, DecodeError> {
+    let v = expect_array(jv)?;
+    let len = v.len();
+    let slots = v.iter().enumerate()
+        .map(|(i, s)| f(s).map_err(|e| e.at_index(i)))
+        .collect::<Result<Vec<T>, DecodeError>>()?;
+    <[T; 16]>::try_from(slots).map_err(|_| DecodeError::length(16, len))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use json::JsonValue;
+
+    use crate::turtle::{Inventory, TurtleState};
+    use crate::turtle_websocket::UpEvent;
+
+    use super::*;
+
+    #[test]
+    fn paths_are_built_from_the_inside_out() {
+        let e = DecodeError::expected("string", &JsonValue::from(3)).at("n").at_index(2).at("b");
+        assert_eq!(e.path(), "$.b[2].n");
+        assert_eq!(e.to_string(), "at $.b[2].n: expected string, got 3");
+        assert_eq!(DecodeError::length(16, 3).path(), "$");
+    }
+
+    #[test]
+    fn fields_report_their_key() {
+        let jv = json::object! { a: "x", b: { c: "not a number" } };
+        assert_eq!(field_with(&jv, "a", expect_str).unwrap(), "x");
+        assert_eq!(field_with(&jv, "missing", expect_str).unwrap_err().to_string(), "at $.missing: expected string, got null");
+        let e = field_with(&jv["b"], "c", expect_i64).unwrap_err().at("b");
+        assert_eq!(e.path(), "$.b.c");
+        assert!(matches!(e.kind(), DecodeErrorKind::Expected("integer", got) if got == "\"not a number\""));
+        assert!(expect_u8(&JsonValue::from(256)).is_err());
+        assert!(expect_u32(&JsonValue::from(-1)).is_err());
+        assert!(expect_object(&json::array![]).is_err());
+        assert!(expect_array(&json::object! {}).is_err());
+    }
+
+    #[test]
+    fn slots_need_16_valid_entries() {
+        let sixteen: Vec<JsonValue> = (0..16).map(JsonValue::from).collect();
+        assert_eq!(slots(&sixteen.clone().into(), expect_i64).unwrap()[15], 15);
+
+        let e = slots(&json::array![1, 2, 3], expect_i64).unwrap_err();
+        assert!(matches!(e.kind(), DecodeErrorKind::Length(16, 3)));
+
+        let mut bad = sixteen;
+        bad[4] = "four".into();
+        let e = slots(&bad.into(), expect_i64).unwrap_err();
+        assert_eq!(e.path(), "$[4]");
+        assert!(slots(&JsonValue::Null, expect_i64).is_err());
+    }
+
+    #[test]
+    fn malformed_messages_are_errors() {
+        let cases = [
+            ("[]", "$"),
+            (r#"{"b": 1}"#, "$.c"),
+            (r#"{"c": "dance"}"#, "$.c"),
+            (r#"{"c": "move_response", "b": {"e": "blocked"}}"#, "$.b.c"),
+            (r#"{"c": "move_response", "b": {"e": "blocked", "c": 1, "r": 3}}"#, "$.b.r"),
+            (r#"{"c": "position_update", "b": {"coordinate": {"x": 1, "y": 2, "z": 3}, "direction": "up"}}"#, "$.b.direction"),
+            (r#"{"c": "position_update", "b": {"coordinate": {"x": 1, "y": "2", "z": 3}, "direction": "N"}}"#, "$.b.coordinate.y"),
+            (r#"{"c": "state_update", "b": {"label": "t", "position": {"coordinate": {"x": 0, "y": 0, "z": 0}, "direction": "N"}}}"#, "$.b.fuel_level"),
+        ];
+        for (message, path) in cases.iter() {
+            let jv = json::parse(message).unwrap();
+            match UpEvent::try_from(&jv) {
+                Err(e) => assert_eq!(e.path(), *path, "{}: {}", message, e),
+                Ok(event) => panic!("{} decoded to {:?}", message, event),
+            }
+        }
+
+        let mut inventory: Vec<JsonValue> = vec![JsonValue::Null; 16];
+        inventory[3] = json::object! { n: "minecraft:dirt" };
+        let e = Inventory::try_from(&JsonValue::from(inventory)).unwrap_err();
+        assert_eq!(e.path(), "$[3].c");
+        let state = json::object! { label: 5 };
+        assert_eq!(TurtleState::try_from(&state).unwrap_err().path(), "$.label");
+    }
+}
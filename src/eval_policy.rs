@@ -0,0 +1,164 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::env;
+
+/// Allowed when `EVAL_ALLOW` is not set
+const DEFAULT_ALLOW: &str = "turtle,textutils,vector,os.getComputerID,os.getComputerLabel";
+
+/// Globals of lua and ComputerCraft that reach outside the eval. Code may only use them as far as
+/// the allow list permits; everything else (locals, `math`, `string`...) is not checked.
+const GUARDED_GLOBALS: &[&str] = &[
+    "_ENV", "_G", "colors", "colours", "commands", "coroutine", "debug", "disk", "dofile", "fs",
+    "getfenv", "getmetatable", "gps", "http", "io", "keys", "load", "loadfile", "loadstring",
+    "multishell", "os", "package", "paintutils", "parallel", "peripheral", "rawequal", "rawget",
+    "rawset", "rednet", "redstone", "require", "rs", "setfenv", "setmetatable", "settings",
+    "shell", "term", "textutils", "turtle", "vector", "window",
+];
+
+/// Decides which lua a turtle may be sent with `Command::Eval` and records every eval.
+/// The allow list holds APIs (`turtle`) or single functions (`os.getComputerLabel`) and is sent
+/// along with the code, the turtle only puts those in the environment the code runs in. The
+/// check here rejects code early with a readable error.
+///
+/// Configured with `EVAL_ALLOW` (comma separated) and `EVAL_AUDIT_FILE` (default
+/// `eval_audit.log`), which gets a json line per eval.
+pub struct EvalPolicy {
+    allowed: Vec<String>,
+    /// Where evals are recorded, `None` only prints them
+    audit_path: Option<String>,
+}
+
+impl Default for EvalPolicy {
+    fn default() -> Self {
+        Self { allowed: Self::parse_allow(DEFAULT_ALLOW), audit_path: None }
+    }
+}
+
+impl EvalPolicy {
+    pub fn load() -> Self {
+        let allow = env::var("EVAL_ALLOW").unwrap_or(String::from(DEFAULT_ALLOW));
+        let audit_path = env::var("EVAL_AUDIT_FILE").unwrap_or(String::from("eval_audit.log"));
+        Self { allowed: Self::parse_allow(allow.as_str()), audit_path: Some(audit_path) }
+    }
+
+    fn parse_allow(s: &str) -> Vec<String> {
+        s.split(',').map(str::trim).filter(|a| !a.is_empty()).map(str::to_owned).collect()
+    }
+
+    pub fn allowed(&self) -> &[String] {
+        &self.allowed
+    }
+
+    /// Rejects code that uses a guarded global the allow list does not cover
+    pub fn check(&self, code: &str) -> Result<(), String> {
+        for name in referenced_names(code) {
+            let root = name.split('.').next().unwrap_or("");
+            if GUARDED_GLOBALS.contains(&root) && !self.permits(name.as_str()) {
+                return Err(format!("{} is not allowed in eval", name));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether an allowed API or function covers the name, `turtle` covers `turtle.forward`
+    fn permits(&self, name: &str) -> bool {
+        self.allowed.iter().any(|a| name == a || name.strip_prefix(a.as_str()).is_some_and(|rest| rest.starts_with('.')))
+    }
+
+    /// Records who evaluated what on which turtle, and how it went
+    pub fn audit(&self, by: &str, turtle: u32, code: &str, outcome: &str) {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let entry = json::object! {
+            time: time,
+            by: by,
+            turtle: turtle,
+            code: code,
+            outcome: outcome,
+        };
+        println!("Eval audit: {}", entry);
+        let path = match &self.audit_path {
+            Some(path) => path,
+            None => return,
+        };
+        let written = OpenOptions::new().create(true).append(true).open(path)
+            .and_then(|mut f| writeln!(f, "{}", json::stringify(entry)));
+        if let Err(e) = written {
+            eprintln!("Could not write eval audit log {}: {}", path, e);
+        }
+    }
+}
+
+/// Dotted names (`turtle.forward`, `os.getComputerLabel`) used in lua code, skipping strings and
+/// comments. Method calls (`a:b`) count as `a.b`.
+fn referenced_names(code: &str) -> Vec<String> {
+    let chars: Vec<char> = code.chars().collect();
+    let mut names = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c == '-' && chars.get(i + 1) == Some(&'-') {
+            i = skip_comment(&chars, i + 2);
+        } else if c == '"' || c == '\'' {
+            i = skip_quoted(&chars, i + 1, c);
+        } else if c == '[' && long_bracket_level(&chars, i).is_some() {
+            i = skip_long_bracket(&chars, i);
+        } else if c.is_alphabetic() || c == '_' {
+            // Takes the whole chain, so the names after the dots are not seen as names of their own
+            let mut name = String::new();
+            loop {
+                let start = i;
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                name.extend(&chars[start..i]);
+                let next = chars.get(i + 1).is_some_and(|c| c.is_alphabetic() || *c == '_');
+                if matches!(chars.get(i), Some('.') | Some(':')) && next {
+                    name.push('.');
+                    i += 1;
+                } else {
+                    break;
+                }
+            }
+            names.push(name);
+        } else {
+            i += 1;
+        }
+    }
+    names
+}
+
+fn skip_comment(chars: &[char], i: usize) -> usize {
+    if long_bracket_level(chars, i).is_some() {
+        return skip_long_bracket(chars, i);
+    }
+    chars[i..].iter().position(|c| *c == '\n').map_or(chars.len(), |p| i + p + 1)
+}
+
+fn skip_quoted(chars: &[char], mut i: usize, quote: char) -> usize {
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 2,
+            c if c == quote => return i + 1,
+            _ => i += 1,
+        }
+    }
+    chars.len()
+}
+
+/// The level of a long bracket (`[[` is 0, `[==[` is 2) opening at `i`
+fn long_bracket_level(chars: &[char], i: usize) -> Option<usize> {
+    if chars.get(i) != Some(&'[') {
+        return None;
+    }
+    let level = chars[i + 1..].iter().take_while(|c| **c == '=').count();
+    if chars.get(i + 1 + level) == Some(&'[') { Some(level) } else { None }
+}
+
+fn skip_long_bracket(chars: &[char], i: usize) -> usize {
+    let level = long_bracket_level(chars, i).unwrap_or(0);
+    let close: Vec<char> = std::iter::once(']').chain(std::iter::repeat_n('=', level)).chain(std::iter::once(']')).collect();
+    let start = i + level + 2;
+    chars[start..].windows(close.len()).position(|w| w == close.as_slice())
+        .map_or(chars.len(), |p| start + p + close.len())
+}
@@ -1,81 +1,233 @@
-use websocket::WebSocketResult;
-
-use crate::turtle::TurtleState;
-use crate::turtle_websocket::{Command, UpEvent, TurtleConnection, TaskCommand, ReceiveError};
+use crate::crafting::{self, Recipe};
+use crate::fleet::Fleet;
+use crate::fuel;
+use crate::maneuver::{Maneuver, MoveError, MoveFailure};
+use crate::pathfinding;
+use crate::task_registry::Task;
+use crate::turtle::{Coordinate, Direction, TurtleState};
+use crate::turtle_websocket::{Command, EvalResponse, UpEvent, TurtleConnection, TaskCommand, TaskQuestion, ReceiveError};
+use crate::world_map::WorldMap;
 use json::JsonValue;
+use std::collections::{HashSet, VecDeque};
 use std::error::Error;
+use std::sync::{mpsc, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Answers questions of a task, or returns `None` to leave them to an operator
+pub type QuestionHandler = fn(&TaskQuestion, &mut TaskExecutor) -> Option<JsonValue>;
+
+pub enum ExecutorCommand {
+    /// Lua code to evaluate and who wants it evaluated, for the audit log
+    Eval { code: String, by: String },
+    Move(Maneuver),
+    GoTo(Coordinate, Option<Direction>),
+    Task(Task, QuestionHandler),
+    /// A recipe and how often to craft it, as often as possible if `None`
+    Craft(Recipe, Option<u8>),
+    /// Does nothing, but cancels the running task when sent with `preempt`
+    Cancel,
+}
+
+pub enum ExecutorResponse {
+    Eval(EvalResponse),
+    Move(Result<(), (MoveError, usize)>),
+    GoTo(Result<(), MoveError>),
+    Task(bool),
+    /// How many items were crafted
+    Craft(Result<u32, String>),
+    Cancelled,
+}
+
+/// A command from outside the runner (e.g. the console) for the executor to run between tasks.
+/// If `preempt` is set, the running task is cancelled first.
+pub struct ExecutorRequest {
+    pub command: ExecutorCommand,
+    pub preempt: bool,
+    pub reply: mpsc::Sender<Result<ExecutorResponse, String>>,
+}
 
-pub enum Task {
-    Fell, FirstTree,
-    RefuelLogs(u8, u8),
-    Anon(String)
+/// How a task run ended
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TaskOutcome {
+    Finished,
+    /// Cancelled by the turtle, an event or question handler, or a preempting request
+    Cancelled,
+    /// Never sent to the turtle, e.g. because it could not get enough fuel
+    Skipped,
+    /// The turtle rebooted while running the task, so the task is gone but may be started again
+    Interrupted,
+    Disconnected,
 }
 
-impl Task {
-    pub fn code(&self) -> &str {
+impl TaskOutcome {
+    pub fn code(&self) -> &'static str {
         match self {
-            Task::Fell => "fell",
-            Task::FirstTree => "first_tree",
-            Task::RefuelLogs(_, _) => "refuel_logs",
-            Task::Anon(s) => s.as_str()
+            TaskOutcome::Finished => "finished",
+            TaskOutcome::Cancelled => "cancelled",
+            TaskOutcome::Skipped => "skipped",
+            TaskOutcome::Interrupted => "interrupted",
+            TaskOutcome::Disconnected => "disconnected",
         }
     }
+}
 
-    pub fn from_code(s: &str) -> Self {
-        match s {
-            "fell" => Task::Fell,
-            "first_tree" => Task::FirstTree,
-            _ => Task::Anon(s.to_lowercase()),
-        }
-    }
+/// A task the executor runs or ran. Tasks started while another one runs, from its handlers or
+/// to refuel for it, are its subtasks and link to it with `parent`.
+#[derive(Clone, Debug)]
+pub struct TaskRun {
+    pub id: u32,
+    pub parent: Option<u32>,
+    pub task: Task,
+    pub started: SystemTime,
+    pub ended: Option<SystemTime>,
+    pub outcome: Option<TaskOutcome>,
 }
 
-impl Into<JsonValue> for &Task {
-    fn into(self) -> JsonValue {
+impl From<&TaskRun> for JsonValue {
+    fn from(run: &TaskRun) -> Self {
+        let secs = |t: SystemTime| t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
         json::object! {
-            c: self.code(),
-            b: match self {
-                Task::RefuelLogs(slot, count) => json::object! {
-                    slot: *slot,
-                    count: *count
-                },
-                _ => JsonValue::Null,
-            }
+            id: run.id,
+            parent: run.parent,
+            task: run.task.code(),
+            arguments: run.task.arguments().clone(),
+            started: secs(run.started),
+            ended: run.ended.map(secs),
+            outcome: run.outcome.map(|o| o.code()),
         }
     }
 }
 
+/// How often `go_to` plans a new route after running into something
+const GO_TO_ATTEMPTS: usize = 5;
+/// How long the turtle may take to answer a command. Reconnects happen in the meantime, so these
+/// are generous.
+const EVAL_TIMEOUT: Duration = Duration::from_secs(60);
+const STATE_TIMEOUT: Duration = Duration::from_secs(30);
+const MOVE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Extra time a move command gets for each block it moves or digs
+const STEP_TIMEOUT: Duration = Duration::from_secs(2);
+/// How often a task waiting for an answer checks whether it should make way for a request
+const QUESTION_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// How long an operator has to answer a question the task does not declare
+const UNDECLARED_QUESTION_TIMEOUT: Duration = Duration::from_secs(300);
+
 pub struct TaskExecutor {
     pub turtle: TurtleState,
     pub connection: TurtleConnection,
+    fleet: Fleet,
+    requests: mpsc::Receiver<ExecutorRequest>,
+    pending_requests: VecDeque<ExecutorRequest>,
+    task_depth: usize,
+    /// The running task and the tasks it was started from, outermost first
+    task_stack: Vec<TaskRun>,
+    /// Runs lost to a reboot of the turtle, innermost first, until the runner takes them
+    interrupted: Vec<TaskRun>,
+    /// Whether `turtle.fuel_level` may be off, because a task moved the turtle since the last state
+    fuel_stale: bool,
 }
 
 impl TaskExecutor {
-    pub fn new(turtle: TurtleState, connection: TurtleConnection) -> TaskExecutor {
-        Self { turtle, connection }
+    pub fn new(turtle: TurtleState, connection: TurtleConnection, fleet: Fleet) -> TaskExecutor {
+        let (request_tx, requests) = mpsc::channel();
+        fleet.update_state(connection.id(), &turtle);
+        fleet.attach(connection.id(), request_tx);
+        Self { turtle, connection, fleet, requests, pending_requests: VecDeque::new(), task_depth: 0,
+               task_stack: Vec::new(), interrupted: Vec::new(), fuel_stale: false }
     }
 
-    // TODO deal with websocket errors better and maybe even internally
+    /// The world map shared with the rest of the fleet
+    pub fn world(&self) -> MutexGuard<'_, WorldMap> {
+        self.fleet.world()
+    }
+
+    /// Sends an event of this turtle to everyone following it, see `Fleet::publish`
+    pub fn publish(&self, event: &str, body: JsonValue) {
+        self.fleet.publish(self.connection.id(), event, body);
+    }
+
+    /// Takes the runs a reboot of the turtle interrupted since the last call, innermost first,
+    /// so they can be started again
+    pub fn take_interrupted(&mut self) -> Vec<TaskRun> {
+        std::mem::take(&mut self.interrupted)
+    }
+
+    /// Executes a task on the turtle. Requests queued for this executor are handled first,
+    /// unless this is a subtask started from within another task.
     pub fn execute<E, Q>(&mut self, task: Task, event_handler: E, question_handler: Q) -> Result<bool, Box<dyn Error>>
         where E: Fn(UpEvent, &mut TaskExecutor) -> bool,
-                Q: Fn(String, &mut TaskExecutor) -> JsonValue {
-        let command = match task {
-            Task::Anon(_) => Command::AnonTask(JsonValue::from(task.code())),
-            _ => Command::Task(task)
-        };
-        let mid = self.connection.send_command(command);
+                Q: Fn(&TaskQuestion, &mut TaskExecutor) -> Option<JsonValue> {
+        if self.task_depth == 0 {
+            self.handle_requests()?;
+        }
+        self.task_depth += 1;
+        let result = self.run_task(task, event_handler, question_handler);
+        self.task_depth -= 1;
+        if self.task_depth == 0 {
+            self.save_world();
+        }
+        result
+    }
+
+    // TODO deal with websocket errors better and maybe even internally
+    fn run_task<E, Q>(&mut self, task: Task, event_handler: E, question_handler: Q) -> Result<bool, Box<dyn Error>>
+        where E: Fn(UpEvent, &mut TaskExecutor) -> bool,
+                Q: Fn(&TaskQuestion, &mut TaskExecutor) -> Option<JsonValue> {
+        let id = self.connection.id();
+        let code = task.code().to_owned();
+        let estimate = self.fleet.tasks().fuel_estimate(&task);
+        let restarts = self.fleet.restarts(id);
+        self.begin_run(task.clone());
+        if let Some(estimate) = estimate {
+            let home = self.turtle.position.coordinate();
+            match self.ensure_fuel(estimate, home) {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    eprintln!("Not starting task {}: {}", code, e);
+                    self.end_run(if self.fleet.restarts(id) > restarts { TaskOutcome::Interrupted } else { TaskOutcome::Skipped });
+                    return Ok(false);
+                }
+                Err(e) => {
+                    self.end_run(TaskOutcome::Disconnected);
+                    return Err(e);
+                }
+            }
+        }
+        let expected_events = self.fleet.tasks().get(code.as_str()).map_or(&[][..], |t| t.events());
+        let task_mid = self.connection.send_command(Command::Task(task));
         let mut continue_execution = true;
         let mut successful_execution = true;
+        let mut cancel_sent = false;
         while continue_execution {
+            if self.fleet.restarts(id) > restarts {
+                println!("Task {} was interrupted by a reboot of turtle {}", task_mid, id);
+                self.end_run(TaskOutcome::Interrupted);
+                return Ok(false);
+            }
             let result = self.connection.receive_event();
             if let Err(e) = result {
                 match e {
-                    ReceiveError::WebsocketError(e) => return Err(e.into()),
-                    ReceiveError::MessageError(e) => eprintln!("Got unexpected message: {}", e)
+                    ReceiveError::Disconnected => {
+                        self.end_run(TaskOutcome::Disconnected);
+                        return Err(format!("Turtle {} disconnected", id).into())
+                    },
+                    ReceiveError::MessageError(e) => eprintln!("Got unexpected message: {}", e),
+                    ReceiveError::CommandTimeout(mid) => eprintln!("Command {} timed out", mid),
+                    ReceiveError::Timeout => {
+                        if self.poll_requests() && !cancel_sent {
+                            println!("Preempting task {}", task_mid);
+                            self.connection.send_task_command(TaskCommand::Cancel, task_mid);
+                            cancel_sent = true;
+                        }
+                    }
                 }
                 continue;
             }
-            let (event, mid, _) = result.unwrap();
+            let (event, mid, cid) = result.unwrap();
+            if cid != task_mid && self.answer_stale_event(&event, mid, cid) {
+                continue;
+            }
             continue_execution = match event {
                 UpEvent::TaskFinish => {
                     self.connection.send_task_command(TaskCommand::FinishResponse, mid);
@@ -85,23 +237,41 @@ impl TaskExecutor {
                     successful_execution = false;
                     false
                 }
-                UpEvent::StateUpdate(_) | UpEvent::PositionUpdate(_) | UpEvent::InventoryUpdate(_) => {
+                event if event.is_update() => {
+                    if !expected_events.contains(&event.code()) {
+                        eprintln!("Task {} sent unexpected event {}", code, event.code());
+                    }
                     self.handle_update_event(event);
                     true
                 }
-                UpEvent::TaskError(_) => {
+                UpEvent::TaskError(e) => {
+                    self.fleet.publish(id, "task_error", e.code().into());
+                    let event = UpEvent::TaskError(e);
                     let continue_execution = event_handler(event, self);
                     self.connection.send_task_command(TaskCommand::ErrorResponse(continue_execution), mid);
                     continue_execution
                 },
                 UpEvent::TaskQuestion(q) => {
-                    let answer = question_handler(q, self);
-                    self.connection.send_task_command(TaskCommand::QuestionResponse(answer), mid);
+                    self.fleet.publish(id, "task_question", (&q).into());
+                    match self.answer_question(code.as_str(), &q, &question_handler) {
+                        Some(answer) => {
+                            self.connection.send_task_command(TaskCommand::QuestionResponse(answer), mid);
+                        }
+                        None if !cancel_sent => {
+                            println!("No answer to {}, cancelling task {}", q.code, task_mid);
+                            self.connection.send_task_command(TaskCommand::Cancel, task_mid);
+                            cancel_sent = true;
+                        }
+                        None => {}
+                    }
                     true
                 }
                 event => {
+                    if let UpEvent::Error = event {
+                        self.fleet.publish(id, "error", format!("Turtle reported an error in task {}", code).into());
+                    }
                     if !event_handler(event, self) {
-                        self.connection.send_task_command(TaskCommand::Cancel, mid);
+                        self.connection.send_task_command(TaskCommand::Cancel, task_mid);
                         false
                     } else {
                         true
@@ -109,25 +279,362 @@ impl TaskExecutor {
                 }
             };
         };
+        self.end_run(if successful_execution { TaskOutcome::Finished } else { TaskOutcome::Cancelled });
         Ok(successful_execution)
     }
 
+    /// Lets a task that is not running anymore end, when it finishes, fails or asks something. Its
+    /// command came from a server that ran before, which can not answer it. Returns whether the
+    /// event was such a left over.
+    fn answer_stale_event(&mut self, event: &UpEvent, mid: u32, cid: u32) -> bool {
+        match event {
+            UpEvent::TaskFinish => {
+                self.connection.send_task_command(TaskCommand::FinishResponse, mid);
+            }
+            UpEvent::TaskError(_) => {
+                self.connection.send_task_command(TaskCommand::ErrorResponse(false), mid);
+            }
+            UpEvent::TaskQuestion(_) => {
+                self.connection.send_task_command(TaskCommand::Cancel, cid);
+            }
+            UpEvent::TaskCancelled => {}
+            _ => return false,
+        }
+        eprintln!("Ignoring {} of task {}, which is not running", event.code(), cid);
+        true
+    }
+
+    /// Puts a new run of the task on the stack, as a subtask of the running one
+    fn begin_run(&mut self, task: Task) {
+        let id = self.connection.id();
+        let run = TaskRun {
+            id: self.fleet.next_task_run(),
+            parent: self.task_stack.last().map(|r| r.id),
+            task,
+            started: SystemTime::now(),
+            ended: None,
+            outcome: None,
+        };
+        self.fleet.publish(id, "task_start", (&run).into());
+        self.task_stack.push(run);
+        self.fleet.set_task_stack(id, &self.task_stack);
+    }
+
+    /// Takes the innermost run off the stack and records how it ended
+    fn end_run(&mut self, outcome: TaskOutcome) {
+        let id = self.connection.id();
+        let Some(mut run) = self.task_stack.pop() else {
+            return;
+        };
+        run.ended = Some(SystemTime::now());
+        run.outcome = Some(outcome);
+        self.fleet.set_task_stack(id, &self.task_stack);
+        self.fleet.task_ended(id, &run);
+        let mut event = JsonValue::from(&run);
+        event["success"] = (outcome == TaskOutcome::Finished).into();
+        self.fleet.publish(id, "task_end", event);
+        if outcome == TaskOutcome::Interrupted {
+            self.interrupted.push(run);
+        }
+    }
+
+    /// Moves newly arrived requests to the pending queue.
+    /// Returns whether any pending request wants to preempt the running task.
+    fn poll_requests(&mut self) -> bool {
+        self.pending_requests.extend(self.requests.try_iter());
+        self.pending_requests.iter().any(|r| r.preempt)
+    }
+
+    /// Handles all requests that are pending or queued, without blocking
+    pub fn handle_requests(&mut self) -> Result<(), Box<dyn Error>> {
+        self.poll_requests();
+        while let Some(request) = self.pending_requests.pop_front() {
+            self.handle_request(request)?;
+        }
+        Ok(())
+    }
+
+    /// Handles requests as they come in, for when the turtle has nothing else to do.
+    /// Only returns on a websocket error or when the fleet drops the request channel.
+    pub fn serve_requests(&mut self) -> Result<(), Box<dyn Error>> {
+        self.handle_requests()?;
+        while let Ok(request) = self.requests.recv() {
+            self.handle_request(request)?;
+        }
+        Ok(())
+    }
+
+    /// Handles requests as they come in for a while, for when the turtle waits for something
+    pub fn serve_requests_for(&mut self, duration: Duration) -> Result<(), Box<dyn Error>> {
+        self.handle_requests()?;
+        let deadline = Instant::now() + duration;
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            match self.requests.recv_timeout(left) {
+                Ok(request) => self.handle_request(request)?,
+                Err(mpsc::RecvTimeoutError::Timeout) => break,
+                Err(mpsc::RecvTimeoutError::Disconnected) => thread::sleep(left),
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_request(&mut self, request: ExecutorRequest) -> Result<(), Box<dyn Error>> {
+        let response = match request.command {
+            ExecutorCommand::Eval { code, by } => self.eval(code, by.as_str())?.map(ExecutorResponse::Eval),
+            ExecutorCommand::Move(maneuver) => Ok(ExecutorResponse::Move(self.run_maneuver(&maneuver)?)),
+            ExecutorCommand::GoTo(target, facing) => {
+                Ok(ExecutorResponse::GoTo(self.go_to(target, facing)?))
+            }
+            ExecutorCommand::Craft(recipe, crafts) => Ok(ExecutorResponse::Craft(self.craft(&recipe, crafts)?)),
+            ExecutorCommand::Cancel => Ok(ExecutorResponse::Cancelled),
+            ExecutorCommand::Task(task, question_handler) => {
+                self.task_depth += 1;
+                let result = self.run_task(task, TaskExecutor::default_event_handler, question_handler);
+                self.task_depth -= 1;
+                Ok(ExecutorResponse::Task(result?))
+            }
+        };
+        self.save_world();
+        // The requester may have given up waiting, which is fine
+        let _ = request.reply.send(response);
+        Ok(())
+    }
+
+    /// Evaluates lua on the turtle if the eval policy allows it, and records it in the audit log
+    pub fn eval(&mut self, code: String, by: &str) -> Result<Result<EvalResponse, String>, Box<dyn Error>> {
+        let policy = self.fleet.eval_policy();
+        if let Err(e) = policy.check(code.as_str()) {
+            policy.audit(by, self.connection.id(), code.as_str(), format!("rejected: {}", e).as_str());
+            return Ok(Err(e));
+        }
+        let command = Command::Eval(code.clone(), policy.allowed().to_vec());
+        let mid = self.connection.send_request(command, EVAL_TIMEOUT);
+        let result = match self.await_response(mid)? {
+            Ok(UpEvent::EvalResponse(response)) => Ok(response),
+            Ok(e) => Err(format!("Expected eval response, got {:?}", e)),
+            Err(e) => Err(e),
+        };
+        let outcome = match &result {
+            Ok(response) => response.to_string(),
+            Err(e) => format!("failed: {}", e),
+        };
+        self.fleet.eval_policy().audit(by, self.connection.id(), code.as_str(), outcome.as_str());
+        Ok(result)
+    }
+
+    fn save_world(&self) {
+        if let Err(e) = self.world().save() {
+            eprintln!("Could not save world map: {}", e);
+        }
+    }
+
+    /// Moves the turtle to the coordinate along a route planned on the world map.
+    /// When a move fails, the blocking coordinate is avoided and a new route is planned from where
+    /// the turtle stopped. Running out of fuel is not retried.
+    pub fn go_to(&mut self, target: Coordinate, facing: Option<Direction>) -> Result<Result<(), MoveError>, Box<dyn Error>> {
+        self.go_to_avoiding(target, facing, HashSet::new())
+    }
+
+    /// Like `go_to`, but never enters the coordinates in `avoid`, e.g. to leave saplings alone.
+    /// Asks the turtle for its state once before planning, since tasks move it without telling us.
+    pub fn go_to_avoiding(&mut self, target: Coordinate, facing: Option<Direction>, mut avoid: HashSet<Coordinate>) -> Result<Result<(), MoveError>, Box<dyn Error>> {
+        if let Err(e) = self.refresh_state()? {
+            return Ok(Err(MoveError::other(e)));
+        }
+        for _ in 0..GO_TO_ATTEMPTS {
+            let route = pathfinding::find_route(&self.world(), &self.turtle.position, target, facing, &avoid);
+            let route = match route {
+                Some(route) => route,
+                None => return Ok(Err(MoveError::other(format!("No route to {:?}", target)))),
+            };
+            if route.is_empty() {
+                return Ok(Ok(()));
+            }
+
+            match self.run_maneuver(&route)? {
+                Ok(()) => return Ok(Ok(())),
+                Err((error, _)) if error.is_fuel() => return Ok(Err(error)),
+                Err((error, index)) => {
+                    println!("Move failed: {}, planning a new route", error);
+                    let (_, remaining) = route.split_at_index(index);
+                    if let Some(blocked) = remaining.steps().first().and_then(|s| s.target(&self.turtle.position)) {
+                        avoid.insert(blocked);
+                    }
+                }
+            }
+        }
+        Ok(Err(MoveError::other(format!("Could not reach {:?} after {} attempts", target, GO_TO_ATTEMPTS))))
+    }
+
+    /// Sends the maneuver as a move command and keeps track of where the turtle went.
+    /// A failure has the error and the string index of the failed step, like `UpEvent::MoveResponse`.
+    pub fn run_maneuver(&mut self, maneuver: &Maneuver) -> Result<Result<(), (MoveError, usize)>, Box<dyn Error>> {
+        let end = maneuver.predict(&self.turtle).position.coordinate();
+        if let Err(e) = self.ensure_fuel(maneuver.fuel_cost() as i64, end)? {
+            return Ok(Err((e, 1)));
+        }
+        let timeout = MOVE_TIMEOUT + STEP_TIMEOUT * maneuver.steps().iter().map(|s| s.count).sum::<u32>();
+        let mid = self.connection.send_request(Command::Move(maneuver.to_string()), timeout);
+        let result = match self.await_response(mid)? {
+            Ok(UpEvent::MoveResponse(r)) => r,
+            Ok(e) => return Ok(Err((MoveError::other(format!("Expected move response, got {:?}", e)), 1))),
+            Err(e) => return Ok(Err((MoveError::other(e), 1))),
+        };
+        let completed = match &result {
+            Ok(()) => maneuver.clone(),
+            Err((_, index)) => maneuver.split_at_index(*index).0,
+        };
+
+        let mut world = self.fleet.world();
+        completed.trace(&self.turtle.position).into_iter().for_each(|c| world.visited(c));
+        drop(world);
+        self.turtle = completed.predict(&self.turtle);
+        self.fleet.update_state(self.connection.id(), &self.turtle);
+        // The turtle does not report moves, followers learn the new position from here
+        self.fleet.publish(self.connection.id(), "position_update", (&self.turtle.position).into());
+        Ok(result)
+    }
+
+    /// Asks the turtle for its full state, which also corrects any position we predicted wrong
+    pub fn refresh_state(&mut self) -> Result<Result<(), String>, Box<dyn Error>> {
+        let mid = self.connection.send_request(Command::State, STATE_TIMEOUT);
+        match self.await_response(mid)? {
+            Ok(event @ UpEvent::StateUpdate(_)) => {
+                self.handle_update_event(event);
+                Ok(Ok(()))
+            }
+            Ok(e) => Ok(Err(format!("Expected state update, got {:?}", e))),
+            Err(e) => Ok(Err(e)),
+        }
+    }
+
+    /// Makes sure the turtle has fuel for `needed` moves plus the way home from `end`, where it
+    /// will be after those moves. Burns items from the inventory when it falls short, failing
+    /// with `MoveFailure::Fuel` when that is not enough.
+    /// Trusts the fuel level we predicted from earlier moves, the turtle is only asked for its
+    /// state when that looks short or a task moved it since.
+    pub fn ensure_fuel(&mut self, needed: i64, end: Coordinate) -> Result<Result<(), MoveError>, Box<dyn Error>> {
+        let needed = needed + fuel::fuel_home(end);
+        if !self.fuel_stale && needed <= self.turtle.fuel_level {
+            return Ok(Ok(()));
+        }
+        if let Err(e) = self.refresh_state()? {
+            return Ok(Err(MoveError::other(e)));
+        }
+        let missing = needed - self.turtle.fuel_level;
+        if missing <= 0 {
+            return Ok(Ok(()));
+        }
+
+        let plan = match fuel::plan_refuel(&self.turtle.inventory, missing) {
+            Ok(plan) => plan,
+            Err(short) => return Ok(Err(MoveError::new(MoveFailure::Fuel, format!("Needs {} fuel but is {} short, even after burning everything", needed, short)))),
+        };
+        println!("Refueling for {} fuel: {:?}", needed, plan);
+        self.task_depth += 1;
+        let refueled = self.run_task(Task::refuel(&plan), TaskExecutor::default_event_handler, TaskExecutor::null_question_handler);
+        self.task_depth -= 1;
+        if !refueled? {
+            return Ok(Err(MoveError::new(MoveFailure::Fuel, "Refuel task failed".to_string())));
+        }
+        if let Err(e) = self.refresh_state()? {
+            return Ok(Err(MoveError::other(e)));
+        }
+        if self.turtle.fuel_level < needed {
+            return Ok(Err(MoveError::new(MoveFailure::Fuel, format!("Needs {} fuel, but only has {} after refueling", needed, self.turtle.fuel_level))));
+        }
+        Ok(Ok(()))
+    }
+
+    /// Crafts the recipe `crafts` times, or as often as the inventory allows. What the recipe
+    /// does not need goes into the inventory in front while crafting, so there has to be an empty
+    /// one if the turtle carries anything else. Returns how many items were made.
+    pub fn craft(&mut self, recipe: &Recipe, crafts: Option<u8>) -> Result<Result<u32, String>, Box<dyn Error>> {
+        if let Err(e) = self.refresh_state()? {
+            return Ok(Err(e));
+        }
+        let crafts = crafts.unwrap_or_else(|| recipe.max_crafts(&self.turtle.inventory));
+        let plan = match crafting::plan_craft(recipe, &self.turtle.inventory, crafts) {
+            Ok(plan) => plan,
+            Err(e) => return Ok(Err(e)),
+        };
+        let count = |e: &TaskExecutor| e.turtle.inventory.find_all(|i| i.name == recipe.output)
+            .map(|(i, _)| i.count as u32)
+            .sum::<u32>();
+        let before = count(self);
+        println!("Crafting {} {} times: {:?}", recipe.output, crafts, plan);
+        self.task_depth += 1;
+        let crafted = self.run_task(Task::craft(&plan), TaskExecutor::default_event_handler, TaskExecutor::null_question_handler);
+        self.task_depth -= 1;
+        if !crafted? {
+            return Ok(Err("Craft task failed".to_string()));
+        }
+        // The inventory is up to date with the inventory_update of the task
+        let made = count(self).saturating_sub(before);
+        let expected = crafts as u32 * recipe.count as u32;
+        if made != expected {
+            return Ok(Err(format!("Expected to craft {} {}, but got {}", expected, recipe.output, made)));
+        }
+        Ok(Ok(made))
+    }
+
+    /// Waits for the reply to the command with this mid. Updates that arrived before it are
+    /// handled first, so the reply is applied to an up to date state.
+    fn await_response(&mut self, mid: u32) -> Result<Result<UpEvent, String>, Box<dyn Error>> {
+        loop {
+            match self.connection.receive_reply(mid) {
+                Ok(event) => {
+                    for update in self.connection.take_buffered(UpEvent::is_update) {
+                        self.handle_update_event(update);
+                    }
+                    return Ok(match event {
+                        UpEvent::Error => {
+                            self.fleet.publish(self.connection.id(), "error", "Turtle reported an error".into());
+                            Err("Turtle reported an error".to_string())
+                        }
+                        event => Ok(event),
+                    });
+                }
+                Err(ReceiveError::Disconnected) => return Err(format!("Turtle {} disconnected", self.connection.id()).into()),
+                Err(ReceiveError::MessageError(e)) => eprintln!("Got unexpected message: {}", e),
+                Err(ReceiveError::Timeout) => {}
+                Err(ReceiveError::CommandTimeout(mid)) => return Ok(Err(format!("Turtle did not answer command {} in time", mid))),
+            }
+        }
+    }
+
     pub fn handle_update_event(&mut self, event: UpEvent) {
+        let id = self.connection.id();
         match event {
             UpEvent::StateUpdate(s) => {
                 self.turtle = s;
+                self.fuel_stale = false;
+                self.world().visited(self.turtle.position.coordinate());
                 println!("Updated turtle state: {:?}",self.turtle);
+                self.fleet.publish(id, "state_update", (&self.turtle).into());
             },
             UpEvent::PositionUpdate(p) => {
                 self.turtle.position = p;
+                // Moves of tasks burn fuel we don't hear about
+                self.fuel_stale = true;
+                self.world().visited(self.turtle.position.coordinate());
                 println!("Updated turtle position: {:?}", self.turtle.position);
+                self.fleet.publish(id, "position_update", (&self.turtle.position).into());
             },
             UpEvent::InventoryUpdate(di) => {
+                self.fleet.publish(id, "inventory_update", (&di).into());
                 di.apply(&mut self.turtle.inventory);
                 println!("Updated turtle inventory: {:?}", self.turtle.inventory);
             },
-            _ => {},
+            UpEvent::BlockUpdate(update) => {
+                println!("Block at {:?} is {:?}", update.coordinate, update.block);
+                self.fleet.publish(id, "block_update", (&update).into());
+                self.world().set(update.coordinate, update.block);
+                return
+            }
+            _ => return,
         }
+        self.fleet.update_state(id, &self.turtle);
     }
 
     pub fn default_event_handler(event: UpEvent, _: &mut Self) -> bool {
@@ -141,8 +648,152 @@ impl TaskExecutor {
         }
     }
 
-    pub fn null_question_handler(question: String, _: &mut Self) -> JsonValue {
-        eprintln!("Got question {} while expecting no questions", question);
-        return JsonValue::Null
+    /// Knows no answers, every question goes to an operator
+    pub fn null_question_handler(_: &TaskQuestion, _: &mut Self) -> Option<JsonValue> {
+        None
+    }
+
+    /// Gets the answer from the handler, or from an operator through the console or REST API when
+    /// the handler does not know it or answers with the wrong type. Without an answer in time the
+    /// question gets its default answer. `None` means the task should be cancelled: there is no
+    /// default, a request wants to preempt the task or the question was dropped.
+    fn answer_question<Q>(&mut self, task: &str, question: &TaskQuestion, handler: &Q) -> Option<JsonValue>
+        where Q: Fn(&TaskQuestion, &mut TaskExecutor) -> Option<JsonValue> {
+        let declared = self.fleet.tasks().question(task, question.code.as_str());
+        match &declared {
+            Some(declared) => if let Err(e) = declared.payload.check(&question.payload) {
+                eprintln!("Question {} of task {} has an unexpected payload {}", question.code, task, e);
+            },
+            None => eprintln!("Task {} asks {}, which it does not declare", task, question.code),
+        }
+        if let Some(answer) = handler(question, self) {
+            match &declared {
+                Some(declared) => match declared.answer.check(&answer) {
+                    Ok(()) => return Some(answer),
+                    Err(e) => eprintln!("Handler gave an invalid answer to {} {}", question.code, e),
+                },
+                None => return Some(answer),
+            }
+        }
+
+        let id = self.connection.id();
+        let timeout = declared.as_ref().map_or(UNDECLARED_QUESTION_TIMEOUT, |q| q.timeout);
+        println!("Turtle {} asks {}, waiting {:?} for an operator to answer", id, question, timeout);
+        let answers = self.fleet.ask(id, question.clone());
+        let deadline = Instant::now() + timeout;
+        loop {
+            match answers.recv_timeout(QUESTION_POLL_INTERVAL) {
+                Ok(answer) => return Some(answer),
+                Err(mpsc::RecvTimeoutError::Timeout) if self.poll_requests() => break,
+                Err(mpsc::RecvTimeoutError::Timeout) if Instant::now() < deadline => {}
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    self.fleet.drop_questions(id);
+                    let default = declared.and_then(|q| q.default);
+                    if let Some(default) = &default {
+                        println!("Nobody answered {} in time, answering {}", question.code, default);
+                    }
+                    return default;
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        self.fleet.drop_questions(id);
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simulator::{Block, World};
+    use crate::simulator::testing::{finish, http, start, start_listening, start_with};
+    use crate::task_registry::{ReplantAnswer, Task};
+    use crate::turtle::{Coordinate, Direction, Item};
+    use crate::turtle_websocket::TaskQuestion;
+    use crate::world_map::KnownBlock;
+
+    use super::*;
+
+    #[test]
+    fn go_to_plans_around_blocks_missing_from_the_map() {
+        let mut world = World::new();
+        world.set_block(Coordinate::new(0, 0, -2), Some(Block::new("minecraft:stone")));
+        let (mut executor, handle) = start(world, 100, None);
+        executor.world().set(Coordinate::new(0, 0, -1), KnownBlock::Air);
+        executor.world().set(Coordinate::new(0, 0, -2), KnownBlock::Air);
+
+        let target = Coordinate::new(0, 0, -3);
+        assert_eq!(executor.go_to(target, Some(Direction::East)).unwrap(), Ok(()));
+        assert_eq!(executor.turtle.position.coordinate(), target);
+
+        let turtle = finish(executor, handle);
+        assert_eq!(turtle.position.coordinate(), target);
+        assert_eq!(turtle.position.direction(), Direction::East);
+        assert_eq!(turtle.item_count("minecraft:cobblestone"), 0);
+    }
+
+    #[test]
+    fn go_to_burns_coal_before_logs_when_low_on_fuel() {
+        let (mut executor, handle) = start_with(World::new(), 5, |t| {
+            t.inventory[0] = Some(Item { count: 10, name: "minecraft:oak_log".to_owned() });
+            t.inventory[1] = Some(Item { count: 3, name: "minecraft:coal".to_owned() });
+        });
+
+        // 30 there and 30 back
+        assert_eq!(executor.go_to(Coordinate::new(0, 0, -30), None).unwrap(), Ok(()));
+        assert_eq!(executor.turtle.fuel_level, 5 + 80 - 30);
+
+        let err = executor.go_to(Coordinate::new(0, 0, -300), None).unwrap().unwrap_err();
+        assert!(err.is_fuel() && err.message.contains("short"), "{}", err);
+
+        let turtle = finish(executor, handle);
+        assert_eq!(turtle.position.coordinate(), Coordinate::new(0, 0, -30));
+        assert_eq!(turtle.item_count("minecraft:coal"), 2);
+        assert_eq!(turtle.item_count("minecraft:oak_log"), 10);
+    }
+
+    #[test]
+    fn subtasks_link_to_the_task_they_run_for() {
+        // Too little fuel for fell, so it refuels from the coal first
+        let (mut executor, handle, address) = start_listening(World::lumberjack(), 5, |t| {
+            t.inventory[1] = Some(Item { count: 3, name: "minecraft:coal".to_owned() });
+        });
+
+        let skip_replant = |_: &TaskQuestion, _: &mut TaskExecutor| Some(ReplantAnswer::skip());
+        assert!(executor.execute(Task::fell(), TaskExecutor::default_event_handler, skip_replant).unwrap());
+
+        let (status, tasks) = http(address, "GET", "/turtles/1/tasks", JsonValue::Null);
+        assert_eq!(status, 200);
+        assert!(tasks["stack"].is_empty());
+        let history: Vec<(&str, &str)> = tasks["history"].members()
+            .map(|r| (r["task"].as_str().unwrap(), r["outcome"].as_str().unwrap()))
+            .collect();
+        assert_eq!(history, [("refuel", "finished"), ("fell", "finished")]);
+        assert_eq!(tasks["history"][0]["parent"], tasks["history"][1]["id"]);
+        assert!(tasks["history"][1]["parent"].is_null());
+        finish(executor, handle);
+    }
+
+    #[test]
+    fn unknown_task_is_cancelled() {
+        let (mut executor, handle) = start(World::new(), 0, None);
+
+        let success = executor.execute(Task::unchecked("dance", JsonValue::Null), TaskExecutor::default_event_handler,
+                                       TaskExecutor::null_question_handler).unwrap();
+        assert!(!success);
+        finish(executor, handle);
+    }
+
+    #[test]
+    fn eval_is_checked_against_the_policy() {
+        let (mut executor, handle) = start(World::new(), 0, None);
+
+        let rejected = executor.eval("fs.delete('startup.lua')".to_owned(), "test").unwrap();
+        assert_eq!(rejected.unwrap_err(), "fs.delete is not allowed in eval");
+        // Only names in code count, not the ones in strings and comments
+        let response = executor.eval("return turtle.getFuelLevel(), \"fs.delete\" -- os.shutdown()".to_owned(), "test").unwrap().unwrap();
+        assert!(!response.success);
+        assert_eq!(response.values, vec![JsonValue::from("The simulator can not evaluate lua")]);
+
+        finish(executor, handle);
     }
 }
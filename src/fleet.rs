@@ -0,0 +1,307 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, mpsc, Mutex, MutexGuard, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use json::JsonValue;
+use tokio::sync::mpsc as async_mpsc;
+
+use crate::eval_policy::EvalPolicy;
+use crate::executor::{ExecutorRequest, TaskRun};
+use crate::task_registry::TaskRegistry;
+use crate::turtle::TurtleState;
+use crate::turtle_websocket::TaskQuestion;
+use crate::world_map::WorldMap;
+
+/// How many ended tasks are kept per turtle
+const TASK_HISTORY: usize = 32;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    Connected,
+    Reconnecting,
+    Gone,
+}
+
+impl ConnectionStatus {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ConnectionStatus::Connected => "connected",
+            ConnectionStatus::Reconnecting => "reconnecting",
+            ConnectionStatus::Gone => "gone",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TurtleRecord {
+    pub id: u32,
+    pub state: TurtleState,
+    pub status: ConnectionStatus,
+    pub current_task: Option<String>,
+    /// The running task and the tasks it was started from, outermost first
+    pub task_stack: Vec<TaskRun>,
+    /// The last `TASK_HISTORY` tasks that ended, oldest first
+    pub task_history: VecDeque<TaskRun>,
+    /// How often the turtle rebooted while connected to this server
+    pub restarts: u32,
+    pub last_seen: SystemTime,
+}
+
+impl TurtleRecord {
+    fn new(id: u32) -> Self {
+        Self {
+            id,
+            state: TurtleState::default(),
+            status: ConnectionStatus::Connected,
+            current_task: None,
+            task_stack: Vec::new(),
+            task_history: VecDeque::new(),
+            restarts: 0,
+            last_seen: SystemTime::now(),
+        }
+    }
+}
+
+impl From<&TurtleRecord> for JsonValue {
+    fn from(record: &TurtleRecord) -> Self {
+        let last_seen = record.last_seen.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        json::object! {
+            id: record.id,
+            status: record.status.code(),
+            task: record.current_task.clone(),
+            task_stack: record.task_stack.iter().map(JsonValue::from).collect::<Vec<JsonValue>>(),
+            restarts: record.restarts,
+            last_seen: last_seen,
+            state: &record.state,
+        }
+    }
+}
+
+/// A question of a running task that waits for an answer from outside, e.g. the REST API
+pub struct PendingQuestion {
+    pub id: u32,
+    pub turtle: u32,
+    /// The task that asked, if the turtle was running one we know
+    pub task: Option<String>,
+    pub question: TaskQuestion,
+    answer: mpsc::Sender<JsonValue>,
+}
+
+/// Someone following the events of some turtles, or all of them if `turtles` is `None`
+struct Subscriber {
+    turtles: Option<HashSet<u32>>,
+    events: async_mpsc::UnboundedSender<JsonValue>,
+}
+
+#[derive(Default)]
+struct Questions {
+    next_id: u32,
+    pending: Vec<PendingQuestion>,
+}
+
+/// Registry of every turtle that connected since the server started, keyed by the id from `/ws/{id}`.
+/// Cloning gives another handle to the same registry, so it can be shared between the listener,
+/// the runner threads and any frontend.
+#[derive(Clone, Default)]
+pub struct Fleet {
+    turtles: Arc<RwLock<HashMap<u32, TurtleRecord>>>,
+    executors: Arc<Mutex<HashMap<u32, mpsc::Sender<ExecutorRequest>>>>,
+    world: Arc<Mutex<WorldMap>>,
+    eval_policy: Arc<EvalPolicy>,
+    tasks: Arc<TaskRegistry>,
+    questions: Arc<Mutex<Questions>>,
+    next_task_run: Arc<AtomicU32>,
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+impl Fleet {
+    /// A fleet that shares the given world map, eval policy and tasks instead of the defaults
+    pub fn new(world: WorldMap, eval_policy: EvalPolicy, tasks: TaskRegistry) -> Self {
+        Self {
+            world: Arc::new(Mutex::new(world)),
+            eval_policy: Arc::new(eval_policy),
+            tasks: Arc::new(tasks),
+            ..Self::default()
+        }
+    }
+
+    /// Marks the turtle as connected, adding it to the registry if it is new
+    pub fn connected(&self, id: u32) {
+        let mut turtles = self.turtles.write().unwrap();
+        let record = turtles.entry(id).or_insert_with(|| TurtleRecord::new(id));
+        record.status = ConnectionStatus::Connected;
+        record.last_seen = SystemTime::now();
+        drop(turtles);
+        self.publish(id, "status", ConnectionStatus::Connected.code().into());
+    }
+
+    pub fn set_status(&self, id: u32, status: ConnectionStatus) {
+        self.update(id, |r| r.status = status);
+        self.publish(id, "status", status.code().into());
+    }
+
+    pub fn seen(&self, id: u32) {
+        self.update(id, |r| r.last_seen = SystemTime::now());
+    }
+
+    pub fn update_state(&self, id: u32, state: &TurtleState) {
+        self.update(id, |r| r.state = state.clone());
+    }
+
+    /// Counts a reboot of the turtle, which ends whatever task it was running
+    pub fn restarted(&self, id: u32) {
+        let mut restarts = 0;
+        self.update(id, |r| {
+            r.restarts += 1;
+            restarts = r.restarts;
+        });
+        self.publish(id, "restarted", restarts.into());
+    }
+
+    pub fn restarts(&self, id: u32) -> u32 {
+        self.turtles.read().unwrap().get(&id).map_or(0, |r| r.restarts)
+    }
+
+    /// A new id for a task run, unique across the fleet
+    pub fn next_task_run(&self) -> u32 {
+        self.next_task_run.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Mirrors the task stack of the executor of the turtle
+    pub fn set_task_stack(&self, id: u32, stack: &[TaskRun]) {
+        self.update(id, |r| {
+            r.current_task = stack.last().map(|run| run.task.code().to_owned());
+            r.task_stack = stack.to_vec();
+        });
+    }
+
+    /// Adds a task that ended to the history of the turtle
+    pub fn task_ended(&self, id: u32, run: &TaskRun) {
+        self.update(id, |r| {
+            if r.task_history.len() >= TASK_HISTORY {
+                r.task_history.pop_front();
+            }
+            r.task_history.push_back(run.clone());
+        });
+    }
+
+    /// Registers the request queue of the executor that controls the turtle
+    pub fn attach(&self, id: u32, requests: mpsc::Sender<ExecutorRequest>) {
+        self.executors.lock().unwrap().insert(id, requests);
+    }
+
+    /// Queues a request on the executor of the turtle
+    pub fn request(&self, id: u32, request: ExecutorRequest) -> Result<(), String> {
+        match self.executors.lock().unwrap().get(&id) {
+            Some(requests) => requests.send(request).map_err(|_| format!("Executor of turtle {} has stopped", id)),
+            None => Err(format!("No executor attached to turtle {}", id)),
+        }
+    }
+
+    pub fn get(&self, id: u32) -> Option<TurtleRecord> {
+        self.turtles.read().unwrap().get(&id).cloned()
+    }
+
+    /// Snapshot of all turtles, ordered by id
+    pub fn list(&self) -> Vec<TurtleRecord> {
+        let mut records: Vec<TurtleRecord> = self.turtles.read().unwrap().values().cloned().collect();
+        records.sort_by_key(|r| r.id);
+        records
+    }
+
+    /// The map of everything the turtles have seen, shared by all turtles
+    pub fn world(&self) -> MutexGuard<'_, WorldMap> {
+        self.world.lock().unwrap()
+    }
+
+    /// What lua the turtles may evaluate
+    pub fn eval_policy(&self) -> &EvalPolicy {
+        &self.eval_policy
+    }
+
+    /// The tasks the turtles can run
+    pub fn tasks(&self) -> &TaskRegistry {
+        &self.tasks
+    }
+
+    /// Follows the events of the given turtles, or of all turtles. Each event is json like
+    /// `{"turtle": 1, "event": "position_update", "b": ...}`. Dropping the receiver unsubscribes.
+    pub fn subscribe(&self, turtles: Option<HashSet<u32>>) -> async_mpsc::UnboundedReceiver<JsonValue> {
+        let (events, receiver) = async_mpsc::unbounded_channel();
+        self.subscribers.lock().unwrap().push(Subscriber { turtles, events });
+        receiver
+    }
+
+    /// Sends an event of the turtle to everyone following it
+    pub fn publish(&self, turtle: u32, event: &str, body: JsonValue) {
+        let event = json::object! {
+            turtle: turtle,
+            event: event,
+            b: body,
+        };
+        // Subscribers whose receiver was dropped are removed on the way
+        self.subscribers.lock().unwrap().retain(|s| {
+            if s.turtles.as_ref().is_some_and(|t| !t.contains(&turtle)) {
+                return !s.events.is_closed();
+            }
+            s.events.send(event.clone()).is_ok()
+        });
+    }
+
+    /// Leaves a question of the task the turtle runs for someone to `answer`, and tells the
+    /// followers of the turtle with an `operator_question` event
+    pub fn ask(&self, turtle: u32, question: TaskQuestion) -> mpsc::Receiver<JsonValue> {
+        let (answer, receiver) = mpsc::channel();
+        let task = self.get(turtle).and_then(|r| r.current_task);
+        let mut event: JsonValue = (&question).into();
+        let mut questions = self.questions.lock().unwrap();
+        questions.next_id += 1;
+        let id = questions.next_id;
+        questions.pending.push(PendingQuestion { id, turtle, task, question, answer });
+        drop(questions);
+        event["id"] = id.into();
+        self.publish(turtle, "operator_question", event);
+        receiver
+    }
+
+    /// The questions waiting for an answer as (id, question), oldest first
+    pub fn questions(&self, turtle: u32) -> Vec<(u32, TaskQuestion)> {
+        self.questions.lock().unwrap().pending.iter()
+            .filter(|q| q.turtle == turtle)
+            .map(|q| (q.id, q.question.clone()))
+            .collect()
+    }
+
+    /// Checks the answer against the question as the task declares it, without answering.
+    /// Questions the task does not declare take any answer.
+    pub fn check_answer(&self, turtle: u32, id: u32, answer: &JsonValue) -> Result<(), String> {
+        let questions = self.questions.lock().unwrap();
+        let asked = questions.pending.iter().find(|q| q.turtle == turtle && q.id == id)
+            .and_then(|q| Some((q.task.as_deref()?, q.question.code.as_str())));
+        match asked {
+            Some((task, question)) if self.tasks.question(task, question).is_some() => self.tasks.check_answer(task, question, answer),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn answer(&self, turtle: u32, id: u32, answer: JsonValue) -> Result<(), String> {
+        let mut questions = self.questions.lock().unwrap();
+        let index = questions.pending.iter().position(|q| q.turtle == turtle && q.id == id)
+            .ok_or_else(|| format!("Turtle {} has no question {}", turtle, id))?;
+        let question = questions.pending.remove(index);
+        question.answer.send(answer).map_err(|_| format!("Task of turtle {} no longer waits for an answer", turtle))
+    }
+
+    /// Drops the questions of the turtle, the task gets no answer
+    pub fn drop_questions(&self, turtle: u32) {
+        self.questions.lock().unwrap().pending.retain(|q| q.turtle != turtle);
+    }
+
+    fn update<F>(&self, id: u32, f: F)
+        where F: FnOnce(&mut TurtleRecord) {
+        if let Some(record) = self.turtles.write().unwrap().get_mut(&id) {
+            f(record);
+        }
+    }
+}
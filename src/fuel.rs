@@ -0,0 +1,64 @@
+use crate::turtle::{Coordinate, Inventory};
+
+/// Fuel a single item gives when burned, `None` if it does not burn (or we don't want to burn it)
+pub fn fuel_value(name: &str) -> Option<i64> {
+    match name {
+        "minecraft:coal" | "minecraft:charcoal" => Some(80),
+        "minecraft:coal_block" => Some(800),
+        "minecraft:lava_bucket" => Some(1000),
+        "minecraft:stick" => Some(5),
+        name if name.ends_with("_log") || name.ends_with("_planks") => Some(15),
+        _ => None,
+    }
+}
+
+/// Lower burns first. Logs go last, because they are what the lumberjack collects.
+fn burn_order(name: &str) -> u8 {
+    match name {
+        "minecraft:coal" | "minecraft:charcoal" => 0,
+        "minecraft:coal_block" => 1,
+        "minecraft:lava_bucket" => 2,
+        name if name.ends_with("_planks") => 3,
+        "minecraft:stick" => 4,
+        _ => 5,
+    }
+}
+
+/// Fuel needed to get back to the origin from a coordinate, so a turtle never strands itself
+pub fn fuel_home(coordinate: Coordinate) -> i64 {
+    coordinate.x().abs() + coordinate.y().abs() + coordinate.z().abs()
+}
+
+/// Burn `count` items of a slot (1-based, like in lua)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Refuel {
+    pub slot: usize,
+    pub count: u8,
+}
+
+/// Picks the items to burn for at least `missing` fuel, or returns how much fuel would still be
+/// missing after burning everything that burns
+pub fn plan_refuel(inventory: &Inventory, missing: i64) -> Result<Vec<Refuel>, i64> {
+    let mut burnable: Vec<_> = inventory.item_iter()
+        .filter_map(|(item, slot)| fuel_value(item.name.as_str()).map(|value| (item, slot, value)))
+        .collect();
+    burnable.sort_by_key(|(item, slot, _)| (burn_order(item.name.as_str()), *slot));
+
+    let mut missing = missing;
+    let mut plan = Vec::new();
+    for (item, slot, value) in burnable {
+        if missing <= 0 {
+            break;
+        }
+        let count = ((missing + value - 1) / value).min(item.count as i64);
+        if count > 0 {
+            plan.push(Refuel { slot, count: count as u8 });
+            missing -= count * value;
+        }
+    }
+    if missing > 0 {
+        Err(missing)
+    } else {
+        Ok(plan)
+    }
+}
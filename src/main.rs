@@ -1,35 +1,59 @@
-use std::sync::{Mutex, Arc};
 use std::thread;
 use crate::turtle::TurtleState;
 use crate::executor::TaskExecutor;
-use crate::turtle_runner::Runner;
-use std::error::Error;
+use crate::turtle_runner::{Runner, RunnerKind};
+use crate::fleet::Fleet;
+use crate::world_map::WorldMap;
+use crate::eval_policy::EvalPolicy;
+use crate::task_registry::TaskRegistry;
+use crate::turtle_ids::TurtleIds;
+use std::sync::{Arc, Mutex};
 
 mod turtle_websocket;
 mod turtle_rest;
+mod turtle_ids;
 mod turtle;
+mod decode;
 mod executor;
 mod maneuver;
 mod console;
 mod turtle_runner;
-
-pub type TurtleList = Arc<Mutex<Vec<TaskExecutor>>>;
+mod fleet;
+#[cfg(any(test, feature = "simulator"))]
+mod simulator;
+mod world_map;
+mod pathfinding;
+mod fuel;
+mod eval_policy;
+mod task_registry;
+mod tree_farm;
+mod quarry;
+mod strip_mine;
+mod schematic;
+mod builder;
+mod crafting;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let (client_rx, _) = turtle_websocket::spawn_websocket_listener()?;
+    let fleet = Fleet::new(WorldMap::load()?, EvalPolicy::load(), TaskRegistry::default());
+    let kind = RunnerKind::from_env()?;
+    let ids = Arc::new(Mutex::new(TurtleIds::load()?));
+    let (client_rx, _) = turtle_websocket::spawn_websocket_listener(fleet.clone(), Arc::clone(&ids))?;
+    #[cfg(feature = "simulator")]
+    spawn_simulated_turtles(&ids)?;
 
-    // let turtle_list = Arc::new(Mutex::new(Vec::new()));
-    let handle;
     {
-        // let turtle_list = Arc::clone(&turtle_list);
-        handle = thread::spawn(move || {
+        let fleet = fleet.clone();
+        thread::spawn(move || {
             loop {
                 let connection = client_rx.recv().unwrap();
                 let turtle = TurtleState::default();
-                let task_executor = TaskExecutor::new(turtle, connection);
+                let task_executor = TaskExecutor::new(turtle, connection, fleet.clone());
                 let mut runner = Runner {
-                    executor: task_executor
+                    executor: task_executor,
+                    kind,
                 };
+                // Runners block on their connection, so each turtle gets a thread of its own. The
+                // sockets are served by the listener's runtime.
                 thread::spawn(move || {
                     match runner.run() {
                         Ok(_) => {}
@@ -37,13 +61,32 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                             eprintln!("Runner encountered error: {:?}", e)
                         }
                     }
-                    thread::park()
+                    // Keep the turtle available to the console once the runner is done
+                    if let Err(e) = runner.executor.serve_requests() {
+                        eprintln!("Executor encountered error: {:?}", e)
+                    }
                 });
-                // turtle_list.lock().unwrap().push(task_executor)
             }
-        })
+        });
     }
 
-    // return console::spawn_console(turtle_list)?.join().map_err(|_| "thread failed".into());
-    return handle.join().map_err(|_| "thread failed".into());
+    console::spawn_console(fleet)?.join().map_err(|_| "thread failed".into())
+}
+
+/// Connects `SIMULATED_TURTLES` simulated turtles to our own listener, each in its own lumberjack
+/// world. Only built with the `simulator` feature.
+#[cfg(feature = "simulator")]
+fn spawn_simulated_turtles(ids: &Mutex<TurtleIds>) -> Result<(), Box<dyn std::error::Error>> {
+    use std::env;
+
+    let count: u32 = env::var("SIMULATED_TURTLES").map_or(Ok(0), |s| s.parse())?;
+    let address = env::var("ADDRESS").unwrap_or(String::from("localhost"));
+    let port = env::var("PORT").unwrap_or(String::from("17576"));
+    for i in 0..count {
+        let id = 10000 + i;
+        let url = format!("ws://{}:{}/ws/{}", address, port, id);
+        let secret = ids.lock().unwrap().secret(id)?;
+        simulator::spawn_simulator(url, secret, simulator::World::lumberjack(), 0)?;
+    }
+    Ok(())
 }
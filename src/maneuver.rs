@@ -1,3 +1,9 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::turtle::{Coordinate, Position, TurtleState};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Move {
     Forward,
     Backward,
@@ -18,4 +24,356 @@ impl Move {
             Move::Right => "r",
         }
     }
+
+    pub fn from_code(c: char) -> Option<Self> {
+        match c {
+            'f' => Some(Move::Forward),
+            'b' => Some(Move::Backward),
+            'u' => Some(Move::Up),
+            'd' => Some(Move::Down),
+            'l' => Some(Move::Left),
+            'r' => Some(Move::Right),
+            _ => None,
+        }
+    }
+
+    pub fn is_turn(&self) -> bool {
+        matches!(self, Move::Left | Move::Right)
+    }
+}
+
+/// One command of the `move.lua` language, e.g. `mf3` is `Forward`, mining, 3 times
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ManeuverStep {
+    pub move_type: Move,
+    pub mine: bool,
+    pub count: u32,
+}
+
+impl ManeuverStep {
+    pub fn new(move_type: Move, mine: bool, count: u32) -> Self {
+        Self { move_type, mine, count }
+    }
+
+    /// Fuel the step takes if it completes, turning is free
+    pub fn fuel_cost(&self) -> u32 {
+        if self.move_type.is_turn() { 0 } else { self.count }
+    }
+
+    /// The first coordinate the step moves into, `None` for turns
+    pub fn target(&self, position: &Position) -> Option<Coordinate> {
+        match self.move_type {
+            Move::Forward => Some(position.ahead(1)),
+            Move::Backward => Some(position.ahead(-1)),
+            Move::Up => Some(position.coordinate().delta(0, 1, 0)),
+            Move::Down => Some(position.coordinate().delta(0, -1, 0)),
+            Move::Left | Move::Right => None,
+        }
+    }
+
+    /// Parses the step starting at index `i` (0-based) and returns it with the index after it
+    pub fn parse_at(chars: &[char], mut i: usize) -> Result<(Self, usize), ManeuverError> {
+        let index = i + 1;
+        let mine = chars.get(i) == Some(&'m');
+        if mine {
+            i += 1;
+        }
+        let move_type = chars.get(i).copied().and_then(Move::from_code)
+            .filter(|m| !(mine && m.is_turn()))
+            .ok_or_else(|| {
+                let code: String = chars[index - 1..(i + 1).min(chars.len())].iter().collect();
+                ManeuverError { message: format!("Unknown command {}", code), index }
+            })?;
+        i += 1;
+        let digits: String = chars[i..].iter().take_while(|c| c.is_ascii_digit()).collect();
+        i += digits.len();
+        let count = if digits.is_empty() {
+            1
+        } else {
+            digits.parse().map_err(|_| ManeuverError { message: format!("Count {} is too large", digits), index })?
+        };
+        Ok((Self::new(move_type, mine, count), i))
+    }
+}
+
+impl fmt::Display for ManeuverStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.mine {
+            write!(f, "m")?;
+        }
+        write!(f, "{}", self.move_type.code())?;
+        if self.count != 1 {
+            write!(f, "{}", self.count)?;
+        }
+        Ok(())
+    }
+}
+
+/// Why a move string was rejected. `index` is the 1-based position of the offending command,
+/// like the progress `t.runString` reports.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ManeuverError {
+    pub message: String,
+    pub index: usize,
+}
+
+impl fmt::Display for ManeuverError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.index)
+    }
+}
+
+impl std::error::Error for ManeuverError {}
+
+/// Why a move stopped, as `t.runString` reports it
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MoveFailure {
+    /// Not enough fuel for the step, the turtle did not start it
+    Fuel,
+    /// A block is in the way
+    Obstacle,
+    /// Anything else, e.g. there is no route or the turtle did not answer
+    Other,
+}
+
+impl MoveFailure {
+    pub fn code(&self) -> &'static str {
+        match self {
+            MoveFailure::Fuel => "fuel",
+            MoveFailure::Obstacle => "obstacle",
+            MoveFailure::Other => "other",
+        }
+    }
+
+    /// The reason `t.runString` gives, anything unknown is `Other`
+    pub fn from_code(s: &str) -> Self {
+        match s {
+            "fuel" => MoveFailure::Fuel,
+            "obstacle" => MoveFailure::Obstacle,
+            _ => MoveFailure::Other,
+        }
+    }
+}
+
+/// A move or route that did not complete
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MoveError {
+    pub failure: MoveFailure,
+    pub message: String,
+}
+
+impl MoveError {
+    pub fn new(failure: MoveFailure, message: String) -> Self {
+        Self { failure, message }
+    }
+
+    pub fn other(message: String) -> Self {
+        Self::new(MoveFailure::Other, message)
+    }
+
+    pub fn is_fuel(&self) -> bool {
+        self.failure == MoveFailure::Fuel
+    }
+}
+
+impl fmt::Display for MoveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for MoveError {}
+
+/// A sequence of moves in the `move.lua` string syntax (`f3r2mu1`...), parsed and validated
+/// before it is sent with `Command::Move`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Maneuver {
+    steps: Vec<ManeuverStep>,
+}
+
+impl Maneuver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn steps(&self) -> &[ManeuverStep] {
+        &self.steps
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    pub fn push(&mut self, step: ManeuverStep) {
+        self.steps.push(step);
+    }
+
+    pub fn last_mut(&mut self) -> Option<&mut ManeuverStep> {
+        self.steps.last_mut()
+    }
+
+    pub fn fuel_cost(&self) -> u32 {
+        self.steps.iter().map(ManeuverStep::fuel_cost).sum()
+    }
+
+    /// The state of the turtle after the whole maneuver succeeded
+    pub fn predict(&self, state: &TurtleState) -> TurtleState {
+        let mut state = state.clone();
+        for step in &self.steps {
+            state.move_turtle(&step.move_type, step.count as i64);
+            state.fuel_level -= step.fuel_cost() as i64;
+        }
+        state
+    }
+
+    /// Every coordinate the turtle enters during the maneuver, in order
+    pub fn trace(&self, from: &Position) -> Vec<Coordinate> {
+        let mut position = from.clone();
+        let mut coordinates = Vec::new();
+        for step in &self.steps {
+            if step.move_type.is_turn() {
+                position.apply_move(&step.move_type, step.count as i64);
+                continue;
+            }
+            for _ in 0..step.count {
+                position.apply_move(&step.move_type, 1);
+                coordinates.push(position.coordinate());
+            }
+        }
+        coordinates
+    }
+
+    /// Splits the maneuver at the 1-based string index where `t.runString` failed, into the
+    /// steps that completed and the steps that did not. The failed step itself may have made
+    /// some progress, which the turtle does not report.
+    pub fn split_at_index(&self, index: usize) -> (Maneuver, Maneuver) {
+        let mut start = 1;
+        let mut completed = 0;
+        for step in &self.steps {
+            if start >= index {
+                break;
+            }
+            start += step.to_string().len();
+            completed += 1;
+        }
+        (Maneuver { steps: self.steps[..completed].to_vec() }, Maneuver { steps: self.steps[completed..].to_vec() })
+    }
+}
+
+impl fmt::Display for Maneuver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.steps.iter().try_for_each(|s| write!(f, "{}", s))
+    }
+}
+
+impl FromStr for Maneuver {
+    type Err = ManeuverError;
+
+    /// Accepts exactly what `t.runString` accepts: a move code (`f`, `b`, `u`, `d`, `l`, `r`, or
+    /// `mf`, `mb`, `mu`, `md` to dig first) followed by an optional count, repeated
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let chars: Vec<char> = s.chars().collect();
+        if chars.is_empty() {
+            return Err(ManeuverError { message: "Empty move string".to_string(), index: 1 });
+        }
+        let mut steps = Vec::new();
+        let mut i = 0;
+        while i < chars.len() {
+            let (step, next) = ManeuverStep::parse_at(&chars, i)?;
+            steps.push(step);
+            i = next;
+        }
+        Ok(Self { steps })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::turtle::Direction;
+
+    use super::*;
+
+    fn maneuver(s: &str) -> Maneuver {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_steps_and_prints_them_back() {
+        let cases = [
+            ("f", vec![ManeuverStep::new(Move::Forward, false, 1)], "f"),
+            ("f1", vec![ManeuverStep::new(Move::Forward, false, 1)], "f"),
+            ("mf12", vec![ManeuverStep::new(Move::Forward, true, 12)], "mf12"),
+            ("b0", vec![ManeuverStep::new(Move::Backward, false, 0)], "b0"),
+            ("ur2md3l", vec![
+                ManeuverStep::new(Move::Up, false, 1),
+                ManeuverStep::new(Move::Right, false, 2),
+                ManeuverStep::new(Move::Down, true, 3),
+                ManeuverStep::new(Move::Left, false, 1),
+            ], "ur2md3l"),
+        ];
+        for (input, steps, printed) in cases.iter() {
+            let parsed = maneuver(input);
+            assert_eq!(parsed.steps(), steps.as_slice(), "{}", input);
+            assert_eq!(parsed.to_string(), *printed);
+            assert_eq!(maneuver(printed), parsed);
+        }
+    }
+
+    #[test]
+    fn parse_at_returns_the_next_index() {
+        let chars: Vec<char> = "f3mu".chars().collect();
+        assert_eq!(ManeuverStep::parse_at(&chars, 0), Ok((ManeuverStep::new(Move::Forward, false, 3), 2)));
+        assert_eq!(ManeuverStep::parse_at(&chars, 2), Ok((ManeuverStep::new(Move::Up, true, 1), 4)));
+    }
+
+    #[test]
+    fn invalid_strings_report_where() {
+        let cases = [
+            ("", "Empty move string", 1),
+            ("x", "Unknown command x", 1),
+            ("f2mr", "Unknown command mr", 3),
+            ("fm", "Unknown command m", 2),
+            ("f99999999999", "Count 99999999999 is too large", 1),
+        ];
+        for (input, message, index) in cases.iter() {
+            let error = input.parse::<Maneuver>().unwrap_err();
+            assert_eq!(error, ManeuverError { message: message.to_string(), index: *index }, "{}", input);
+        }
+    }
+
+    #[test]
+    fn fuel_is_counted_for_moves_only() {
+        assert_eq!(maneuver("f3r2mu2l").fuel_cost(), 5);
+        assert_eq!(maneuver("r4").fuel_cost(), 0);
+    }
+
+    #[test]
+    fn predict_moves_and_burns_fuel() {
+        let state = TurtleState { fuel_level: 10, ..TurtleState::default() };
+        let predicted = maneuver("f2ru3b").predict(&state);
+        assert_eq!(predicted.position.coordinate(), Coordinate::new(-1, 3, -2));
+        assert_eq!(predicted.position.direction(), Direction::East);
+        assert_eq!(predicted.fuel_level, 4);
+    }
+
+    #[test]
+    fn trace_lists_every_coordinate_entered() {
+        let from = Position::new(Coordinate::new(5, 0, 5), Direction::South);
+        assert_eq!(maneuver("f2lmfdr2").trace(&from), vec![
+            Coordinate::new(5, 0, 6),
+            Coordinate::new(5, 0, 7),
+            Coordinate::new(6, 0, 7),
+            Coordinate::new(6, -1, 7),
+        ]);
+    }
+
+    #[test]
+    fn split_at_index_keeps_the_completed_steps() {
+        let m = maneuver("f3r2mu");
+        let cases = [(1, "", "f3r2mu"), (3, "f3", "r2mu"), (5, "f3r2", "mu"), (7, "f3r2mu", "")];
+        for (index, done, rest) in cases.iter() {
+            let (completed, remaining) = m.split_at_index(*index);
+            assert_eq!((completed.to_string(), remaining.to_string()), (done.to_string(), rest.to_string()), "index {}", index);
+        }
+    }
 }
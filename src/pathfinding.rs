@@ -0,0 +1,130 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use crate::maneuver::{Maneuver, ManeuverStep, Move};
+use crate::turtle::{Coordinate, Direction, Position};
+use crate::world_map::{KnownBlock, WorldMap};
+
+const MOVE_COST: u32 = 2;
+const TURN_COST: u32 = 1;
+/// Extra cost of moving into a block we have to dig first
+const DIG_COST: u32 = 3;
+/// Extra cost of moving into a block we know nothing about, it might have to be dug
+const UNKNOWN_COST: u32 = 1;
+/// Gives up instead of searching forever when the target is walled in
+const MAX_EXPANSIONS: usize = 200_000;
+
+/// Blocks a route should never go through
+fn is_undiggable(name: &str) -> bool {
+    name == "minecraft:bedrock" || name.starts_with("computercraft:turtle")
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+struct Node {
+    coordinate: Coordinate,
+    direction: Direction,
+}
+
+#[derive(PartialEq, Eq)]
+struct Open {
+    estimate: Reverse<u32>,
+    node: Node,
+}
+
+impl Ord for Open {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.estimate.cmp(&other.estimate)
+    }
+}
+
+impl PartialOrd for Open {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Plans the cheapest route from a position to a coordinate with A* on the known world.
+/// Unknown blocks are assumed to be passable after digging, coordinates in `avoid` are never
+/// entered. If `facing` is given the turtle also ends up facing that direction.
+///
+/// Consecutive turns are merged (`r2`), but moves are not (`mfmf` instead of `mf2`), so the
+/// progress of a failed move tells exactly where the turtle stopped.
+pub fn find_route(world: &WorldMap, from: &Position, to: Coordinate, facing: Option<Direction>, avoid: &HashSet<Coordinate>) -> Option<Maneuver> {
+    let start = Node { coordinate: from.coordinate(), direction: from.direction() };
+    // Most of the world is unknown, so estimating with the cost of an unknown block keeps the
+    // search narrow. Routes through known air may come out slightly longer than needed.
+    let heuristic = |n: &Node| {
+        let c = n.coordinate;
+        let distance = (c.x() - to.x()).abs() + (c.y() - to.y()).abs() + (c.z() - to.z()).abs();
+        distance as u32 * (MOVE_COST + UNKNOWN_COST)
+    };
+
+    let mut open = BinaryHeap::new();
+    let mut costs: HashMap<Node, u32> = HashMap::new();
+    let mut came_from: HashMap<Node, (Node, ManeuverStep)> = HashMap::new();
+    costs.insert(start, 0);
+    open.push(Open { estimate: Reverse(heuristic(&start)), node: start });
+
+    let mut expansions = 0;
+    while let Some(Open { node, .. }) = open.pop() {
+        if node.coordinate == to && facing.is_none_or(|d| d == node.direction) {
+            return Some(reconstruct(&came_from, node));
+        }
+        expansions += 1;
+        if expansions > MAX_EXPANSIONS {
+            return None;
+        }
+
+        let cost = costs[&node];
+        let position = Position::new(node.coordinate, node.direction);
+        let neighbours = [Move::Left, Move::Right, Move::Forward, Move::Up, Move::Down].iter().filter_map(|&move_type| {
+            let mut step = ManeuverStep::new(move_type, false, 1);
+            let step_cost = match step.target(&position) {
+                None => TURN_COST,
+                Some(target) if avoid.contains(&target) => return None,
+                Some(target) => match world.get(target) {
+                    Some(KnownBlock::Air) => MOVE_COST,
+                    Some(KnownBlock::Named(name)) if is_undiggable(name) => return None,
+                    Some(_) => {
+                        step.mine = true;
+                        MOVE_COST + DIG_COST
+                    }
+                    None => {
+                        step.mine = true;
+                        MOVE_COST + UNKNOWN_COST
+                    }
+                }
+            };
+            let mut next = position.clone();
+            next.apply_move(&step.move_type, 1);
+            Some((Node { coordinate: next.coordinate(), direction: next.direction() }, step, cost + step_cost))
+        }).collect::<Vec<_>>();
+
+        for (next, step, next_cost) in neighbours {
+            if costs.get(&next).is_none_or(|&c| next_cost < c) {
+                costs.insert(next, next_cost);
+                came_from.insert(next, (node, step));
+                open.push(Open { estimate: Reverse(next_cost + heuristic(&next)), node: next });
+            }
+        }
+    }
+    None
+}
+
+fn reconstruct(came_from: &HashMap<Node, (Node, ManeuverStep)>, mut node: Node) -> Maneuver {
+    let mut steps = Vec::new();
+    while let Some((previous, step)) = came_from.get(&node) {
+        steps.push(*step);
+        node = *previous;
+    }
+    steps.reverse();
+
+    let mut maneuver = Maneuver::new();
+    for step in steps {
+        match maneuver.last_mut() {
+            Some(last) if last.move_type == step.move_type && step.move_type.is_turn() => last.count += 1,
+            _ => maneuver.push(step),
+        }
+    }
+    maneuver
+}
@@ -0,0 +1,429 @@
+use std::error::Error;
+use std::time::Duration;
+use std::{env, fs, io};
+
+use json::JsonValue;
+
+use crate::decode::{DecodeError, expect_i64, expect_object, expect_u32, field, field_with};
+use crate::executor::TaskExecutor;
+use crate::fuel;
+use crate::maneuver::{Maneuver, ManeuverStep, Move, MoveError};
+use crate::task_registry::Task;
+use crate::turtle::{Coordinate, Position};
+
+/// Fuel kept on top of the way back to the chest
+const FUEL_MARGIN: i64 = 20;
+/// How long to wait at the chest for fuel before looking again
+const FUEL_WAIT: Duration = Duration::from_secs(60);
+
+/// Digs out a box of `width` (to the right) by `length` (ahead) by `depth` (down) blocks, starting
+/// with the block in front of `start` on its level. Layers are dug in serpentine rows, each layer
+/// in the opposite order of the one above, so every block is next to the one before. The chest
+/// for what was dug is right behind `start`.
+/// The size comes from `QUARRY_WIDTH`, `QUARRY_LENGTH` and `QUARRY_DEPTH` (default 8 each, must be
+/// positive). The
+/// box and how far the turtle got are stored as json in the file at `QUARRY_FILE` (default
+/// `quarry_{id}.json`, with the id of the turtle), so it resumes where it stopped.
+pub struct Quarry {
+    /// Where the quarry is saved, `None` keeps it in memory only
+    path: Option<String>,
+    pub start: Position,
+    pub width: i64,
+    pub length: i64,
+    pub depth: i64,
+    /// How many blocks of the box were dug, in digging order
+    pub progress: i64,
+}
+
+impl Quarry {
+    pub fn new(start: Position, width: i64, length: i64, depth: i64) -> Self {
+        Self { path: None, start, width, length, depth, progress: 0 }
+    }
+
+    /// Loads the quarry of the turtle, or starts a new one at `start` if it has none yet
+    pub fn load(id: u32, start: &Position) -> io::Result<Self> {
+        let path = env::var("QUARRY_FILE").unwrap_or(String::from("quarry_{id}.json"))
+            .replace("{id}", id.to_string().as_str());
+        let var = |name: &str| env::var(name).ok().and_then(|s| s.parse().ok()).unwrap_or(8);
+        Self::open(path, start, (var("QUARRY_WIDTH"), var("QUARRY_LENGTH"), var("QUARRY_DEPTH")))
+    }
+
+    /// Loads the quarry from the file, or starts one of `size` (width, length, depth) that is
+    /// saved there
+    pub fn open(path: String, start: &Position, size: (i64, i64, i64)) -> io::Result<Self> {
+        let mut quarry = match fs::read_to_string(&path) {
+            Ok(s) => json::parse(s.as_str())
+                .map_err(|e| e.to_string())
+                .and_then(|jv| Self::decode(&jv).map_err(|e| e.to_string()))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Could not parse quarry file {}: {}", path, e)))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let (width, length, depth) = size;
+                if width <= 0 || length <= 0 || depth <= 0 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              format!("Quarry size must be positive, got {}x{}x{}", width, length, depth)));
+                }
+                Self::new(start.clone(), width, length, depth)
+            }
+            Err(e) => return Err(e),
+        };
+        quarry.path = Some(path);
+        Ok(quarry)
+    }
+
+    fn decode(jv: &JsonValue) -> Result<Self, DecodeError> {
+        expect_object(jv)?;
+        let expect_size = |jv| expect_i64(jv)
+            .and_then(|n| if n > 0 { Ok(n) } else { Err(DecodeError::expected("positive integer", jv)) });
+        Ok(Self {
+            path: None,
+            start: field(jv, "start")?,
+            width: field_with(jv, "width", expect_size)?,
+            length: field_with(jv, "length", expect_size)?,
+            depth: field_with(jv, "depth", expect_size)?,
+            progress: field_with(jv, "progress", |jv| expect_u32(jv).map(i64::from))?,
+        })
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let jv = json::object! {
+            start: &self.start,
+            width: self.width,
+            length: self.length,
+            depth: self.depth,
+            progress: self.progress,
+        };
+        fs::write(path, json::stringify(jv))
+    }
+
+    fn size(&self) -> i64 {
+        self.width * self.length * self.depth
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.progress >= self.size()
+    }
+
+    /// The coordinate of the n-th block in digging order
+    pub fn block(&self, n: i64) -> Coordinate {
+        let area = self.width * self.length;
+        let layer = n / area;
+        let mut i = n % area;
+        if layer % 2 == 1 {
+            i = area - 1 - i;
+        }
+        let (column, mut row) = (i / self.length, i % self.length);
+        if column % 2 == 1 {
+            row = self.length - 1 - row;
+        }
+        let mut position = self.start.clone();
+        position.move_horizontal(1 + row);
+        position.turn(1);
+        position.move_horizontal(column);
+        position.coordinate().delta(0, -layer, 0)
+    }
+
+    fn chest_side(&self) -> Position {
+        let mut position = self.start.clone();
+        position.turn(2);
+        position
+    }
+
+    /// Digs until the box is empty, going to the chest whenever the inventory fills up or fuel
+    /// runs low. Ends at the chest. Stops without counting the block when it can't be dug for
+    /// any other reason than fuel, so it is dug first when resuming.
+    pub fn run(&mut self, executor: &mut TaskExecutor) -> Result<(), Box<dyn Error>> {
+        executor.refresh_state()??;
+        // Ran out of fuel since the last trip to the chest, without digging anything in between
+        let mut out_of_fuel = false;
+        while !self.is_done() {
+            if self.needs_unloading(executor) {
+                self.unload(executor, false)?;
+            }
+            let target = self.block(self.progress);
+            match self.dig_to(executor, target)? {
+                Ok(()) => out_of_fuel = false,
+                Err(e) if e.is_fuel() => {
+                    println!("Quarry ran out of fuel at {:?}: {}", target, e);
+                    // The fuel estimate was not enough after the last trip, so wait at the chest
+                    // instead of going back and forth
+                    self.unload(executor, out_of_fuel)?;
+                    out_of_fuel = true;
+                    continue;
+                }
+                Err(e) => return Err(format!("Could not dig {:?} of the quarry: {}", target, e).into()),
+            }
+            self.progress += 1;
+            if let Err(e) = self.save() {
+                eprintln!("Could not save quarry: {}", e);
+            }
+        }
+        println!("Quarry of {}x{}x{} done", self.width, self.length, self.depth);
+        self.unload(executor, false)?;
+        Ok(())
+    }
+
+    /// Moves into the block, digging it out. Blocks next to the turtle take a single move, others
+    /// (e.g. when resuming) a route planned through what was dug so far.
+    fn dig_to(&self, executor: &mut TaskExecutor, target: Coordinate) -> Result<Result<(), MoveError>, Box<dyn Error>> {
+        let position = executor.turtle.position.clone();
+        let here = position.coordinate();
+        let mut maneuver = Maneuver::new();
+        if target == here.delta(0, -1, 0) {
+            maneuver.push(ManeuverStep::new(Move::Down, true, 1));
+        } else if let Some(turns) = (0..4).find(|&t| Position::new(here, position.direction().turn(t)).ahead(1) == target) {
+            match turns {
+                0 => {}
+                3 => maneuver.push(ManeuverStep::new(Move::Left, false, 1)),
+                t => maneuver.push(ManeuverStep::new(Move::Right, false, t as u32)),
+            }
+            maneuver.push(ManeuverStep::new(Move::Forward, true, 1));
+        } else {
+            return executor.go_to(target, None);
+        }
+        Ok(executor.run_maneuver(&maneuver)?.map_err(|(e, _)| e))
+    }
+
+    /// Whether the inventory is about full, or the fuel, counting what could be burned, barely
+    /// gets the turtle back to the chest
+    fn needs_unloading(&self, executor: &TaskExecutor) -> bool {
+        let turtle = &executor.turtle;
+        let free_slots = 16 - turtle.inventory.item_iter().count();
+        free_slots <= 1 || self.fuel_missing(executor) > 0
+    }
+
+    /// Fuel missing to get to the chest and back to the next block, even after burning everything
+    fn fuel_missing(&self, executor: &TaskExecutor) -> i64 {
+        let turtle = &executor.turtle;
+        let distance = |a: Coordinate, b: Coordinate| (a.x() - b.x()).abs() + (a.y() - b.y()).abs() + (a.z() - b.z()).abs();
+        let needed = distance(turtle.position.coordinate(), self.start.coordinate())
+            + distance(self.start.coordinate(), self.block(self.progress.min(self.size() - 1)))
+            + FUEL_MARGIN;
+        let missing = needed - turtle.fuel_level;
+        if missing <= 0 {
+            return 0;
+        }
+        fuel::plan_refuel(&turtle.inventory, missing).err().unwrap_or(0)
+    }
+
+    /// Goes to the chest, drops everything that does not burn and waits there until the turtle
+    /// has enough fuel to carry on, at least once when `wait`. Then returns to the last block it
+    /// dug, waiting for more fuel as long as the way back needs it.
+    fn unload(&mut self, executor: &mut TaskExecutor, mut wait: bool) -> Result<(), Box<dyn Error>> {
+        let chest_side = self.chest_side();
+        if let Err(e) = executor.go_to(chest_side.coordinate(), Some(chest_side.direction()))? {
+            return Err(format!("Could not get back to the chest: {}", e).into());
+        }
+        executor.refresh_state()??;
+        let items: Vec<(usize, u8)> = executor.turtle.inventory.item_iter()
+            .filter(|(i, _)| fuel::fuel_value(i.name.as_str()).is_none())
+            .map(|(i, slot)| (slot, i.count))
+            .collect();
+        if !items.is_empty() && !executor.execute(Task::deposit(&items), TaskExecutor::default_event_handler, TaskExecutor::null_question_handler)? {
+            return Err("Could not unload into the chest".into());
+        }
+        if self.is_done() {
+            return Ok(());
+        }
+        while wait || self.fuel_missing(executor) > 0 {
+            match self.fuel_missing(executor) {
+                0 => println!("Quarry ran out of fuel, waiting at the chest for more"),
+                missing => println!("Quarry needs {} more fuel, waiting at the chest", missing),
+            }
+            executor.serve_requests_for(FUEL_WAIT)?;
+            executor.refresh_state()??;
+            wait = false;
+        }
+        let resume = if self.progress == 0 { self.start.coordinate() } else { self.block(self.progress - 1) };
+        loop {
+            match executor.go_to(resume, None)? {
+                Ok(()) => return Ok(()),
+                Err(e) if e.is_fuel() => {
+                    println!("Quarry needs more fuel to get back: {}, waiting", e);
+                    executor.serve_requests_for(FUEL_WAIT)?;
+                }
+                Err(e) => return Err(format!("Could not get back into the quarry: {}", e).into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simulator::{Block, STACK_SIZE, World};
+    use crate::simulator::testing::{finish, start_with};
+    use crate::turtle::{Coordinate, Direction, Item, Position};
+
+    use super::*;
+
+    #[test]
+    fn blocks_are_dug_in_serpentine_layers() {
+        let cases = [
+            (Quarry::new(Position::default(), 2, 3, 2), vec![
+                (0, 0, -1), (0, 0, -2), (0, 0, -3), (1, 0, -3), (1, 0, -2), (1, 0, -1),
+                (1, -1, -1), (1, -1, -2), (1, -1, -3), (0, -1, -3), (0, -1, -2), (0, -1, -1),
+            ]),
+            (Quarry::new(Position::new(Coordinate::new(5, 10, 5), Direction::East), 2, 2, 1), vec![
+                (6, 10, 5), (7, 10, 5), (7, 10, 6), (6, 10, 6),
+            ]),
+            (Quarry::new(Position::default(), 1, 1, 3), vec![
+                (0, 0, -1), (0, -1, -1), (0, -2, -1),
+            ]),
+        ];
+        for (quarry, expected) in cases.iter() {
+            let blocks: Vec<Coordinate> = (0..quarry.size()).map(|n| quarry.block(n)).collect();
+            let expected: Vec<Coordinate> = expected.iter().map(|&(x, y, z)| Coordinate::new(x, y, z)).collect();
+            assert_eq!(blocks, expected, "{}x{}x{}", quarry.width, quarry.length, quarry.depth);
+        }
+    }
+
+    #[test]
+    fn every_block_is_next_to_the_one_before() {
+        let sizes = [(1, 1, 1), (1, 4, 3), (3, 1, 3), (2, 2, 4), (3, 4, 3), (4, 5, 2), (5, 5, 3)];
+        for &(width, length, depth) in sizes.iter() {
+            let quarry = Quarry::new(Position::default(), width, length, depth);
+            let blocks: Vec<Coordinate> = (0..quarry.size()).map(|n| quarry.block(n)).collect();
+            let mut unique = blocks.clone();
+            unique.sort_by_key(|c| (c.x(), c.y(), c.z()));
+            unique.dedup();
+            assert_eq!(unique.len(), blocks.len(), "{}x{}x{} digs a block twice", width, length, depth);
+            assert!(blocks.iter().all(|c| (0..width).contains(&c.x()) && (1 - depth..=0).contains(&c.y()) && (-length..0).contains(&c.z())),
+                    "{}x{}x{} leaves the box", width, length, depth);
+            for (n, pair) in blocks.windows(2).enumerate() {
+                let (a, b) = (pair[0], pair[1]);
+                let distance = (a.x() - b.x()).abs() + (a.y() - b.y()).abs() + (a.z() - b.z()).abs();
+                assert_eq!(distance, 1, "{}x{}x{}: block {} {:?} is not next to {:?}", width, length, depth, n + 1, b, a);
+            }
+        }
+    }
+
+    #[test]
+    fn saved_quarry_loads_back() {
+        let path = env::temp_dir().join(format!("quarry_{}.json", std::process::id()));
+        let path = path.to_str().unwrap().to_owned();
+        let _ = fs::remove_file(&path);
+
+        let start = Position::new(Coordinate::new(3, 60, -7), Direction::West);
+        let mut quarry = Quarry::open(path.clone(), &start, (2, 3, 4)).unwrap();
+        quarry.progress = 5;
+        quarry.save().unwrap();
+
+        // The size of a saved quarry wins over the configured one
+        let loaded = Quarry::open(path.clone(), &Position::default(), (8, 8, 8)).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!((loaded.width, loaded.length, loaded.depth, loaded.progress), (2, 3, 4, 5));
+        assert_eq!((loaded.start.coordinate(), loaded.start.direction()), (start.coordinate(), start.direction()));
+    }
+
+    #[test]
+    fn sizes_must_be_positive() {
+        let path = env::temp_dir().join(format!("quarry_missing_{}.json", std::process::id()));
+        let path = path.to_str().unwrap().to_owned();
+        for &size in [(0, 8, 8), (8, -1, 8), (8, 8, 0)].iter() {
+            let error = Quarry::open(path.clone(), &Position::default(), size).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{:?}", size);
+        }
+        assert!(Quarry::open(path, &Position::default(), (1, 1, 1)).is_ok());
+    }
+
+    #[test]
+    fn bad_quarries_are_errors() {
+        let start = r#""start": {"coordinate": {"x": 0, "y": 0, "z": 0}, "direction": "N"}"#;
+        let cases = [
+            (format!(r#"{{{}, "width": 0, "length": 1, "depth": 1, "progress": 0}}"#, start), "width"),
+            (format!(r#"{{{}, "width": 1, "length": -2, "depth": 1, "progress": 0}}"#, start), "length"),
+            (format!(r#"{{{}, "width": 1, "length": 1, "depth": 1, "progress": -1}}"#, start), "progress"),
+            (r#"{"width": 1, "length": 1, "depth": 1, "progress": 0}"#.to_owned(), "start"),
+        ];
+        for (input, path) in cases.iter() {
+            let error = Quarry::decode(&json::parse(input).unwrap()).err().unwrap();
+            assert!(error.to_string().contains(path), "{} should fail at {}, got {}", input, path, error);
+        }
+    }
+
+    #[test]
+    fn quarry_digs_the_box_and_unloads_when_full() {
+        let mut world = World::new();
+        world.fill(Coordinate::new(-1, -3, -5), Coordinate::new(4, 1, 0), "minecraft:stone");
+        world.set_block(Coordinate::new(0, 0, 0), None);
+        world.set_block(Coordinate::new(0, 0, 1), Some(Block::new("minecraft:chest")));
+        // Only two free slots, so the first trip to the chest comes early
+        let (mut executor, handle) = start_with(world, 1000, |t| {
+            for slot in 0..14 {
+                t.inventory[slot] = Some(Item { count: STACK_SIZE, name: "minecraft:dirt".to_owned() });
+            }
+        });
+        let mut quarry = Quarry::new(Position::default(), 3, 4, 2);
+
+        quarry.run(&mut executor).unwrap();
+        assert!(quarry.is_done());
+
+        let turtle = finish(executor, handle);
+        for n in 0..3 * 4 * 2 {
+            assert!(turtle.world.block(quarry.block(n)).is_none(), "{:?} was not dug", quarry.block(n));
+        }
+        assert!(turtle.world.block(Coordinate::new(3, 0, -1)).is_some());
+        assert!(turtle.world.block(Coordinate::new(0, -2, -1)).is_some());
+        let chest = turtle.world.block(Coordinate::new(0, 0, 1)).unwrap();
+        let count = |name: &str| chest.contents.iter().filter(|i| i.name == name).map(|i| i.count as u32).sum::<u32>();
+        assert_eq!(count("minecraft:dirt"), 14 * STACK_SIZE as u32);
+        assert_eq!(count("minecraft:cobblestone"), 3 * 4 * 2);
+        assert_eq!(turtle.item_count("minecraft:cobblestone"), 0);
+    }
+
+    #[test]
+    fn quarry_resumes_from_its_file() {
+        let path = std::env::temp_dir().join(format!("quarry_resume_{}.json", std::process::id()));
+        let path = path.to_str().unwrap().to_owned();
+        let _ = std::fs::remove_file(&path);
+        let mut quarry = Quarry::open(path.clone(), &Position::default(), (2, 3, 2)).unwrap();
+        quarry.progress = 4;
+        quarry.save().unwrap();
+
+        // The first four blocks were dug before the turtle stopped
+        let mut world = World::new();
+        world.fill(Coordinate::new(-1, -2, -4), Coordinate::new(2, 1, 0), "minecraft:stone");
+        world.set_block(Coordinate::new(0, 0, 0), None);
+        world.set_block(Coordinate::new(0, 0, 1), Some(Block::new("minecraft:chest")));
+        for n in 0..4 {
+            world.set_block(quarry.block(n), None);
+        }
+        let (mut executor, handle) = start_with(world, 1000, |_| {});
+        let mut quarry = Quarry::open(path.clone(), &Position::default(), (8, 8, 8)).unwrap();
+        assert_eq!(quarry.progress, 4);
+
+        quarry.run(&mut executor).unwrap();
+        let saved = Quarry::open(path.clone(), &Position::default(), (8, 8, 8)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(saved.is_done());
+
+        let turtle = finish(executor, handle);
+        for n in 0..2 * 3 * 2 {
+            assert!(turtle.world.block(quarry.block(n)).is_none(), "{:?} was not dug", quarry.block(n));
+        }
+        let chest = turtle.world.block(Coordinate::new(0, 0, 1)).unwrap();
+        let dug: u32 = chest.contents.iter().filter(|i| i.name == "minecraft:cobblestone").map(|i| i.count as u32).sum();
+        assert_eq!(dug, 2 * 3 * 2 - 4);
+    }
+
+    #[test]
+    fn quarry_stops_at_a_block_it_can_not_dig() {
+        let mut world = World::new();
+        world.fill(Coordinate::new(-1, -2, -4), Coordinate::new(2, 1, 0), "minecraft:stone");
+        world.set_block(Coordinate::new(0, 0, 0), None);
+        world.set_block(Coordinate::new(0, 0, 1), Some(Block::new("minecraft:chest")));
+        let mut quarry = Quarry::new(Position::default(), 2, 3, 2);
+        world.set_block(quarry.block(2), Some(Block::new("minecraft:bedrock")));
+        let (mut executor, handle) = start_with(world, 1000, |_| {});
+
+        assert!(quarry.run(&mut executor).is_err());
+        assert_eq!(quarry.progress, 2);
+
+        let turtle = finish(executor, handle);
+        assert!(turtle.world.block(quarry.block(1)).is_none());
+        assert!(turtle.world.block(quarry.block(3)).is_some());
+    }
+}
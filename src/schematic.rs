@@ -0,0 +1,391 @@
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::io::Read;
+use std::{fs, io};
+
+use flate2::read::GzDecoder;
+use json::JsonValue;
+
+use crate::decode::{DecodeError, expect_array, expect_object, expect_str, field_with};
+
+/// A box of blocks to build, `None` where nothing goes. `x` runs to the right, `y` up and `z`
+/// away from whoever builds it, indices are `x + z * width + y * width * length` like in Sponge
+/// schematics.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Schematic {
+    pub width: usize,
+    pub height: usize,
+    pub length: usize,
+    blocks: Vec<Option<String>>,
+}
+
+impl Schematic {
+    /// Loads a Sponge schematic (`.schem`, version 2 or 3) or the json layer format
+    pub fn load(path: &str) -> io::Result<Self> {
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, format!("Could not parse schematic {}: {}", path, e));
+        if path.ends_with(".schem") {
+            Self::from_sponge(fs::read(path)?.as_slice()).map_err(invalid)
+        } else {
+            let s = fs::read_to_string(path)?;
+            json::parse(s.as_str())
+                .map_err(|e| e.to_string())
+                .and_then(|jv| Self::try_from(&jv).map_err(|e| e.to_string()))
+                .map_err(invalid)
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize, z: usize) -> Option<&str> {
+        self.blocks[x + z * self.width + y * self.width * self.length].as_deref()
+    }
+
+    /// Reads the gzipped NBT of a Sponge schematic. Block states are dropped, turtles place
+    /// blocks the way they face.
+    pub fn from_sponge(bytes: &[u8]) -> Result<Self, String> {
+        let mut nbt = Vec::new();
+        GzDecoder::new(bytes).read_to_end(&mut nbt).map_err(|e| e.to_string())?;
+        let root = Nbt::read_root(&mut nbt.as_slice())?;
+        // Version 3 wraps everything in a `Schematic` compound and moves the blocks into `Blocks`
+        let schematic = root.get("Schematic").unwrap_or(&root);
+        let blocks = schematic.get("Blocks").unwrap_or(schematic);
+        let dimension = |key: &str| match schematic.get(key) {
+            Some(Nbt::Short(s)) => Ok(*s as u16 as usize),
+            _ => Err(format!("Expected short {}", key)),
+        };
+        let (width, height, length) = (dimension("Width")?, dimension("Height")?, dimension("Length")?);
+
+        let mut palette = HashMap::new();
+        match blocks.get("Palette") {
+            Some(Nbt::Compound(entries)) => for (state, index) in entries {
+                let index = match index {
+                    Nbt::Int(i) => *i,
+                    _ => return Err(format!("Expected int palette index for {}", state)),
+                };
+                let name = state.split('[').next().unwrap_or("");
+                let block = match name {
+                    "minecraft:air" | "minecraft:cave_air" | "minecraft:void_air" | "minecraft:structure_void" => None,
+                    name => Some(name.to_owned()),
+                };
+                palette.insert(index, block);
+            },
+            _ => return Err("Expected compound Palette".to_string()),
+        }
+        let data = match blocks.get("BlockData").or_else(|| blocks.get("Data")) {
+            Some(Nbt::ByteArray(data)) => data,
+            _ => return Err("Expected byte array BlockData".to_string()),
+        };
+
+        // Palette indices are varints, at most 5 bytes for an i32
+        let mut indices = Vec::with_capacity(width * height * length);
+        let (mut value, mut shift) = (0i32, 0);
+        for byte in data {
+            value |= ((byte & 0x7f) as i32) << shift;
+            if byte & 0x80 == 0 {
+                indices.push(value);
+                value = 0;
+                shift = 0;
+            } else if shift == 28 {
+                return Err(format!("Palette index {} is longer than 5 bytes", indices.len()));
+            } else {
+                shift += 7;
+            }
+        }
+        if indices.len() != width * height * length {
+            return Err(format!("Expected {} blocks, got {}", width * height * length, indices.len()));
+        }
+        let blocks = indices.iter()
+            .map(|i| palette.get(i).cloned().ok_or_else(|| format!("Palette has no block {}", i)))
+            .collect::<Result<Vec<Option<String>>, String>>()?;
+        Ok(Self { width, height, length, blocks })
+    }
+}
+
+/// The json layer format: a palette of characters and the layers from the bottom up, each a list
+/// of rows from front to back. `.` and space are air.
+/// ```json
+/// {"palette": {"#": "minecraft:cobblestone"}, "layers": [["###", "#.#", "###"]]}
+/// ```
+impl TryFrom<&JsonValue> for Schematic {
+    type Error = DecodeError;
+
+    fn try_from(jv: &JsonValue) -> Result<Self, Self::Error> {
+        expect_object(jv)?;
+        let mut palette = HashMap::new();
+        for (key, name) in field_with(jv, "palette", expect_object)?.iter() {
+            let c = match key.chars().collect::<Vec<char>>().as_slice() {
+                [c] => *c,
+                _ => return Err(DecodeError::expected("single character", &key.into()).at("palette")),
+            };
+            palette.insert(c, expect_str(name).map_err(|e| e.at(key).at("palette"))?.to_owned());
+        }
+        let layers = field_with(jv, "layers", expect_array)?;
+        let rows = |layer: &JsonValue| -> Result<Vec<Vec<char>>, DecodeError> {
+            expect_array(layer)?.iter().enumerate()
+                .map(|(i, row)| expect_str(row).map(|r| r.chars().collect()).map_err(|e| e.at_index(i)))
+                .collect()
+        };
+        let layers = layers.iter().enumerate()
+            .map(|(i, layer)| rows(layer).map_err(|e| e.at_index(i).at("layers")))
+            .collect::<Result<Vec<Vec<Vec<char>>>, DecodeError>>()?;
+
+        let height = layers.len();
+        let length = layers.iter().map(Vec::len).max().unwrap_or(0);
+        let width = layers.iter().flatten().map(Vec::len).max().unwrap_or(0);
+        let mut blocks = vec![None; width * height * length];
+        for (y, layer) in layers.iter().enumerate() {
+            for (z, row) in layer.iter().enumerate() {
+                for (x, c) in row.iter().enumerate() {
+                    blocks[x + z * width + y * width * length] = match c {
+                        '.' | ' ' => None,
+                        c => Some(palette.get(c).cloned().ok_or_else(|| {
+                            DecodeError::expected("character from the palette", &c.to_string().into()).at_index(z).at_index(y).at("layers")
+                        })?),
+                    };
+                }
+            }
+        }
+        Ok(Self { width, height, length, blocks })
+    }
+}
+
+/// The subset of NBT that schematics use
+#[derive(Debug)]
+enum Nbt {
+    Short(i16),
+    Int(i32),
+    Other,
+    ByteArray(Vec<u8>),
+    Compound(HashMap<String, Nbt>),
+}
+
+impl Nbt {
+    fn get(&self, key: &str) -> Option<&Nbt> {
+        match self {
+            Nbt::Compound(entries) => entries.get(key),
+            _ => None,
+        }
+    }
+
+    fn read_root(input: &mut &[u8]) -> Result<Nbt, String> {
+        if take(input, 1)?[0] != 10 {
+            return Err("Expected a compound at the root".to_string());
+        }
+        read_string(input)?;
+        Self::read(10, input)
+    }
+
+    fn read(tag: u8, input: &mut &[u8]) -> Result<Nbt, String> {
+        let length = |input: &mut &[u8]| -> Result<usize, String> {
+            Ok(i32::from_be_bytes(take(input, 4)?.try_into().unwrap()).max(0) as usize)
+        };
+        Ok(match tag {
+            1 => {
+                take(input, 1)?;
+                Nbt::Other
+            }
+            2 => Nbt::Short(i16::from_be_bytes(take(input, 2)?.try_into().unwrap())),
+            3 => Nbt::Int(i32::from_be_bytes(take(input, 4)?.try_into().unwrap())),
+            4 | 6 => {
+                take(input, 8)?;
+                Nbt::Other
+            }
+            5 => {
+                take(input, 4)?;
+                Nbt::Other
+            }
+            7 => {
+                let n = length(input)?;
+                Nbt::ByteArray(take(input, n)?.to_vec())
+            }
+            8 => {
+                read_string(input)?;
+                Nbt::Other
+            }
+            9 => {
+                let item = take(input, 1)?[0];
+                for _ in 0..length(input)? {
+                    Self::read(item, input)?;
+                }
+                Nbt::Other
+            }
+            10 => {
+                let mut entries = HashMap::new();
+                loop {
+                    let tag = take(input, 1)?[0];
+                    if tag == 0 {
+                        break;
+                    }
+                    let name = read_string(input)?;
+                    entries.insert(name, Self::read(tag, input)?);
+                }
+                Nbt::Compound(entries)
+            }
+            11 => {
+                let n = length(input)?;
+                take(input, n * 4)?;
+                Nbt::Other
+            }
+            12 => {
+                let n = length(input)?;
+                take(input, n * 8)?;
+                Nbt::Other
+            }
+            tag => return Err(format!("Unknown NBT tag {}", tag)),
+        })
+    }
+}
+
+fn take<'a>(input: &mut &'a [u8], n: usize) -> Result<&'a [u8], String> {
+    if input.len() < n {
+        return Err("Unexpected end of NBT".to_string());
+    }
+    let (taken, rest) = input.split_at(n);
+    *input = rest;
+    Ok(taken)
+}
+
+fn read_string(input: &mut &[u8]) -> Result<String, String> {
+    let n = u16::from_be_bytes(take(input, 2)?.try_into().unwrap()) as usize;
+    String::from_utf8(take(input, n)?.to_vec()).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    fn tag(nbt: &mut Vec<u8>, tag: u8, name: &str, payload: &[u8]) {
+        nbt.push(tag);
+        nbt.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        nbt.extend_from_slice(name.as_bytes());
+        nbt.extend_from_slice(payload);
+    }
+
+    fn gzip(nbt: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(nbt).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// The uncompressed NBT of a Sponge schematic of the given size, version 3 nests the blocks
+    /// like the real ones
+    fn sponge(version: i32, (width, height, length): (i16, i16, i16), palette: &[(&str, i32)], data: &[u8]) -> Vec<u8> {
+        let mut entries = Vec::new();
+        for (state, index) in palette {
+            tag(&mut entries, 3, state, &index.to_be_bytes());
+        }
+        entries.push(0);
+        let mut block_data = (data.len() as i32).to_be_bytes().to_vec();
+        block_data.extend_from_slice(data);
+
+        let mut schematic = Vec::new();
+        tag(&mut schematic, 3, "Version", &version.to_be_bytes());
+        tag(&mut schematic, 2, "Width", &width.to_be_bytes());
+        tag(&mut schematic, 2, "Height", &height.to_be_bytes());
+        tag(&mut schematic, 2, "Length", &length.to_be_bytes());
+        // Something the reader has to skip
+        tag(&mut schematic, 11, "Offset", &[0, 0, 0, 3, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3]);
+        if version >= 3 {
+            let mut blocks = Vec::new();
+            tag(&mut blocks, 10, "Palette", &entries);
+            tag(&mut blocks, 7, "Data", &block_data);
+            blocks.push(0);
+            tag(&mut schematic, 10, "Blocks", &blocks);
+        } else {
+            tag(&mut schematic, 10, "Palette", &entries);
+            tag(&mut schematic, 7, "BlockData", &block_data);
+        }
+        schematic.push(0);
+
+        let mut nbt = Vec::new();
+        if version >= 3 {
+            let mut root = Vec::new();
+            tag(&mut root, 10, "Schematic", &schematic);
+            root.push(0);
+            tag(&mut nbt, 10, "", &root);
+        } else {
+            tag(&mut nbt, 10, "Schematic", &schematic);
+        }
+        nbt
+    }
+
+    const PALETTE: [(&str, i32); 3] = [
+        ("minecraft:air", 0),
+        ("minecraft:cobblestone", 1),
+        ("minecraft:oak_planks[waterlogged=false]", 2),
+    ];
+    const DATA: [u8; 12] = [1, 2, 1, 2, 0, 2, 1, 0, 1, 0, 0, 0];
+
+    fn layers() -> Schematic {
+        let jv = json::object! {
+            palette: { "#": "minecraft:cobblestone", "P": "minecraft:oak_planks" },
+            layers: [["#P#", "P.P"], ["#.#"]],
+        };
+        Schematic::try_from(&jv).unwrap()
+    }
+
+    #[test]
+    fn json_layers_go_from_the_bottom_up() {
+        let schematic = layers();
+        assert_eq!((schematic.width, schematic.height, schematic.length), (3, 2, 2));
+        assert_eq!(schematic.get(0, 0, 0), Some("minecraft:cobblestone"));
+        assert_eq!(schematic.get(1, 0, 0), Some("minecraft:oak_planks"));
+        assert_eq!(schematic.get(1, 0, 1), None);
+        assert_eq!(schematic.get(2, 1, 0), Some("minecraft:cobblestone"));
+        // Missing rows are air
+        assert_eq!(schematic.get(0, 1, 1), None);
+    }
+
+    #[test]
+    fn bad_json_layers_are_errors() {
+        let cases = [
+            (r##"{"palette": {"ab": "minecraft:stone"}, "layers": []}"##, "palette"),
+            (r##"{"palette": {"#": 1}, "layers": []}"##, "palette.#"),
+            (r##"{"palette": {}, "layers": "#"}"##, "layers"),
+            (r##"{"palette": {}, "layers": [["..", 3]]}"##, "layers[0][1]"),
+            (r##"{"palette": {"#": "minecraft:stone"}, "layers": [["#"], [".", "#X"]]}"##, "layers[1][1]"),
+        ];
+        for (input, path) in cases.iter() {
+            let error = Schematic::try_from(&json::parse(input).unwrap()).unwrap_err();
+            assert!(error.to_string().contains(path), "{} should fail at {}, got {}", input, path, error);
+        }
+    }
+
+    #[test]
+    fn sponge_versions_read_the_same_blocks() {
+        for version in [2, 3] {
+            let nbt = sponge(version, (3, 2, 2), &PALETTE, &DATA);
+            assert_eq!(Schematic::from_sponge(&gzip(&nbt)), Ok(layers()), "version {}", version);
+        }
+    }
+
+    #[test]
+    fn palette_indices_can_take_several_bytes() {
+        let palette = [("minecraft:air", 0), ("minecraft:stone", 300), ("minecraft:dirt", i32::MAX)];
+        let data = [0xac, 0x02, 0, 0xff, 0xff, 0xff, 0xff, 0x07];
+        let schematic = Schematic::from_sponge(&gzip(&sponge(2, (3, 1, 1), &palette, &data))).unwrap();
+        assert_eq!(schematic.get(0, 0, 0), Some("minecraft:stone"));
+        assert_eq!(schematic.get(1, 0, 0), None);
+        assert_eq!(schematic.get(2, 0, 0), Some("minecraft:dirt"));
+    }
+
+    #[test]
+    fn bad_sponge_schematics_are_errors() {
+        let mut truncated = sponge(2, (3, 2, 2), &PALETTE, &DATA);
+        truncated.truncate(truncated.len() - 8);
+        let cases: [(&str, Vec<u8>, &str); 5] = [
+            ("unknown palette index", sponge(2, (3, 2, 2), &PALETTE, &[1, 2, 1, 2, 0, 2, 1, 0, 1, 0, 0, 5]), "no block 5"),
+            ("too few blocks", sponge(3, (3, 2, 2), &PALETTE, &DATA[1..]), "Expected 12 blocks, got 11"),
+            ("varint of 6 bytes", sponge(2, (1, 1, 1), &PALETTE, &[0x80, 0x80, 0x80, 0x80, 0x80, 0x01]), "longer than 5 bytes"),
+            ("truncated", truncated, "Unexpected end of NBT"),
+            ("no compound at the root", vec![8, 0, 0, 0, 0], "Expected a compound"),
+        ];
+        for (name, nbt, message) in cases.iter() {
+            let error = Schematic::from_sponge(&gzip(nbt)).unwrap_err();
+            assert!(error.contains(message), "{}: expected {}, got {}", name, message, error);
+        }
+        assert!(Schematic::from_sponge(b"not gzip").is_err());
+    }
+}
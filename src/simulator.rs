@@ -0,0 +1,1500 @@
+//! Headless turtle that talks the websocket protocol of `websocket.lua`, so the server can be run and
+//! tested without a ComputerCraft world. The tasks in `lua-scripts/tasks` are ported one to one,
+//! including their quirks, on top of a small voxel world.
+
+use std::collections::HashMap;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use json::JsonValue;
+use std::net::TcpStream;
+use tungstenite::{Message, WebSocket};
+use tungstenite::client::IntoClientRequest;
+use tungstenite::http::header::InvalidHeaderValue;
+use tungstenite::stream::MaybeTlsStream;
+
+use crate::maneuver::{ManeuverStep, Move};
+use crate::turtle::{Coordinate, Item, Position};
+use crate::turtle_websocket::SECRET_HEADER;
+
+pub const STACK_SIZE: u8 = 64;
+pub const TREE_HEIGHT: i64 = 5;
+/// One in this many broken leaves drops a sapling
+const SAPLING_CHANCE: u32 = 3;
+/// How long the turtle waits before reconnecting when it lost the connection
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Debug)]
+pub struct Block {
+    pub name: String,
+    /// Items stored in the block, only used by chests
+    pub contents: Vec<Item>,
+}
+
+impl Block {
+    pub fn new(name: &str) -> Self {
+        Self { name: name.to_owned(), contents: Vec::new() }
+    }
+
+    pub fn is_log(&self) -> bool {
+        self.name.ends_with("_log")
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct World {
+    blocks: HashMap<Coordinate, Block>,
+    /// Items lying around after being dropped where there was no inventory to drop them in
+    dropped: HashMap<Coordinate, Vec<Item>>,
+    leaves_broken: u32,
+}
+
+impl World {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A dirt floor just below the origin with one tree right in front of a turtle at the origin facing north
+    pub fn lumberjack() -> Self {
+        let mut world = Self::new();
+        world.fill(Coordinate::new(-8, -3, -8), Coordinate::new(8, -2, 8), "minecraft:stone");
+        world.fill(Coordinate::new(-8, -1, -8), Coordinate::new(8, -1, 8), "minecraft:dirt");
+        world.grow_tree(Coordinate::new(0, 0, -1), None);
+        world
+    }
+
+    pub fn block(&self, coordinate: Coordinate) -> Option<&Block> {
+        self.blocks.get(&coordinate)
+    }
+
+    pub fn set_block(&mut self, coordinate: Coordinate, block: Option<Block>) {
+        match block {
+            Some(block) => self.blocks.insert(coordinate, block),
+            None => self.blocks.remove(&coordinate),
+        };
+    }
+
+    /// Fills the box between two corners (inclusive) with a block
+    pub fn fill(&mut self, from: Coordinate, to: Coordinate, name: &str) {
+        for x in from.x().min(to.x())..=from.x().max(to.x()) {
+            for y in from.y().min(to.y())..=from.y().max(to.y()) {
+                for z in from.z().min(to.z())..=from.z().max(to.z()) {
+                    self.set_block(Coordinate::new(x, y, z), Some(Block::new(name)));
+                }
+            }
+        }
+    }
+
+    /// Grows an oak with its trunk starting at `base`. Leaves only replace air and never `avoid`,
+    /// which is where the turtle is.
+    pub fn grow_tree(&mut self, base: Coordinate, avoid: Option<Coordinate>) {
+        for dy in 0..TREE_HEIGHT {
+            self.set_block(base.delta(0, dy, 0), Some(Block::new("minecraft:oak_log")));
+        }
+        let canopy = [(TREE_HEIGHT - 2, 2), (TREE_HEIGHT - 1, 2), (TREE_HEIGHT, 1)];
+        for (dy, radius) in canopy.iter() {
+            for dx in -radius..=*radius {
+                for dz in -radius..=*radius {
+                    let c = base.delta(dx, *dy, dz);
+                    if self.block(c).is_none() && Some(c) != avoid {
+                        self.set_block(c, Some(Block::new("minecraft:oak_leaves")));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Removes a block and returns what it drops, or `None` if there is nothing to break
+    fn break_block(&mut self, coordinate: Coordinate) -> Option<Vec<Item>> {
+        let block = self.block(coordinate)?;
+        if block.name == "minecraft:bedrock" {
+            return None;
+        }
+        let block = self.blocks.remove(&coordinate).unwrap();
+        let drop = |name: &str| vec![Item { count: 1, name: name.to_owned() }];
+        Some(match block.name.as_str() {
+            name if name.ends_with("_leaves") => {
+                self.leaves_broken += 1;
+                if self.leaves_broken.is_multiple_of(SAPLING_CHANCE) {
+                    drop(name.replace("_leaves", "_sapling").as_str())
+                } else {
+                    Vec::new()
+                }
+            }
+            "minecraft:stone" => drop("minecraft:cobblestone"),
+            "minecraft:grass_block" => drop("minecraft:dirt"),
+            "minecraft:chest" => {
+                let mut items = block.contents;
+                items.push(Item { count: 1, name: block.name });
+                items
+            }
+            name => drop(name),
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Side {
+    Front,
+    Up,
+    Down,
+}
+
+fn fuel_value(name: &str) -> i64 {
+    match name {
+        "minecraft:coal" | "minecraft:charcoal" => 80,
+        "minecraft:lava_bucket" => 1000,
+        "minecraft:stick" => 5,
+        name if name.ends_with("_log") || name.ends_with("_planks") => 15,
+        name if name.ends_with("_sapling") => 5,
+        _ => 0,
+    }
+}
+
+/// Why a (simulated) task stopped before finishing
+#[derive(Debug)]
+enum Abort {
+    /// The task raised a lua error, it reports itself as cancelled
+    Failed(String),
+    /// The server cancelled the task with this cid
+    Cancelled(u32),
+    Disconnected(Box<tungstenite::Error>),
+}
+
+impl From<tungstenite::Error> for Abort {
+    fn from(e: tungstenite::Error) -> Self {
+        Abort::Disconnected(Box::new(e))
+    }
+}
+
+type MoveResult = Result<(), (&'static str, usize)>;
+
+pub struct SimTurtle {
+    pub world: World,
+    pub position: Position,
+    pub fuel_level: i64,
+    pub inventory: [Option<Item>; 16],
+    /// Drop and restore the connection before every n-th message that is sent, to exercise reconnects
+    pub drop_connection_every: Option<u32>,
+    /// Reboot once when the server starts this task, before acknowledging it
+    pub reboot_on_task: Option<String>,
+    /// Lose the connection once before sending this task event, and only reconnect after
+    /// `RECONNECT_DELAY`, like `websocket.lua` waits a second
+    pub lose_connection_on: Option<String>,
+    selected: usize,
+    reported: [Option<Item>; 16],
+    url: String,
+    secret: String,
+    client: WebSocket<MaybeTlsStream<TcpStream>>,
+    /// Like `os.epoch` in `websocket.lua`, changes when the turtle reboots
+    session: u64,
+    mid_counter: u32,
+    /// Sent messages the server did not acknowledge yet, replayed after a reconnect
+    unacked: Vec<(u32, String)>,
+    last_received: Option<u32>,
+    server_session: Option<u64>,
+    tasks: Vec<u32>,
+    /// The events that tasks further down the stack wait for, each in its own coroutine in Lua
+    awaiting: Vec<(String, u32)>,
+    /// Events for one of `awaiting` that came in while a nested command ran
+    pulled: Vec<(String, u32, JsonValue)>,
+}
+
+/// Starts a simulated turtle in its own thread. The thread ends when the server closes the
+/// connection and gives back the turtle, so its world can be inspected.
+#[cfg(feature = "simulator")]
+pub fn spawn_simulator(url: String, secret: String, world: World, fuel_level: i64) -> Result<JoinHandle<SimTurtle>, Box<tungstenite::Error>> {
+    let mut turtle = SimTurtle::connect(url, secret, world, fuel_level)?;
+    Ok(thread::spawn(move || {
+        if let Err(e) = turtle.run() {
+            println!("Simulated turtle stopped: {}", e);
+        }
+        turtle
+    }))
+}
+
+impl SimTurtle {
+    /// Connects to a websocket url of the form `ws://host:port/ws/{id}`, with the secret of the id
+    pub fn connect(url: String, secret: String, world: World, fuel_level: i64) -> Result<Self, Box<tungstenite::Error>> {
+        let client = Self::open(&url, &secret)?;
+        const EMPTY: Option<Item> = None;
+        let mut turtle = Self {
+            world,
+            position: Position::default(),
+            fuel_level,
+            inventory: [EMPTY; 16],
+            drop_connection_every: None,
+            reboot_on_task: None,
+            lose_connection_on: None,
+            selected: 0,
+            reported: [EMPTY; 16],
+            url,
+            secret,
+            client,
+            session: 1,
+            mid_counter: 0,
+            unacked: Vec::new(),
+            last_received: None,
+            server_session: None,
+            tasks: Vec::new(),
+            awaiting: Vec::new(),
+            pulled: Vec::new(),
+        };
+        turtle.resync()?;
+        Ok(turtle)
+    }
+
+    fn open(url: &str, secret: &str) -> Result<WebSocket<MaybeTlsStream<TcpStream>>, Box<tungstenite::Error>> {
+        let mut request = url.into_client_request()?;
+        let secret = secret.parse().map_err(|e: InvalidHeaderValue| tungstenite::Error::HttpFormat(e.into()))?;
+        request.headers_mut().insert(SECRET_HEADER, secret);
+        Ok(tungstenite::connect(request)?.0)
+    }
+
+    /// Handles commands until the connection is closed
+    pub fn run(&mut self) -> Result<(), Box<tungstenite::Error>> {
+        loop {
+            let message = match self.receive() {
+                Ok(message) => message,
+                Err(Abort::Disconnected(e)) => return Err(e),
+                Err(e) => panic!("Unexpected abort outside of a task: {:?}", e),
+            };
+            match self.handle_message(message) {
+                Ok(()) => {}
+                Err(Abort::Disconnected(e)) => return Err(e),
+                Err(e) => eprintln!("Simulated turtle got abort outside of a task: {:?}", e),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub fn item_count(&self, name: &str) -> u32 {
+        self.inventory.iter().flatten()
+            .filter(|i| i.name == name)
+            .map(|i| i.count as u32)
+            .sum()
+    }
+
+    // --- Protocol
+
+    /// Receives the next message from the server that was not received before, acknowledging
+    /// it like `websocket.lua` does
+    fn receive(&mut self) -> Result<JsonValue, Abort> {
+        loop {
+            // Pings are answered by tungstenite while reading
+            let jv = match self.client.read_message()? {
+                Message::Text(s) => match json::parse(s.as_str()) {
+                    Ok(jv) => jv,
+                    Err(e) => {
+                        eprintln!("Simulated turtle could not parse {}: {}", s, e);
+                        continue;
+                    }
+                },
+                Message::Close(_) => return Err(tungstenite::Error::ConnectionClosed.into()),
+                _ => continue,
+            };
+            match jv["c"].as_str() {
+                Some("ACK") => self.acknowledged(jv["ack"].as_u32()),
+                Some("HELLO") => self.greeted(&jv)?,
+                _ if self.reboots_for(&jv) => {
+                    self.reboot().map_err(Abort::Disconnected)?;
+                }
+                _ => {
+                    let mid = jv["mid"].as_u32();
+                    let duplicate = matches!((mid, self.last_received), (Some(mid), Some(last)) if mid <= last);
+                    if !duplicate {
+                        self.last_received = mid.or(self.last_received);
+                    }
+                    let ack = json::object! { c: "ack", ack: self.last_received };
+                    self.client.write_message(Message::Text(json::stringify(ack)))?;
+                    if !duplicate {
+                        return Ok(jv);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends what the server did not acknowledge yet, unless it restarted
+    fn greeted(&mut self, hello: &JsonValue) -> Result<(), Abort> {
+        let session = hello["session"].as_u64();
+        if session != self.server_session {
+            if self.server_session.is_some() {
+                self.unacked.clear();
+            }
+            self.server_session = session;
+            self.last_received = None;
+        }
+        if hello["ack_session"].as_u64() == Some(self.session) {
+            self.acknowledged(hello["ack"].as_u32());
+        }
+        for (_, message) in &self.unacked {
+            self.client.write_message(Message::Text(message.clone()))?;
+        }
+        Ok(())
+    }
+
+    fn acknowledged(&mut self, ack: Option<u32>) {
+        if let Some(ack) = ack {
+            self.unacked.retain(|(mid, _)| *mid > ack);
+        }
+    }
+
+    fn reboots_for(&mut self, message: &JsonValue) -> bool {
+        let task = message["b"]["b"]["c"].as_str();
+        let reboot = message["c"] == "COMMAND" && message["b"]["c"] == "TASK" && task.is_some() && self.reboot_on_task.as_deref() == task;
+        if reboot {
+            self.reboot_on_task = None;
+        }
+        reboot
+    }
+
+    /// Loses everything `websocket.lua` keeps in memory and connects again with a new session,
+    /// the world and inventory stay
+    fn reboot(&mut self) -> Result<(), Box<tungstenite::Error>> {
+        let _ = self.client.close(None);
+        let _ = self.client.write_pending();
+        self.session += 1;
+        self.mid_counter = 0;
+        self.unacked.clear();
+        self.last_received = None;
+        self.server_session = None;
+        self.tasks.clear();
+        self.awaiting.clear();
+        self.pulled.clear();
+        self.client = Self::open(&self.url, &self.secret)?;
+        self.resync()
+    }
+
+    fn reconnect(&mut self) -> Result<(), Box<tungstenite::Error>> {
+        let _ = self.client.close(None);
+        let _ = self.client.write_pending();
+        self.client = Self::open(&self.url, &self.secret)?;
+        self.resync()
+    }
+
+    /// Tells the server what we received, what it missed is replayed when it greets us
+    fn resync(&mut self) -> Result<(), Box<tungstenite::Error>> {
+        let hello = json::object! {
+            c: "hello",
+            session: self.session,
+            ack: self.last_received,
+            ack_session: self.server_session,
+        };
+        self.client.write_message(Message::Text(json::stringify(hello)))?;
+        Ok(())
+    }
+
+    fn send(&mut self, cid: Option<u32>, code: &str, body: JsonValue) -> Result<u32, Abort> {
+        let mid = self.mid_counter;
+        self.mid_counter += 1;
+        if let Some(n) = self.drop_connection_every {
+            if mid % n == n - 1 {
+                self.reconnect().map_err(Abort::Disconnected)?;
+            }
+        }
+        if self.lose_connection_on.as_deref() == Some(code) {
+            self.lose_connection_on = None;
+            let _ = self.client.close(None);
+            let _ = self.client.write_pending();
+            thread::sleep(RECONNECT_DELAY);
+            self.reconnect().map_err(Abort::Disconnected)?;
+        }
+        let mut message = json::object! {
+            mid: mid,
+            c: code,
+        };
+        if let Some(cid) = cid {
+            message["cid"] = cid.into();
+        }
+        // Lua leaves out nil fields, so must we
+        if !body.is_null() {
+            message["b"] = body;
+        }
+        let message = json::stringify(message);
+        self.unacked.push((mid, message.clone()));
+        self.client.write_message(Message::Text(message))?;
+        Ok(mid)
+    }
+
+    fn handle_message(&mut self, message: JsonValue) -> Result<(), Abort> {
+        // Task events outside of a task have nobody pulling them, so they are dropped
+        if message["c"] == "COMMAND" {
+            let cid = message["mid"].as_u32().unwrap_or(0);
+            self.handle_command(&message["b"], cid)?;
+        }
+        Ok(())
+    }
+
+    fn handle_command(&mut self, command: &JsonValue, cid: u32) -> Result<(), Abort> {
+        match command["c"].as_str() {
+            Some("EVAL") => {
+                let response = json::array![false, "The simulator can not evaluate lua"];
+                self.send(Some(cid), "eval_response", response)?;
+            }
+            Some("MOVE") => {
+                let body = match self.run_string(command["b"].as_str().unwrap_or("")) {
+                    Ok(()) => JsonValue::Null,
+                    Err((e, c, None)) => json::object! { e: e, c: c },
+                    Err((e, c, Some(r))) => json::object! { e: e, c: c, r: r },
+                };
+                self.send(Some(cid), "move_response", body)?;
+            }
+            Some("STATE") => {
+                self.reported = self.inventory.clone();
+                let inventory: Vec<JsonValue> = self.inventory.iter().map(|slot| match slot {
+                    Some(i) => json::object! { n: i.name.clone(), c: i.count },
+                    None => JsonValue::new_object(),
+                }).collect();
+                let state = json::object! {
+                    label: "",
+                    position: &self.position,
+                    fuel_level: self.fuel_level,
+                    inventory: inventory,
+                };
+                self.send(Some(cid), "state_update", state)?;
+            }
+            Some("TASK") => {
+                let code = command["b"]["c"].as_str().unwrap_or("").to_owned();
+                self.run_task(cid, code.as_str(), &command["b"]["b"])?;
+            }
+            c => println!("Unknown command {:?}", c),
+        }
+        Ok(())
+    }
+
+    /// Waits for a task event answering message `cid`. Commands that come in meanwhile are
+    /// executed, like the nested executor of `websocket.lua` does.
+    fn pull_event(&mut self, event: &str, cid: u32) -> Result<JsonValue, Abort> {
+        self.awaiting.push((event.to_owned(), cid));
+        let result = self.pull_awaited(event, cid);
+        self.awaiting.pop();
+        result
+    }
+
+    fn pull_awaited(&mut self, event: &str, cid: u32) -> Result<JsonValue, Abort> {
+        loop {
+            if let Some(i) = self.pulled.iter().position(|(e, c, _)| e == event && *c == cid) {
+                return Ok(self.pulled.remove(i).2);
+            }
+            let message = self.receive()?;
+            if message["c"] == "COMMAND" {
+                self.handle_message(message)?;
+                continue;
+            }
+            let message_cid = message["cid"].as_u32();
+            let code = message["b"]["c"].as_str();
+            match (code, message_cid) {
+                (Some("task_cancel"), Some(c)) if self.tasks.contains(&c) => return Err(Abort::Cancelled(c)),
+                (Some(code), Some(c)) if code == event && c == cid => return Ok(message["b"]["b"].clone()),
+                (Some(code), Some(c)) if self.awaiting.iter().any(|(e, a)| e == code && *a == c) => {
+                    self.pulled.push((code.to_owned(), c, message["b"]["b"].clone()));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn run_task(&mut self, cid: u32, code: &str, args: &JsonValue) -> Result<(), Abort> {
+        self.tasks.push(cid);
+        let result = match code {
+            "fell" => self.fell(cid),
+            "first_tree" => self.first_tree(cid),
+            "refuel_logs" => self.refuel_logs(args),
+            "refuel" => self.refuel_items(cid, args),
+            "fell_inter" => self.fell_inter(cid),
+            "inspect" => self.task_inspect(cid, Side::Front).map(|_| ()),
+            "plant" => self.plant(cid, args),
+            "deposit" => self.deposit(cid, args),
+            "inspect_around" => self.inspect_around(cid),
+            "place_torch" => self.place_torch(cid, args),
+            "place_down" => self.place_down(cid, args),
+            "restock" => self.restock(cid, args),
+            "craft" => self.craft_task(cid, args),
+            _ => Err(Abort::Failed(format!("failed to load task {}.lua in tasks", code))),
+        };
+        self.tasks.pop();
+        match result {
+            Ok(()) => {
+                let mid = self.send(Some(cid), "task_finish", JsonValue::Null)?;
+                self.pull_event("task_finish_response", mid)?;
+            }
+            Err(Abort::Failed(e)) => {
+                println!("Task error: {}", e);
+                self.send(Some(cid), "task_cancelled", JsonValue::Null)?;
+            }
+            Err(Abort::Cancelled(c)) if c == cid => {
+                self.send(Some(cid), "task_cancelled", JsonValue::Null)?;
+            }
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+
+    /// Runs a subtask like `subtask_execute`, which swallows errors
+    fn subtask<F>(&mut self, f: F) -> Result<(), Abort>
+        where F: FnOnce(&mut Self) -> Result<(), Abort> {
+        match f(self) {
+            Err(Abort::Failed(e)) => {
+                println!("Task error: {}", e);
+                Ok(())
+            }
+            r => r,
+        }
+    }
+
+    /// A move of the wrapped move api `wt`, which asks the server whether to retry on failure
+    fn wt<F>(&mut self, cid: u32, f: F) -> Result<(), Abort>
+        where F: Fn(&mut Self) -> MoveResult {
+        loop {
+            match f(self) {
+                Ok(()) => return Ok(()),
+                Err((error, _)) => {
+                    let mid = self.send(Some(cid), "task_error", error.into())?;
+                    if !self.pull_event("task_error_response", mid)?.as_bool().unwrap_or(false) {
+                        return Err(Abort::Failed(format!("Aborting task, reason: {}", error)));
+                    }
+                }
+            }
+        }
+    }
+
+    fn question(&mut self, cid: u32, question: &str, payload: JsonValue) -> Result<JsonValue, Abort> {
+        let question = json::object! {
+            q: question,
+            b: payload,
+        };
+        let mid = self.send(Some(cid), "task_question", question)?;
+        self.pull_event("task_answer", mid)
+    }
+
+    /// Port of `task:inspect`
+    fn task_inspect(&mut self, cid: u32, side: Side) -> Result<Option<Block>, Abort> {
+        let block = self.inspect(side);
+        let mut update = json::object! {
+            coordinate: self.target(side),
+        };
+        if let Some(block) = &block {
+            update["name"] = block.name.clone().into();
+        }
+        self.send(Some(cid), "block_update", update)?;
+        Ok(block)
+    }
+
+    fn send_position(&mut self, cid: u32) -> Result<(), Abort> {
+        let position = (&self.position).into();
+        self.send(Some(cid), "position_update", position).map(|_| ())
+    }
+
+    fn send_inventory(&mut self, cid: u32) -> Result<(), Abort> {
+        let delta = self.inventory.iter().zip(self.reported.iter())
+            .map(|(now, before)| match (now, before) {
+                (None, None) => JsonValue::new_object(),
+                (None, Some(_)) => JsonValue::Null,
+                (Some(i), Some(b)) if i.name == b.name && i.count == b.count => JsonValue::new_object(),
+                (Some(i), Some(b)) if i.name == b.name => i.count.into(),
+                (Some(i), _) => json::object! { n: i.name.clone(), c: i.count },
+            })
+            .collect();
+        self.reported = self.inventory.clone();
+        self.send(Some(cid), "inventory_update", JsonValue::Array(delta)).map(|_| ())
+    }
+
+    // --- Tasks, ported from lua-scripts/tasks
+
+    fn fell(&mut self, cid: u32) -> Result<(), Abort> {
+        self.wt(cid, |t| t.mf(1))?;
+        self.send_position(cid)?;
+        self.subtask(|t| t.fell_inter(cid))?;
+        self.send_inventory(cid)
+    }
+
+    fn fell_inter(&mut self, cid: u32) -> Result<(), Abort> {
+        while self.task_inspect(cid, Side::Up)?.is_some_and(|b| b.is_log()) {
+            self.wt(cid, |t| t.mu(1))?;
+        }
+        self.wt(cid, |t| t.mu(1))?;
+        self.send_position(cid)?;
+
+        for _ in 0..3 {
+            self.dig(Side::Front);
+            self.wt(cid, |t| t.l(1))?;
+        }
+        self.dig(Side::Front);
+
+        self.wt(cid, |t| t.md(2))?;
+        self.send_position(cid)?;
+
+        self.spiral(cid, 5, |t| {
+            t.dig(Side::Up);
+            t.dig(Side::Down);
+            t.send_position(cid)
+        })?;
+
+        self.send_inventory(cid)?;
+        let position = json::object! { position: &self.position };
+        let answer = self.question(cid, "replant", position)?;
+        if !answer.is_array() {
+            return Err(Abort::Failed(format!("bad argument to unpack, got {}", answer)));
+        }
+        let replant = answer[0].as_bool().unwrap_or(!answer[0].is_null());
+        let wait = answer[1].as_bool().unwrap_or(!answer[1].is_null());
+        if replant {
+            self.wt(cid, |t| t.r(1))?;
+            self.wt(cid, |t| t.mf(2))?;
+            self.wt(cid, |t| t.r(1))?;
+            self.wt(cid, |t| t.mf(2))?;
+            while !self.detect(Side::Down) {
+                self.wt(cid, |t| t.d(1))?;
+            }
+            self.wt(cid, |t| t.mb(1))?;
+            let sapling_slot = answer[2].as_usize()
+                .ok_or_else(|| Abort::Failed(format!("bad argument to select, got {}", answer[2])))?;
+            self.select(sapling_slot);
+            self.place(Side::Front);
+            self.select(1);
+            if wait {
+                loop {
+                    match self.inspect(Side::Front) {
+                        Some(block) if block.is_log() => break,
+                        Some(_) => {}
+                        None => {
+                            let mid = self.send(Some(cid), "task_error", "No block to inspect".into())?;
+                            if !self.pull_event("task_error_response", mid)?.as_bool().unwrap_or(false) {
+                                return Err(Abort::Failed("Aborting task, reason: No block to inspect".to_owned()));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn first_tree(&mut self, cid: u32) -> Result<(), Abort> {
+        self.dig(Side::Front);
+        self.send_position(cid)?;
+        self.select(1);
+        self.craft();
+        self.refuel(STACK_SIZE);
+
+        self.wt(cid, |t| t.f(1))?;
+        self.wt(cid, |t| t.mu(2))?;
+        self.craft();
+        for slot in [2, 3, 5, 7, 9, 10, 11].iter() {
+            self.transfer_to(*slot, 1);
+        }
+        self.select(16);
+        self.craft();
+
+        self.subtask(|t| t.fell_inter(cid))
+    }
+
+    fn refuel_logs(&mut self, args: &JsonValue) -> Result<(), Abort> {
+        let slot = args["slot"].as_usize().unwrap_or(0);
+        let count = args["count"].as_u8().unwrap_or(0);
+        self.select(16);
+        self.dig(Side::Front);
+        self.place(Side::Front);
+        for i in 1..=15 {
+            if i != slot && self.item_count_in(i) > 0 {
+                self.select(i);
+                self.drop(Side::Front, STACK_SIZE);
+            }
+        }
+        self.select(slot);
+        self.drop(Side::Front, self.item_count_in(slot).saturating_sub(count));
+        self.craft();
+        for i in 1..=15 {
+            if self.item_count_in(i) > 0 {
+                self.select(i);
+                self.refuel(STACK_SIZE);
+            } else {
+                break;
+            }
+        }
+        while self.suck(Side::Front, STACK_SIZE) {}
+        Ok(())
+    }
+
+    fn refuel_items(&mut self, cid: u32, args: &JsonValue) -> Result<(), Abort> {
+        for fuel in args.members() {
+            self.select(fuel["slot"].as_usize().unwrap_or(0));
+            self.refuel(fuel["count"].as_u8().unwrap_or(0));
+        }
+        self.select(1);
+        self.send_inventory(cid)
+    }
+
+    fn plant(&mut self, cid: u32, args: &JsonValue) -> Result<(), Abort> {
+        while self.suck(Side::Front, STACK_SIZE) {}
+        self.select(args["slot"].as_usize().unwrap_or(0));
+        self.place(Side::Front);
+        self.select(1);
+        self.task_inspect(cid, Side::Front)?;
+        self.send_inventory(cid)
+    }
+
+    fn deposit(&mut self, cid: u32, args: &JsonValue) -> Result<(), Abort> {
+        for item in args.members() {
+            self.select(item["slot"].as_usize().unwrap_or(0));
+            self.drop(Side::Front, item["count"].as_u8().unwrap_or(0));
+        }
+        self.select(1);
+        self.send_inventory(cid)
+    }
+
+    fn inspect_around(&mut self, cid: u32) -> Result<(), Abort> {
+        self.task_inspect(cid, Side::Up)?;
+        self.task_inspect(cid, Side::Down)?;
+        self.task_inspect(cid, Side::Front)?;
+        self.wt(cid, |t| t.l(1))?;
+        self.task_inspect(cid, Side::Front)?;
+        self.wt(cid, |t| t.r(2))?;
+        self.task_inspect(cid, Side::Front)?;
+        self.wt(cid, |t| t.l(1))
+    }
+
+    fn place_torch(&mut self, cid: u32, args: &JsonValue) -> Result<(), Abort> {
+        self.wt(cid, |t| t.l(1))?;
+        self.dig(Side::Front);
+        self.select(args["slot"].as_usize().unwrap_or(0));
+        self.place(Side::Front);
+        self.select(1);
+        self.task_inspect(cid, Side::Front)?;
+        self.wt(cid, |t| t.r(1))?;
+        self.send_inventory(cid)
+    }
+
+    fn place_down(&mut self, cid: u32, args: &JsonValue) -> Result<(), Abort> {
+        self.select(args["slot"].as_usize().unwrap_or(0));
+        self.place(Side::Down);
+        self.select(1);
+        self.task_inspect(cid, Side::Down)?;
+        self.send_inventory(cid)
+    }
+
+    /// Port of `restock.lua`, the stack it takes moves to the front of the chest like there
+    fn restock(&mut self, cid: u32, args: &JsonValue) -> Result<(), Abort> {
+        let (name, count) = (args["item"].as_str().unwrap_or(""), args["count"].as_u32().unwrap_or(0));
+        let target = self.target(Side::Front);
+        loop {
+            let have = self.inventory.iter().flatten().filter(|i| i.name == name).map(|i| i.count as u32).sum::<u32>();
+            if have >= count {
+                break;
+            }
+            let contents = match self.world.blocks.get_mut(&target) {
+                Some(block) if block.name == "minecraft:chest" => &mut block.contents,
+                _ => return Err(Abort::Failed("there is no inventory in front to restock from".to_owned())),
+            };
+            match contents.iter().position(|i| i.name == name) {
+                Some(index) => {
+                    let stack = contents.remove(index);
+                    contents.insert(0, stack);
+                }
+                None => break,
+            }
+            match self.inventory.iter().position(Option::is_none) {
+                Some(empty) => self.select(empty + 1),
+                None => break,
+            }
+            if !self.suck(Side::Front, (count - have).min(STACK_SIZE as u32) as u8) {
+                break;
+            }
+        }
+        self.select(1);
+        self.send_inventory(cid)
+    }
+
+    fn craft_task(&mut self, cid: u32, args: &JsonValue) -> Result<(), Abort> {
+        if !args["stash"].is_empty() {
+            match self.world.block(self.target(Side::Front)) {
+                Some(block) if block.name == "minecraft:chest" && block.contents.is_empty() => {}
+                Some(block) if block.name == "minecraft:chest" => {
+                    return Err(Abort::Failed("the inventory in front is not empty, stashed items could not be told apart".to_owned()));
+                }
+                _ => return Err(Abort::Failed("there is no inventory in front to stash items in".to_owned())),
+            }
+        }
+        let total = |t: &Self| t.inventory.iter().flatten().map(|i| i.count as u32).sum::<u32>();
+        let mut stashed = 0;
+        let mut failure = None;
+        for item in args["stash"].members() {
+            let (slot, count) = (item["slot"].as_usize().unwrap_or(0), item["count"].as_u8().unwrap_or(0));
+            self.select(slot);
+            let before = self.item_count_in(slot);
+            self.drop(Side::Front, count);
+            let dropped = before - self.item_count_in(slot);
+            stashed += dropped as u32;
+            if dropped != count {
+                failure = Some(format!("could only stash {} of {} items from slot {}", dropped, count, slot));
+                break;
+            }
+        }
+        if failure.is_none() {
+            for m in args["moves"].members() {
+                let (from, to, count) = (m["from"].as_usize().unwrap_or(0), m["to"].as_usize().unwrap_or(0), m["count"].as_u8().unwrap_or(0));
+                self.select(from);
+                if !self.transfer_to(to, count) {
+                    failure = Some(format!("could not move {} items from slot {} to {}", count, from, to));
+                    break;
+                }
+            }
+        }
+        if failure.is_none() {
+            self.select(args["slot"].as_usize().unwrap_or(0));
+            if !self.craft() {
+                failure = Some("could not craft: No matching recipes".to_owned());
+            }
+        }
+        while stashed > 0 {
+            let before = total(self);
+            if !self.suck(Side::Front, stashed.min(STACK_SIZE as u32) as u8) {
+                break;
+            }
+            stashed -= total(self) - before;
+        }
+        if stashed > 0 && failure.is_none() {
+            failure = Some(format!("could not take back {} stashed items", stashed));
+        }
+        self.select(1);
+        self.send_inventory(cid)?;
+        match failure {
+            Some(e) => Err(Abort::Failed(e)),
+            None => Ok(()),
+        }
+    }
+
+    /// Port of `util:spiral` with the wrapped move api, always mining
+    fn spiral<A>(&mut self, cid: u32, d: usize, action: A) -> Result<(), Abort>
+        where A: Fn(&mut Self) -> Result<(), Abort> {
+        for i in 1..=d {
+            for _ in 0..2 {
+                for j in 1..=i {
+                    action(self)?;
+                    self.wt(cid, |t| t.mf(1))?;
+                    if i == d && j == d - 1 {
+                        return action(self);
+                    }
+                }
+                self.wt(cid, |t| t.r(1))?;
+            }
+        }
+        Ok(())
+    }
+
+    // --- Move api, ported from move.lua
+
+    fn f(&mut self, n: usize) -> MoveResult {
+        self.repeat_move(n, Self::forward)
+    }
+
+    fn b(&mut self, n: usize) -> MoveResult {
+        self.repeat_move(n, Self::back)
+    }
+
+    fn u(&mut self, n: usize) -> MoveResult {
+        self.repeat_move(n, Self::up)
+    }
+
+    fn d(&mut self, n: usize) -> MoveResult {
+        self.repeat_move(n, Self::down)
+    }
+
+    fn l(&mut self, n: usize) -> MoveResult {
+        self.position.turn(-((n % 4) as i8));
+        Ok(())
+    }
+
+    fn r(&mut self, n: usize) -> MoveResult {
+        self.position.turn((n % 4) as i8);
+        Ok(())
+    }
+
+    fn mf(&mut self, n: usize) -> MoveResult {
+        self.repeat_mine(n, Side::Front, Self::forward)
+    }
+
+    fn mu(&mut self, n: usize) -> MoveResult {
+        self.repeat_mine(n, Side::Up, Self::up)
+    }
+
+    fn md(&mut self, n: usize) -> MoveResult {
+        self.repeat_mine(n, Side::Down, Self::down)
+    }
+
+    fn mb(&mut self, n: usize) -> MoveResult {
+        for i in 1..=n {
+            match self.b(1) {
+                Ok(()) => {}
+                Err(("obstacle", _)) => {
+                    self.l(2)?;
+                    let result = self.mf(n - (i - 1));
+                    self.r(2)?;
+                    return result;
+                }
+                Err((e, _)) => return Err((e, i - 1)),
+            }
+        }
+        Ok(())
+    }
+
+    fn repeat_move(&mut self, n: usize, step: fn(&mut Self) -> bool) -> MoveResult {
+        if self.fuel_level < n as i64 {
+            return Err(("fuel", 0));
+        }
+        for i in 1..=n {
+            if !step(self) {
+                return Err(("obstacle", i - 1));
+            }
+        }
+        Ok(())
+    }
+
+    fn repeat_mine(&mut self, n: usize, side: Side, step: fn(&mut Self) -> bool) -> MoveResult {
+        if self.fuel_level < n as i64 {
+            return Err(("fuel", 0));
+        }
+        for i in 1..=n {
+            self.dig(side);
+            self.repeat_move(1, step).map_err(|(e, _)| (e, i - 1))?;
+        }
+        Ok(())
+    }
+
+    /// Port of `t.runString`, returns the error message, the index of the failed move and why it
+    /// failed, if it was a move at all
+    pub fn run_string(&mut self, s: &str) -> Result<(), (String, usize, Option<&'static str>)> {
+        let chars: Vec<char> = s.chars().collect();
+        let mut i = 0;
+        while i < chars.len() {
+            let (step, next) = ManeuverStep::parse_at(&chars, i).map_err(|e| (e.message, e.index, None))?;
+            let count = step.count as usize;
+            let result = match (step.move_type, step.mine) {
+                (Move::Forward, false) => self.f(count),
+                (Move::Backward, false) => self.b(count),
+                (Move::Up, false) => self.u(count),
+                (Move::Down, false) => self.d(count),
+                (Move::Left, _) => self.l(count),
+                (Move::Right, _) => self.r(count),
+                (Move::Forward, true) => self.mf(count),
+                (Move::Up, true) => self.mu(count),
+                (Move::Down, true) => self.md(count),
+                (Move::Backward, true) => self.mb(count),
+            };
+            if let Err((e, _)) = result {
+                let code = format!("{}{}", if step.mine { "m" } else { "" }, step.move_type.code());
+                return Err((format!("Could not complete move {}{} due to \"{}\"", code, count, e), i + 1, Some(e)));
+            }
+            i = next;
+        }
+        Ok(())
+    }
+
+    // --- Turtle api
+
+    fn target(&self, side: Side) -> Coordinate {
+        match side {
+            Side::Front => self.position.ahead(1),
+            Side::Up => self.position.coordinate().delta(0, 1, 0),
+            Side::Down => self.position.coordinate().delta(0, -1, 0),
+        }
+    }
+
+    fn step_to(&mut self, target: Coordinate) -> bool {
+        if self.fuel_level <= 0 || self.world.block(target).is_some() {
+            return false;
+        }
+        self.position = Position::new(target, self.position.direction());
+        self.fuel_level -= 1;
+        true
+    }
+
+    fn forward(&mut self) -> bool {
+        self.step_to(self.position.ahead(1))
+    }
+
+    fn back(&mut self) -> bool {
+        self.step_to(self.position.ahead(-1))
+    }
+
+    fn up(&mut self) -> bool {
+        self.step_to(self.target(Side::Up))
+    }
+
+    fn down(&mut self) -> bool {
+        self.step_to(self.target(Side::Down))
+    }
+
+    fn detect(&self, side: Side) -> bool {
+        self.world.block(self.target(side)).is_some()
+    }
+
+    fn inspect(&self, side: Side) -> Option<Block> {
+        self.world.block(self.target(side)).cloned()
+    }
+
+    fn dig(&mut self, side: Side) -> bool {
+        match self.world.break_block(self.target(side)) {
+            Some(items) => {
+                for item in items {
+                    if let Some(rest) = self.insert(item) {
+                        self.world.dropped.entry(self.position.coordinate()).or_default().push(rest);
+                    }
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn place(&mut self, side: Side) -> bool {
+        let target = self.target(side);
+        if self.world.block(target).is_some() {
+            return false;
+        }
+        let name = match self.take_selected(1) {
+            Some(item) => item.name,
+            None => return false,
+        };
+        if name.ends_with("_sapling") {
+            // Saplings grow into a tree on the spot, so tasks never wait for growth
+            let turtle = self.position.coordinate();
+            self.world.grow_tree(target, Some(turtle));
+        } else {
+            self.world.set_block(target, Some(Block::new(name.as_str())));
+        }
+        true
+    }
+
+    fn drop(&mut self, side: Side, count: u8) -> bool {
+        let item = match self.take_selected(count) {
+            Some(item) => item,
+            None => return false,
+        };
+        let target = self.target(side);
+        match self.world.blocks.get_mut(&target) {
+            Some(block) if block.name == "minecraft:chest" => block.contents.push(item),
+            _ => self.world.dropped.entry(target).or_default().push(item),
+        }
+        true
+    }
+
+    /// Takes up to `count` of the first stack in a chest, or of the last item dropped there
+    fn suck(&mut self, side: Side, count: u8) -> bool {
+        let target = self.target(side);
+        let take = |items: &mut Vec<Item>, index: usize| {
+            let stack = items.get_mut(index)?;
+            if stack.count > count {
+                stack.count -= count;
+                Some(Item { count, name: stack.name.clone() })
+            } else {
+                Some(items.remove(index))
+            }
+        };
+        let item = match self.world.blocks.get_mut(&target) {
+            Some(block) if block.name == "minecraft:chest" => take(&mut block.contents, 0),
+            Some(_) => None,
+            None => self.world.dropped.get_mut(&target).and_then(|items| {
+                let last = items.len().checked_sub(1)?;
+                take(items, last)
+            }),
+        };
+        match item {
+            Some(item) => {
+                let count = item.count;
+                match self.insert(item) {
+                    Some(rest) => {
+                        // No room, put the rest back where it came from
+                        let moved = rest.count < count;
+                        match self.world.blocks.get_mut(&target) {
+                            Some(block) => block.contents.insert(0, rest),
+                            None => self.world.dropped.entry(target).or_default().push(rest),
+                        }
+                        moved
+                    }
+                    None => true,
+                }
+            }
+            None => false,
+        }
+    }
+
+    fn refuel(&mut self, count: u8) -> bool {
+        let value = match &self.inventory[self.selected] {
+            Some(item) => fuel_value(item.name.as_str()),
+            None => return false,
+        };
+        if value == 0 {
+            return false;
+        }
+        let burned = self.take_selected(count).unwrap();
+        self.fuel_level += value * burned.count as i64;
+        if burned.name == "minecraft:lava_bucket" {
+            self.inventory[self.selected] = Some(Item { count: 1, name: "minecraft:bucket".to_owned() });
+        }
+        true
+    }
+
+    /// Slots are 1-based, like in lua
+    fn select(&mut self, slot: usize) {
+        if (1..=16).contains(&slot) {
+            self.selected = slot - 1;
+        }
+    }
+
+    fn item_count_in(&self, slot: usize) -> u8 {
+        self.inventory[slot - 1].as_ref().map_or(0, |i| i.count)
+    }
+
+    fn transfer_to(&mut self, slot: usize, count: u8) -> bool {
+        let to = slot - 1;
+        let name = match (&self.inventory[self.selected], &self.inventory[to]) {
+            (Some(from), None) => from.name.clone(),
+            (Some(from), Some(i)) if from.name == i.name => from.name.clone(),
+            _ => return false,
+        };
+        let room = STACK_SIZE - self.inventory[to].as_ref().map_or(0, |i| i.count);
+        let moved = match self.take_selected(count.min(room)) {
+            Some(item) => item.count,
+            None => return false,
+        };
+        let slot = self.inventory[to].get_or_insert(Item { count: 0, name });
+        slot.count += moved;
+        true
+    }
+
+    fn take_selected(&mut self, count: u8) -> Option<Item> {
+        let slot = &mut self.inventory[self.selected];
+        let item = slot.as_mut()?;
+        let taken = count.min(item.count);
+        if taken == 0 {
+            return None;
+        }
+        item.count -= taken;
+        let name = item.name.clone();
+        if item.count == 0 {
+            *slot = None;
+        }
+        Some(Item { count: taken, name })
+    }
+
+    /// Puts an item in the inventory, starting at the selected slot. Returns what did not fit.
+    fn insert(&mut self, mut item: Item) -> Option<Item> {
+        let order: Vec<usize> = (0..16).map(|i| (self.selected + i) % 16).collect();
+        for &i in order.iter() {
+            if let Some(stack) = &mut self.inventory[i] {
+                if stack.name == item.name {
+                    let moved = item.count.min(STACK_SIZE - stack.count);
+                    stack.count += moved;
+                    item.count -= moved;
+                }
+            }
+            if item.count == 0 {
+                return None;
+            }
+        }
+        for &i in order.iter() {
+            if self.inventory[i].is_none() {
+                self.inventory[i] = Some(item);
+                return None;
+            }
+        }
+        Some(item)
+    }
+
+    /// Crafts as much as possible with the items laid out in the 4x4 inventory, putting the
+    /// result in the selected slot. Knows planks from logs and chests from planks.
+    fn craft(&mut self) -> bool {
+        let used: Vec<usize> = (0..16).filter(|&i| self.inventory[i].is_some()).collect();
+        if used.is_empty() {
+            return false;
+        }
+        let (rows, cols): (Vec<usize>, Vec<usize>) = used.iter().map(|i| (i / 4, i % 4)).unzip();
+        let (top, left) = (*rows.iter().min().unwrap(), *cols.iter().min().unwrap());
+        let (bottom, right) = (*rows.iter().max().unwrap(), *cols.iter().max().unwrap());
+        if bottom - top > 2 || right - left > 2 {
+            return false;
+        }
+
+        let name = |i: usize| self.inventory[i].as_ref().map(|i| i.name.as_str());
+        let (output, per_craft) = if used.len() == 1 && name(used[0]).is_some_and(|n| n.ends_with("_log")) {
+            (name(used[0]).unwrap().replace("_log", "_planks"), 4)
+        } else {
+            let ring = [0, 1, 2, 4, 6, 8, 9, 10];
+            let is_chest = bottom - top == 2 && right - left == 2
+                && used.len() == 8
+                && ring.iter().all(|o| name(top * 4 + left + o).is_some_and(|n| n.ends_with("_planks")));
+            if !is_chest {
+                return false;
+            }
+            ("minecraft:chest".to_owned(), 1)
+        };
+
+        let crafts = used.iter()
+            .map(|&i| self.inventory[i].as_ref().unwrap().count)
+            .min().unwrap()
+            .min(STACK_SIZE / per_craft);
+        for &i in used.iter() {
+            let item = self.inventory[i].as_mut().unwrap();
+            item.count -= crafts;
+            if item.count == 0 {
+                self.inventory[i] = None;
+            }
+        }
+        if let Some(rest) = self.insert(Item { count: crafts * per_craft, name: output }) {
+            self.world.dropped.entry(self.position.coordinate()).or_default().push(rest);
+        }
+        true
+    }
+}
+
+/// Helpers for tests that run the server against a `SimTurtle`. The turtle runs the Rust ports of
+/// the Lua tasks, so these tests do not cover what the Lua scripts themselves do.
+#[cfg(test)]
+pub mod testing {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::{Arc, mpsc, Mutex};
+
+    use crate::executor::TaskExecutor;
+    use crate::fleet::Fleet;
+    use crate::maneuver::MoveError;
+    use crate::turtle::TurtleState;
+    use crate::turtle_ids::{OperatorToken, TurtleIds};
+    use crate::turtle_websocket::{self, Command, ReceiveError, Timeouts, TurtleConnection, UpEvent};
+
+    use super::*;
+
+    pub const OPERATOR: &str = "operator";
+
+    pub fn start(world: World, fuel_level: i64, drop_connection_every: Option<u32>) -> (TaskExecutor, JoinHandle<SimTurtle>) {
+        start_with(world, fuel_level, |t| t.drop_connection_every = drop_connection_every)
+    }
+
+    pub fn start_with<F>(world: World, fuel_level: i64, setup: F) -> (TaskExecutor, JoinHandle<SimTurtle>)
+        where F: FnOnce(&mut SimTurtle) {
+        let (executor, handle, _) = start_listening(world, fuel_level, setup);
+        (executor, handle)
+    }
+
+    /// Like `start_with`, but also gives the address of the listener for http requests
+    pub fn start_listening<F>(world: World, fuel_level: i64, setup: F) -> (TaskExecutor, JoinHandle<SimTurtle>, SocketAddr)
+        where F: FnOnce(&mut SimTurtle) {
+        let (address, fleet, connections, handle) = listen(world, fuel_level, setup);
+        let connection = connections.recv().unwrap();
+        (TaskExecutor::new(TurtleState::default(), connection, fleet), handle, address)
+    }
+
+    /// Starts a listener with a simulated turtle, the connections it accepts are for the test
+    pub fn listen<F>(world: World, fuel_level: i64, setup: F) -> (SocketAddr, Fleet, mpsc::Receiver<TurtleConnection>, JoinHandle<SimTurtle>)
+        where F: FnOnce(&mut SimTurtle) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let url = format!("ws://{}/ws/1", address);
+        let fleet = Fleet::default();
+        let mut ids = TurtleIds::default();
+        let secret = ids.secret(1).unwrap();
+        let (connections, _) = turtle_websocket::spawn_listener(listener, fleet.clone(), Arc::new(Mutex::new(ids)), OperatorToken::new(OPERATOR.to_owned()), Timeouts::default()).unwrap();
+        let mut turtle = SimTurtle::connect(url, secret, world, fuel_level).unwrap();
+        setup(&mut turtle);
+        let handle = thread::spawn(move || {
+            let _ = turtle.run();
+            turtle
+        });
+        (address, fleet, connections, handle)
+    }
+
+    /// Sends a request with a json body as the operator and returns the status and json body of the response
+    pub fn http(address: SocketAddr, method: &str, path: &str, body: JsonValue) -> (u16, JsonValue) {
+        http_with(address, Some(OPERATOR), method, path, body)
+    }
+
+    pub fn http_with(address: SocketAddr, token: Option<&str>, method: &str, path: &str, body: JsonValue) -> (u16, JsonValue) {
+        let body = if body.is_null() { String::new() } else { json::stringify(body) };
+        let authorization = token.map_or(String::new(), |t| format!("Authorization: Bearer {}\r\n", t));
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "{} {} HTTP/1.1\r\nHost: {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+               method, path, address, authorization, body.len(), body).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response.split(' ').nth(1).and_then(|s| s.parse().ok()).unwrap();
+        let body = response.split("\r\n\r\n").nth(1).unwrap_or("");
+        (status, json::parse(body).unwrap_or(JsonValue::Null))
+    }
+
+    pub fn run_move(executor: &mut TaskExecutor, s: &str) -> Result<(), (MoveError, usize)> {
+        let mid = executor.connection.send_request(Command::Move(s.to_owned()), Duration::from_secs(10));
+        loop {
+            match executor.connection.receive_reply(mid) {
+                Ok(UpEvent::MoveResponse(r)) => return r,
+                Ok(e) => panic!("Expected move response, got {:?}", e),
+                Err(ReceiveError::Timeout) => {}
+                Err(e) => panic!("No move response: {:?}", e),
+            }
+        }
+    }
+
+    pub fn finish(executor: TaskExecutor, handle: JoinHandle<SimTurtle>) -> SimTurtle {
+        drop(executor);
+        handle.join().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    use crate::executor::TaskExecutor;
+    use crate::fleet::Fleet;
+    use crate::fuel::Refuel;
+    use crate::maneuver::{MoveError, MoveFailure};
+    use crate::task_registry::{ReplantAnswer, Task};
+    use crate::turtle::{Coordinate, Direction, Item, TurtleState};
+    use crate::turtle_ids::{OperatorToken, TurtleIds};
+    use crate::turtle_websocket::{self, Command, ReceiveError, TaskQuestion, Timeouts, UpEvent};
+
+    use super::*;
+    use super::testing::{finish, listen, run_move, start, start_with, OPERATOR};
+
+    #[test]
+    fn moves_stop_at_obstacles_and_low_fuel() {
+        let mut world = World::new();
+        world.set_block(Coordinate::new(0, 0, -3), Some(Block::new("minecraft:stone")));
+        let (mut executor, handle) = start(world, 10, None);
+
+        let err = run_move(&mut executor, "rlf3").unwrap_err();
+        assert_eq!(err, (MoveError::new(MoveFailure::Obstacle, "Could not complete move f3 due to \"obstacle\"".to_owned()), 3));
+        assert_eq!(run_move(&mut executor, "mf2r2"), Ok(()));
+        let err = run_move(&mut executor, "u2f10").unwrap_err();
+        assert_eq!(err, (MoveError::new(MoveFailure::Fuel, "Could not complete move f10 due to \"fuel\"".to_owned()), 3));
+
+        let turtle = finish(executor, handle);
+        assert_eq!(turtle.position.coordinate(), Coordinate::new(0, 2, -4));
+        assert_eq!(turtle.position.direction(), Direction::South);
+        assert_eq!(turtle.item_count("minecraft:cobblestone"), 1);
+        assert_eq!(turtle.fuel_level, 4);
+    }
+
+    #[test]
+    fn mine_back_digs_when_blocked() {
+        let mut world = World::new();
+        world.set_block(Coordinate::new(0, 0, 1), Some(Block::new("minecraft:dirt")));
+        let (mut executor, handle) = start(world, 10, None);
+
+        assert_eq!(run_move(&mut executor, "mb2"), Ok(()));
+
+        let turtle = finish(executor, handle);
+        assert_eq!(turtle.position.coordinate(), Coordinate::new(0, 0, 2));
+        assert_eq!(turtle.position.direction(), Direction::North);
+        assert_eq!(turtle.item_count("minecraft:dirt"), 1);
+    }
+
+    #[test]
+    fn moves_survive_reconnects() {
+        let (mut executor, handle) = start(World::new(), 100, Some(2));
+
+        for _ in 0..5 {
+            assert_eq!(run_move(&mut executor, "f1"), Ok(()));
+        }
+
+        let turtle = finish(executor, handle);
+        assert_eq!(turtle.position.coordinate(), Coordinate::new(0, 0, -5));
+    }
+
+    #[test]
+    fn tasks_lost_to_a_reboot_are_not_resent() {
+        let (mut executor, handle) = start_with(World::new(), 10, |t| {
+            t.inventory[0] = Some(Item { count: 2, name: "minecraft:coal".to_owned() });
+            t.reboot_on_task = Some("refuel".to_owned());
+        });
+        let refuel = || Task::refuel(&[Refuel { slot: 0, count: 1 }]);
+
+        // The turtle reboots with the task unacknowledged, the new session must not run it
+        assert!(!executor.execute(refuel(), TaskExecutor::default_event_handler, TaskExecutor::null_question_handler).unwrap());
+        let interrupted = executor.take_interrupted();
+        assert_eq!(interrupted.len(), 1);
+        assert_eq!(interrupted[0].task.code(), "refuel");
+
+        // Starting it again runs it once
+        assert!(executor.execute(refuel(), TaskExecutor::default_event_handler, TaskExecutor::null_question_handler).unwrap());
+        let turtle = finish(executor, handle);
+        assert_eq!(turtle.item_count("minecraft:coal"), 1);
+        assert_eq!(turtle.fuel_level, 10 + 80);
+    }
+
+    #[test]
+    fn tasks_of_a_server_that_restarted_do_not_end_new_ones() {
+        let (_, fleet, connections, handle) = listen(World::new(), 10, |t| {
+            t.inventory[0] = Some(Item { count: 3, name: "minecraft:coal".to_owned() });
+            t.lose_connection_on = Some("inventory_update".to_owned());
+        });
+        let refuel = || Task::refuel(&[Refuel { slot: 0, count: 1 }]);
+
+        // The server stops while the turtle runs its task and is away
+        let mut executor = TaskExecutor::new(TurtleState::default(), connections.recv().unwrap(), fleet.clone());
+        executor.connection.send_command(Command::Task(refuel()));
+        thread::sleep(RECONNECT_DELAY / 2);
+        drop(executor);
+
+        // The next server hears the end of that task first, which must not end its own
+        let connection = connections.recv_timeout(Duration::from_secs(5)).unwrap();
+        let mut executor = TaskExecutor::new(TurtleState::default(), connection, fleet);
+        assert!(executor.execute(refuel(), TaskExecutor::default_event_handler, TaskExecutor::null_question_handler).unwrap());
+        assert_eq!(executor.turtle.inventory.find(|i| i.name == "minecraft:coal").map(|(i, _)| i.count), Some(1));
+
+        let turtle = finish(executor, handle);
+        assert_eq!(turtle.item_count("minecraft:coal"), 1);
+        assert_eq!(turtle.fuel_level, 10 + 2 * 80);
+    }
+
+    #[test]
+    fn connections_need_the_secret_of_their_id() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut ids = TurtleIds::default();
+        let (id, secret) = ids.new_id().unwrap();
+        let _ = turtle_websocket::spawn_listener(listener, Fleet::default(), Arc::new(Mutex::new(ids)), OperatorToken::new(OPERATOR.to_owned()), Timeouts::default()).unwrap();
+
+        let url = |id| format!("ws://{}/ws/{}", address, id);
+        assert!(SimTurtle::connect(url(id), "wrong".to_owned(), World::new(), 0).is_err());
+        assert!(SimTurtle::connect(url(id + 1), secret.clone(), World::new(), 0).is_err());
+        assert!(SimTurtle::connect(url(id), secret, World::new(), 0).is_ok());
+    }
+
+    #[test]
+    fn tasks_survive_reconnects() {
+        // Drops the connection right before some of the task events, including the finish
+        let (mut executor, handle) = start(World::lumberjack(), 100, Some(3));
+
+        let skip_replant = |_: &TaskQuestion, _: &mut TaskExecutor| Some(ReplantAnswer::skip());
+        let success = executor.execute(Task::fell(), TaskExecutor::default_event_handler, skip_replant).unwrap();
+        assert!(success);
+
+        let turtle = finish(executor, handle);
+        assert_eq!(turtle.item_count("minecraft:oak_log"), TREE_HEIGHT as u32);
+    }
+
+    #[test]
+    fn replies_are_matched_to_their_commands() {
+        let (mut executor, handle) = start(World::new(), 100, None);
+
+        let move_mid = executor.connection.send_request(Command::Move("f2".to_owned()), Duration::from_secs(10));
+        let state_mid = executor.connection.send_request(Command::State, Duration::from_secs(10));
+        let state = loop {
+            match executor.connection.receive_reply(state_mid) {
+                Ok(UpEvent::StateUpdate(state)) => break state,
+                Err(ReceiveError::Timeout) => {}
+                r => panic!("Expected state update, got {:?}", r),
+            }
+        };
+        assert_eq!(state.position.coordinate(), Coordinate::new(0, 0, -2));
+        // Already arrived, but kept for its own command
+        assert!(matches!(executor.connection.receive_reply(move_mid), Ok(UpEvent::MoveResponse(Ok(())))));
+        assert!(matches!(executor.connection.receive_reply(move_mid), Err(ReceiveError::CommandTimeout(_))));
+
+        finish(executor, handle);
+    }
+}
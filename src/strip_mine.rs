@@ -0,0 +1,261 @@
+use std::collections::{BTreeMap, HashSet};
+use std::env;
+use std::error::Error;
+
+use json::JsonValue;
+
+use crate::executor::TaskExecutor;
+use crate::maneuver::{Maneuver, ManeuverStep, Move};
+use crate::task_registry::Task;
+use crate::turtle::{Coordinate, Position};
+use crate::world_map::KnownBlock;
+
+/// Gives up on a vein after this many blocks, in case the world map is wrong about it
+const MAX_VEIN: usize = 64;
+
+fn is_ore(block: Option<&KnownBlock>) -> bool {
+    block.and_then(KnownBlock::name)
+        .is_some_and(|name| name.ends_with("_ore") || name == "minecraft:ancient_debris")
+}
+
+fn neighbours(coordinate: Coordinate) -> [Coordinate; 6] {
+    [
+        coordinate.delta(1, 0, 0),
+        coordinate.delta(-1, 0, 0),
+        coordinate.delta(0, 1, 0),
+        coordinate.delta(0, -1, 0),
+        coordinate.delta(0, 0, 1),
+        coordinate.delta(0, 0, -1),
+    ]
+}
+
+/// A main tunnel straight ahead of `start`, with branches to both sides after every `spacing`
+/// blocks of tunnel. After every step the turtle looks around and mines out any ore vein it
+/// sees, so veins get dug up completely before it moves on. Torches go into the wall every
+/// `torch_spacing` blocks of main tunnel, if the turtle has any. When that block is where
+/// branches leave the tunnel, the torch goes into the wall of the next block instead.
+/// Configured with `STRIP_LENGTH` (default 32), `STRIP_SPACING` (default 3),
+/// `STRIP_BRANCH_LENGTH` (default 8) and `STRIP_TORCH_SPACING` (default 8, 0 for no torches).
+pub struct StripMine {
+    pub start: Position,
+    pub length: i64,
+    pub spacing: i64,
+    pub branch_length: i64,
+    pub torch_spacing: i64,
+    /// Ore blocks mined in this run, by name
+    pub yields: BTreeMap<String, u32>,
+}
+
+impl StripMine {
+    pub fn new(start: Position, length: i64, spacing: i64, branch_length: i64) -> Self {
+        Self { start, length, spacing, branch_length, torch_spacing: 0, yields: BTreeMap::new() }
+    }
+
+    pub fn from_env(start: Position) -> Self {
+        let var = |name: &str, default: i64| env::var(name).ok().and_then(|s| s.parse().ok()).unwrap_or(default);
+        Self {
+            torch_spacing: var("STRIP_TORCH_SPACING", 8),
+            ..Self::new(start, var("STRIP_LENGTH", 32), var("STRIP_SPACING", 3), var("STRIP_BRANCH_LENGTH", 8))
+        }
+    }
+
+    fn yields_json(yields: &BTreeMap<String, u32>) -> JsonValue {
+        let mut jv = JsonValue::new_object();
+        for (name, count) in yields {
+            jv[name.as_str()] = (*count).into();
+        }
+        jv
+    }
+
+    /// Digs the main tunnel and its branches, then returns to the start. Ends early when the
+    /// inventory is full. Every vein is reported with an `ore_update` event of what it yielded,
+    /// and the whole run with a `mining_yield` event at the end.
+    pub fn run(&mut self, executor: &mut TaskExecutor) -> Result<(), Box<dyn Error>> {
+        if self.spacing < 0 || self.torch_spacing < 0 {
+            return Err(format!("Strip mine spacing must not be negative, got {} and torch spacing {}", self.spacing, self.torch_spacing).into());
+        }
+        executor.refresh_state()??;
+        let forward = self.start.direction();
+        let mut torch_due = false;
+        for step in 1..=self.length {
+            if !self.dig_ahead(executor)? {
+                break;
+            }
+            let tunnel = executor.turtle.position.clone();
+            torch_due |= self.torch_spacing > 0 && step % self.torch_spacing == 0;
+            if step % (self.spacing + 1) == 0 {
+                for turn in [-1, 1] {
+                    executor.go_to(tunnel.coordinate(), Some(forward.turn(turn)))??;
+                    for _ in 0..self.branch_length {
+                        if !self.dig_ahead(executor)? {
+                            break;
+                        }
+                    }
+                }
+                executor.go_to(tunnel.coordinate(), Some(forward))??;
+            } else if torch_due {
+                self.place_torch(executor)?;
+                torch_due = false;
+            }
+            if Self::inventory_full(executor) {
+                println!("Inventory is full, stopping the strip mine after {} blocks", step);
+                break;
+            }
+        }
+        executor.go_to(self.start.coordinate(), Some(forward))??;
+        executor.publish("mining_yield", Self::yields_json(&self.yields));
+        Ok(())
+    }
+
+    fn inventory_full(executor: &TaskExecutor) -> bool {
+        executor.turtle.inventory.item_iter().count() >= 15
+    }
+
+    /// Digs one block ahead, moves into it and mines the veins it sees from there.
+    /// Returns false when the way is blocked.
+    fn dig_ahead(&mut self, executor: &mut TaskExecutor) -> Result<bool, Box<dyn Error>> {
+        let mut maneuver = Maneuver::new();
+        maneuver.push(ManeuverStep::new(Move::Forward, true, 1));
+        if let Err((e, _)) = executor.run_maneuver(&maneuver)? {
+            eprintln!("Strip mine is blocked: {}", e);
+            return Ok(false);
+        }
+        self.look_around(executor)?;
+        Ok(true)
+    }
+
+    /// Inspects the sides and mines out every vein of ore next to the turtle, returning to where
+    /// it was afterwards
+    fn look_around(&mut self, executor: &mut TaskExecutor) -> Result<(), Box<dyn Error>> {
+        if !executor.execute(Task::inspect_around(), TaskExecutor::default_event_handler, TaskExecutor::null_question_handler)? {
+            return Ok(());
+        }
+        let here = executor.turtle.position.clone();
+        let mut vein: Vec<Coordinate> = Self::ores_around(executor, here.coordinate());
+        if vein.is_empty() {
+            return Ok(());
+        }
+
+        let mut mined = BTreeMap::new();
+        let mut seen: HashSet<Coordinate> = vein.iter().copied().collect();
+        while let Some(ore) = vein.pop() {
+            if seen.len() > MAX_VEIN {
+                eprintln!("Vein at {:?} is larger than {} blocks, leaving the rest", here.coordinate(), MAX_VEIN);
+                break;
+            }
+            let name = match executor.world().get(ore).filter(|b| is_ore(Some(b))).and_then(KnownBlock::name) {
+                Some(name) => name.to_owned(),
+                None => continue,
+            };
+            if let Err(e) = executor.go_to(ore, None)? {
+                eprintln!("Could not mine {} at {:?}: {}", name, ore, e);
+                continue;
+            }
+            *mined.entry(name).or_insert(0) += 1;
+            executor.execute(Task::inspect_around(), TaskExecutor::default_event_handler, TaskExecutor::null_question_handler)?;
+            for next in Self::ores_around(executor, ore) {
+                if seen.insert(next) {
+                    vein.push(next);
+                }
+            }
+        }
+        executor.go_to(here.coordinate(), Some(here.direction()))??;
+
+        if !mined.is_empty() {
+            println!("Mined a vein at {:?}: {:?}", here.coordinate(), mined);
+            executor.publish("ore_update", Self::yields_json(&mined));
+            for (name, count) in mined {
+                *self.yields.entry(name).or_insert(0) += count;
+            }
+        }
+        Ok(())
+    }
+
+    fn ores_around(executor: &TaskExecutor, coordinate: Coordinate) -> Vec<Coordinate> {
+        let world = executor.world();
+        neighbours(coordinate).iter().copied().filter(|c| is_ore(world.get(*c))).collect()
+    }
+
+    fn place_torch(&mut self, executor: &mut TaskExecutor) -> Result<(), Box<dyn Error>> {
+        let slot = match executor.turtle.inventory.find(|i| i.name == "minecraft:torch" && i.count > 0) {
+            Some((_, slot)) => slot,
+            None => return Ok(()),
+        };
+        executor.execute(Task::place_torch(slot), TaskExecutor::default_event_handler, TaskExecutor::null_question_handler)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simulator::{Block, World};
+    use crate::simulator::testing::{finish, start_with};
+    use crate::turtle::{Coordinate, Item, Position};
+
+    use super::*;
+
+    #[test]
+    fn strip_mine_follows_veins_and_counts_the_ores() {
+        let mut world = World::new();
+        world.fill(Coordinate::new(-8, -2, -12), Coordinate::new(8, 2, 0), "minecraft:stone");
+        world.set_block(Coordinate::new(0, 0, 0), None);
+        // A vein next to the tunnel that bends up and away from it, and one ore in a branch
+        for c in [Coordinate::new(1, 0, -2), Coordinate::new(2, 0, -2), Coordinate::new(2, 1, -2)].iter() {
+            world.set_block(*c, Some(Block::new("minecraft:iron_ore")));
+        }
+        world.set_block(Coordinate::new(-2, 0, -4), Some(Block::new("minecraft:coal_ore")));
+        let (mut executor, handle) = start_with(world, 1000, |t| {
+            t.inventory[0] = Some(Item { count: 4, name: "minecraft:torch".to_owned() });
+        });
+        let mut mine = StripMine::new(Position::default(), 8, 3, 3);
+        mine.torch_spacing = 6;
+
+        mine.run(&mut executor).unwrap();
+        assert_eq!(mine.yields.get("minecraft:iron_ore"), Some(&3));
+        assert_eq!(mine.yields.get("minecraft:coal_ore"), Some(&1));
+        assert_eq!(executor.turtle.position.coordinate(), Coordinate::new(0, 0, 0));
+
+        let turtle = finish(executor, handle);
+        assert_eq!(turtle.item_count("minecraft:iron_ore"), 3);
+        assert!(turtle.world.block(Coordinate::new(0, 0, -8)).is_none());
+        assert!(turtle.world.block(Coordinate::new(3, 0, -4)).is_none());
+        assert!(turtle.world.block(Coordinate::new(4, 0, -4)).is_some());
+        assert_eq!(turtle.world.block(Coordinate::new(-1, 0, -6)).map(|b| b.name.as_str()), Some("minecraft:torch"));
+    }
+
+    #[test]
+    fn strip_mine_places_torches_with_the_default_spacings() {
+        let mut world = World::new();
+        world.fill(Coordinate::new(-3, -1, -18), Coordinate::new(3, 1, 0), "minecraft:stone");
+        world.set_block(Coordinate::new(0, 0, 0), None);
+        let (mut executor, handle) = start_with(world, 1000, |t| {
+            t.inventory[0] = Some(Item { count: 4, name: "minecraft:torch".to_owned() });
+        });
+        // Every torch block is also a branch block, so the torches go one block further
+        let mut mine = StripMine::new(Position::default(), 17, 3, 1);
+        mine.torch_spacing = 8;
+
+        mine.run(&mut executor).unwrap();
+
+        let turtle = finish(executor, handle);
+        assert_eq!(turtle.item_count("minecraft:torch"), 2);
+        for z in [-9, -17] {
+            assert_eq!(turtle.world.block(Coordinate::new(-1, 0, z)).map(|b| b.name.as_str()), Some("minecraft:torch"), "no torch at {}", z);
+        }
+        assert!(turtle.world.block(Coordinate::new(-1, 0, -8)).is_none());
+        assert!(turtle.world.block(Coordinate::new(-2, 0, -8)).is_some());
+    }
+
+    #[test]
+    fn strip_mine_rejects_negative_spacing() {
+        let mut world = World::new();
+        world.fill(Coordinate::new(-3, -1, -8), Coordinate::new(3, 1, 0), "minecraft:stone");
+        world.set_block(Coordinate::new(0, 0, 0), None);
+        let (mut executor, handle) = start_with(world, 1000, |_| {});
+        let mut mine = StripMine::new(Position::default(), 4, -1, 1);
+
+        assert!(mine.run(&mut executor).is_err());
+        let turtle = finish(executor, handle);
+        assert!(turtle.world.block(Coordinate::new(0, 0, -1)).is_some());
+    }
+}
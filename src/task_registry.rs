@@ -0,0 +1,606 @@
+use std::time::Duration;
+
+use json::JsonValue;
+
+use crate::crafting::CraftPlan;
+use crate::fuel::Refuel;
+use crate::decode::{DecodeError, expect_array, expect_bool, expect_i64, expect_object, expect_str};
+
+/// The shape of task arguments and question answers, checked before anything reaches the turtle
+#[derive(Clone, Debug)]
+pub enum ArgType {
+    /// No value, tasks without arguments
+    Null,
+    Bool,
+    Integer { min: i64, max: i64 },
+    /// An inventory slot as lua numbers them, 1 to 16
+    Slot,
+    String,
+    /// Any number of values of the same type
+    List(Box<ArgType>),
+    /// Values of the given types in order, trailing `Optional` ones may be left out
+    Tuple(Vec<ArgType>),
+    Object(Vec<(&'static str, ArgType)>),
+    Optional(Box<ArgType>),
+    Any,
+}
+
+impl ArgType {
+    pub fn check(&self, jv: &JsonValue) -> Result<(), DecodeError> {
+        match self {
+            ArgType::Null if jv.is_null() => Ok(()),
+            ArgType::Null => Err(DecodeError::expected("null", jv)),
+            ArgType::Bool => expect_bool(jv).map(|_| ()),
+            ArgType::Integer { min, max } => match expect_i64(jv)? {
+                i if (*min..=*max).contains(&i) => Ok(()),
+                _ => Err(DecodeError::expected("integer in range", jv)),
+            },
+            ArgType::Slot => match expect_i64(jv)? {
+                1..=16 => Ok(()),
+                _ => Err(DecodeError::expected("slot (1-16)", jv)),
+            },
+            ArgType::String => expect_str(jv).map(|_| ()),
+            ArgType::List(item) => expect_array(jv)?.iter().enumerate()
+                .try_for_each(|(i, v)| item.check(v).map_err(|e| e.at_index(i))),
+            ArgType::Tuple(items) => {
+                let values = expect_array(jv)?;
+                if values.len() > items.len() {
+                    return Err(DecodeError::length(items.len(), values.len()));
+                }
+                items.iter().enumerate()
+                    .try_for_each(|(i, item)| item.check(values.get(i).unwrap_or(&JsonValue::Null)).map_err(|e| e.at_index(i)))
+            }
+            ArgType::Object(fields) => {
+                expect_object(jv)?;
+                fields.iter().try_for_each(|(key, field)| field.check(&jv[*key]).map_err(|e| e.at(key)))
+            }
+            ArgType::Optional(_) if jv.is_null() => Ok(()),
+            ArgType::Optional(inner) => inner.check(jv),
+            ArgType::Any => Ok(()),
+        }
+    }
+}
+
+/// Describes the type for clients, e.g. `{"type": "list", "of": {"type": "slot"}}`
+impl From<&ArgType> for JsonValue {
+    fn from(arg: &ArgType) -> Self {
+        match arg {
+            ArgType::Null => json::object! { type: "null" },
+            ArgType::Bool => json::object! { type: "bool" },
+            ArgType::Integer { min, max } => json::object! { type: "integer", min: *min, max: *max },
+            ArgType::Slot => json::object! { type: "slot" },
+            ArgType::String => json::object! { type: "string" },
+            ArgType::List(item) => json::object! { type: "list", of: JsonValue::from(item.as_ref()) },
+            ArgType::Tuple(items) => json::object! {
+                type: "tuple",
+                of: items.iter().map(JsonValue::from).collect::<Vec<JsonValue>>(),
+            },
+            ArgType::Object(fields) => {
+                let mut of = JsonValue::new_object();
+                for (key, field) in fields {
+                    of[*key] = field.into();
+                }
+                json::object! { type: "object", of: of }
+            }
+            ArgType::Optional(inner) => json::object! { type: "optional", of: JsonValue::from(inner.as_ref()) },
+            ArgType::Any => json::object! { type: "any" },
+        }
+    }
+}
+
+/// A question a task may ask with `task:task_question`, what it sends along and what the answer
+/// has to look like. Questions the handler of the task can not answer go to an operator, who has
+/// `timeout` to answer before the task gets the `default` answer, or is cancelled without one.
+pub struct Question {
+    pub code: &'static str,
+    pub payload: ArgType,
+    pub answer: ArgType,
+    pub default: Option<JsonValue>,
+    pub timeout: Duration,
+}
+
+/// A lua task in `lua-scripts/tasks`, as far as the server needs to know it. Adding a task means
+/// writing the lua file and registering an implementation of this with the `TaskRegistry`.
+pub trait TaskDefinition: Send + Sync {
+    /// The name of the lua file, and what the task is called in the console and REST API
+    fn code(&self) -> &'static str;
+
+    fn description(&self) -> &'static str;
+
+    fn arguments(&self) -> ArgType {
+        ArgType::Null
+    }
+
+    fn questions(&self) -> Vec<Question> {
+        Vec::new()
+    }
+
+    /// Events besides the task lifecycle (finish, cancel, errors, questions) the task sends
+    fn events(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Rough fuel the task takes, if we know
+    fn fuel_estimate(&self, _arguments: &JsonValue) -> Option<i64> {
+        None
+    }
+}
+
+/// A task with checked arguments, ready to be sent to a turtle
+#[derive(Clone, Debug)]
+pub struct Task {
+    code: String,
+    arguments: JsonValue,
+}
+
+impl Task {
+    pub fn code(&self) -> &str {
+        self.code.as_str()
+    }
+
+    pub fn arguments(&self) -> &JsonValue {
+        &self.arguments
+    }
+
+    /// A task that is not checked against any definition. The registry builds checked ones.
+    pub fn unchecked(code: &str, arguments: JsonValue) -> Self {
+        Self { code: code.to_owned(), arguments }
+    }
+}
+
+impl From<&Task> for JsonValue {
+    fn from(task: &Task) -> Self {
+        json::object! {
+            c: task.code.as_str(),
+            b: task.arguments.clone(),
+        }
+    }
+}
+
+/// Tasks the server starts by itself, these match the definitions below
+impl Task {
+    pub fn fell() -> Self {
+        Self::unchecked("fell", JsonValue::Null)
+    }
+
+    pub fn first_tree() -> Self {
+        Self::unchecked("first_tree", JsonValue::Null)
+    }
+
+    pub fn refuel_logs(slot: u8, count: u8) -> Self {
+        Self::unchecked("refuel_logs", json::object! { slot: slot, count: count })
+    }
+
+    pub fn refuel(plan: &[Refuel]) -> Self {
+        let refuels: Vec<JsonValue> = plan.iter().map(|r| json::object! { slot: r.slot, count: r.count }).collect();
+        Self::unchecked("refuel", refuels.into())
+    }
+
+    pub fn inspect() -> Self {
+        Self::unchecked("inspect", JsonValue::Null)
+    }
+
+    pub fn inspect_around() -> Self {
+        Self::unchecked("inspect_around", JsonValue::Null)
+    }
+
+    pub fn place_torch(torch_slot: usize) -> Self {
+        Self::unchecked("place_torch", json::object! { slot: torch_slot })
+    }
+
+    pub fn plant(sapling_slot: usize) -> Self {
+        Self::unchecked("plant", json::object! { slot: sapling_slot })
+    }
+
+    pub fn place_down(slot: usize) -> Self {
+        Self::unchecked("place_down", json::object! { slot: slot })
+    }
+
+    pub fn craft(plan: &CraftPlan) -> Self {
+        let stash: Vec<JsonValue> = plan.stash.iter().map(|(slot, count)| json::object! { slot: *slot, count: *count }).collect();
+        let moves: Vec<JsonValue> = plan.moves.iter().map(|m| json::object! { from: m.from, to: m.to, count: m.count }).collect();
+        Self::unchecked("craft", json::object! { stash: stash, moves: moves, slot: plan.slot })
+    }
+
+    /// Takes `item` from the inventory in front until the turtle has `count` of it
+    pub fn restock(item: &str, count: u32) -> Self {
+        Self::unchecked("restock", json::object! { item: item, count: count })
+    }
+
+    /// Drops `count` items of each slot into the inventory in front
+    pub fn deposit(items: &[(usize, u8)]) -> Self {
+        let items: Vec<JsonValue> = items.iter().map(|(slot, count)| json::object! { slot: *slot, count: *count }).collect();
+        Self::unchecked("deposit", items.into())
+    }
+}
+
+/// The tasks the server knows, looked up by code
+pub struct TaskRegistry {
+    tasks: Vec<Box<dyn TaskDefinition>>,
+}
+
+/// Has the tasks that come with the lua scripts
+impl Default for TaskRegistry {
+    fn default() -> Self {
+        let mut registry = Self { tasks: Vec::new() };
+        registry.register(Box::new(Fell));
+        registry.register(Box::new(FirstTree));
+        registry.register(Box::new(RefuelLogs));
+        registry.register(Box::new(RefuelSlots));
+        registry.register(Box::new(Inspect));
+        registry.register(Box::new(Plant));
+        registry.register(Box::new(Deposit));
+        registry.register(Box::new(InspectAround));
+        registry.register(Box::new(PlaceTorch));
+        registry.register(Box::new(PlaceDown));
+        registry.register(Box::new(Restock));
+        registry.register(Box::new(Craft));
+        registry
+    }
+}
+
+impl TaskRegistry {
+    /// Adds a task, replacing one with the same code
+    pub fn register(&mut self, task: Box<dyn TaskDefinition>) {
+        self.tasks.retain(|t| t.code() != task.code());
+        self.tasks.push(task);
+    }
+
+    pub fn get(&self, code: &str) -> Option<&dyn TaskDefinition> {
+        self.tasks.iter().find(|t| t.code() == code).map(|t| t.as_ref())
+    }
+
+    pub fn list(&self) -> impl Iterator<Item=&dyn TaskDefinition> {
+        self.tasks.iter().map(|t| t.as_ref())
+    }
+
+    /// Checks the arguments against the definition of the task
+    pub fn build(&self, code: &str, arguments: JsonValue) -> Result<Task, String> {
+        let definition = self.get(code).ok_or_else(|| format!("Unknown task {}", code))?;
+        definition.arguments().check(&arguments)
+            .map_err(|e| format!("Invalid arguments for task {} {}", code, e))?;
+        Ok(Task { code: code.to_owned(), arguments })
+    }
+
+    /// The question as the task declares it
+    pub fn question(&self, code: &str, question: &str) -> Option<Question> {
+        self.get(code).and_then(|t| t.questions().into_iter().find(|q| q.code == question))
+    }
+
+    /// Checks the answer to a question of the task. Questions the task does not declare can not
+    /// be answered.
+    pub fn check_answer(&self, code: &str, question: &str, answer: &JsonValue) -> Result<(), String> {
+        let expected = self.question(code, question)
+            .ok_or_else(|| format!("Task {} does not ask {}", code, question))?;
+        expected.answer.check(answer).map_err(|e| format!("Invalid answer to {} {}", question, e))
+    }
+
+    pub fn fuel_estimate(&self, task: &Task) -> Option<i64> {
+        self.get(task.code()).and_then(|t| t.fuel_estimate(task.arguments()))
+    }
+}
+
+impl From<&dyn TaskDefinition> for JsonValue {
+    fn from(task: &dyn TaskDefinition) -> Self {
+        let questions: Vec<JsonValue> = task.questions().iter()
+            .map(|q| json::object! {
+                code: q.code,
+                payload: JsonValue::from(&q.payload),
+                answer: JsonValue::from(&q.answer),
+                default: q.default.clone(),
+                timeout: q.timeout.as_secs(),
+            })
+            .collect();
+        json::object! {
+            code: task.code(),
+            description: task.description(),
+            arguments: JsonValue::from(&task.arguments()),
+            questions: questions,
+            events: task.events(),
+        }
+    }
+}
+
+/// Answer to `replant`, asked after a tree is felled with the position of the turtle
+pub struct ReplantAnswer {
+    /// Wait for the sapling to grow into a tree before finishing the task
+    pub wait: bool,
+    pub sapling_slot: usize,
+}
+
+impl ReplantAnswer {
+    /// Leaves the spot empty
+    pub fn skip() -> JsonValue {
+        json::array![false]
+    }
+}
+
+/// `[replant, wait for the tree, sapling slot]` as the lua task unpacks it
+impl From<&ReplantAnswer> for JsonValue {
+    fn from(answer: &ReplantAnswer) -> Self {
+        json::array![true, answer.wait, answer.sapling_slot]
+    }
+}
+
+fn replant_question() -> Question {
+    Question {
+        code: "replant",
+        payload: ArgType::Object(vec![("position", ArgType::Any)]),
+        answer: ArgType::Tuple(vec![
+            ArgType::Bool,
+            ArgType::Optional(Box::new(ArgType::Bool)),
+            ArgType::Optional(Box::new(ArgType::Slot)),
+        ]),
+        default: Some(ReplantAnswer::skip()),
+        timeout: Duration::from_secs(300),
+    }
+}
+
+struct Fell;
+
+impl TaskDefinition for Fell {
+    fn code(&self) -> &'static str {
+        "fell"
+    }
+
+    fn description(&self) -> &'static str {
+        "Fells the tree in front and replants it"
+    }
+
+    fn questions(&self) -> Vec<Question> {
+        vec![replant_question()]
+    }
+
+    fn events(&self) -> &'static [&'static str] {
+        &["position_update", "inventory_update", "block_update"]
+    }
+
+    fn fuel_estimate(&self, _arguments: &JsonValue) -> Option<i64> {
+        // Climbing the tree, the spiral through the leaves and replanting
+        Some(60)
+    }
+}
+
+struct FirstTree;
+
+impl TaskDefinition for FirstTree {
+    fn code(&self) -> &'static str {
+        "first_tree"
+    }
+
+    fn description(&self) -> &'static str {
+        "Fells the first tree without fuel, burning its logs on the way"
+    }
+
+    fn questions(&self) -> Vec<Question> {
+        vec![replant_question()]
+    }
+
+    fn events(&self) -> &'static [&'static str] {
+        &["position_update", "inventory_update", "block_update"]
+    }
+}
+
+struct RefuelLogs;
+
+impl TaskDefinition for RefuelLogs {
+    fn code(&self) -> &'static str {
+        "refuel_logs"
+    }
+
+    fn description(&self) -> &'static str {
+        "Crafts the logs in a slot into planks and burns them"
+    }
+
+    fn arguments(&self) -> ArgType {
+        ArgType::Object(vec![
+            ("slot", ArgType::Slot),
+            ("count", ArgType::Integer { min: 1, max: 64 }),
+        ])
+    }
+}
+
+struct RefuelSlots;
+
+impl TaskDefinition for RefuelSlots {
+    fn code(&self) -> &'static str {
+        "refuel"
+    }
+
+    fn description(&self) -> &'static str {
+        "Burns the given number of items from each slot"
+    }
+
+    fn arguments(&self) -> ArgType {
+        ArgType::List(Box::new(ArgType::Object(vec![
+            ("slot", ArgType::Slot),
+            ("count", ArgType::Integer { min: 0, max: 64 }),
+        ])))
+    }
+
+    fn events(&self) -> &'static [&'static str] {
+        &["inventory_update"]
+    }
+}
+
+struct Inspect;
+
+impl TaskDefinition for Inspect {
+    fn code(&self) -> &'static str {
+        "inspect"
+    }
+
+    fn description(&self) -> &'static str {
+        "Reports the block in front to the world map"
+    }
+
+    fn events(&self) -> &'static [&'static str] {
+        &["block_update"]
+    }
+}
+
+struct Plant;
+
+impl TaskDefinition for Plant {
+    fn code(&self) -> &'static str {
+        "plant"
+    }
+
+    fn description(&self) -> &'static str {
+        "Picks up the drops in front and plants a sapling from a slot there"
+    }
+
+    fn arguments(&self) -> ArgType {
+        ArgType::Object(vec![("slot", ArgType::Slot)])
+    }
+
+    fn events(&self) -> &'static [&'static str] {
+        &["block_update", "inventory_update"]
+    }
+}
+
+struct Deposit;
+
+impl TaskDefinition for Deposit {
+    fn code(&self) -> &'static str {
+        "deposit"
+    }
+
+    fn description(&self) -> &'static str {
+        "Drops the given number of items from each slot into the chest in front"
+    }
+
+    fn arguments(&self) -> ArgType {
+        ArgType::List(Box::new(ArgType::Object(vec![
+            ("slot", ArgType::Slot),
+            ("count", ArgType::Integer { min: 0, max: 64 }),
+        ])))
+    }
+
+    fn events(&self) -> &'static [&'static str] {
+        &["inventory_update"]
+    }
+}
+
+struct InspectAround;
+
+impl TaskDefinition for InspectAround {
+    fn code(&self) -> &'static str {
+        "inspect_around"
+    }
+
+    fn description(&self) -> &'static str {
+        "Reports the blocks on every side but the back to the world map"
+    }
+
+    fn events(&self) -> &'static [&'static str] {
+        &["block_update"]
+    }
+}
+
+struct PlaceTorch;
+
+impl TaskDefinition for PlaceTorch {
+    fn code(&self) -> &'static str {
+        "place_torch"
+    }
+
+    fn description(&self) -> &'static str {
+        "Digs a niche into the wall to the left and puts a torch from a slot in it"
+    }
+
+    fn arguments(&self) -> ArgType {
+        ArgType::Object(vec![("slot", ArgType::Slot)])
+    }
+
+    fn events(&self) -> &'static [&'static str] {
+        &["block_update", "inventory_update"]
+    }
+}
+
+struct PlaceDown;
+
+impl TaskDefinition for PlaceDown {
+    fn code(&self) -> &'static str {
+        "place_down"
+    }
+
+    fn description(&self) -> &'static str {
+        "Places a block from a slot below the turtle"
+    }
+
+    fn arguments(&self) -> ArgType {
+        ArgType::Object(vec![("slot", ArgType::Slot)])
+    }
+
+    fn events(&self) -> &'static [&'static str] {
+        &["block_update", "inventory_update"]
+    }
+}
+
+struct Restock;
+
+impl TaskDefinition for Restock {
+    fn code(&self) -> &'static str {
+        "restock"
+    }
+
+    fn description(&self) -> &'static str {
+        "Takes one item from the chest in front until the turtle has the count, is full or the chest has no more"
+    }
+
+    fn arguments(&self) -> ArgType {
+        ArgType::Object(vec![
+            ("item", ArgType::String),
+            ("count", ArgType::Integer { min: 1, max: u32::MAX as i64 }),
+        ])
+    }
+
+    fn events(&self) -> &'static [&'static str] {
+        &["inventory_update"]
+    }
+}
+
+struct Craft;
+
+impl TaskDefinition for Craft {
+    fn code(&self) -> &'static str {
+        "craft"
+    }
+
+    fn description(&self) -> &'static str {
+        "Stashes items into the empty chest in front, moves items between slots, crafts into a slot and takes the stashed items back"
+    }
+
+    fn arguments(&self) -> ArgType {
+        let count = || ArgType::Integer { min: 0, max: 64 };
+        ArgType::Object(vec![
+            ("stash", ArgType::List(Box::new(ArgType::Object(vec![("slot", ArgType::Slot), ("count", count())])))),
+            ("moves", ArgType::List(Box::new(ArgType::Object(vec![("from", ArgType::Slot), ("to", ArgType::Slot), ("count", count())])))),
+            ("slot", ArgType::Slot),
+        ])
+    }
+
+    fn events(&self) -> &'static [&'static str] {
+        &["inventory_update"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn task_arguments_and_answers_are_checked() {
+        let tasks = TaskRegistry::default();
+        assert!(tasks.build("refuel_logs", json::object! { slot: 3, count: 2 }).is_ok());
+        let err = tasks.build("refuel_logs", json::object! { slot: 17, count: 2 }).unwrap_err();
+        assert_eq!(err, "Invalid arguments for task refuel_logs at $.slot: expected slot (1-16), got 17");
+        assert_eq!(tasks.build("dance", JsonValue::Null).unwrap_err(), "Unknown task dance");
+
+        assert!(tasks.check_answer("fell", "replant", &json::array![false]).is_ok());
+        assert!(tasks.check_answer("fell", "replant", &json::array![true, false, 4]).is_ok());
+        assert!(tasks.check_answer("fell", "replant", &json::array![true, "yes"]).is_err());
+        assert!(tasks.check_answer("refuel", "replant", &json::array![false]).is_err());
+    }
+}
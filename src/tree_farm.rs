@@ -0,0 +1,365 @@
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{env, fs, io};
+
+use json::JsonValue;
+
+use crate::decode::{DecodeError, expect_array, expect_object, expect_str, expect_u32, expect_u64, field, field_with};
+use crate::executor::TaskExecutor;
+use crate::task_registry::{ReplantAnswer, Task};
+use crate::turtle::{Coordinate, Position};
+use crate::turtle_websocket::TaskQuestion;
+use crate::world_map::KnownBlock;
+
+/// What was last seen on a planting spot
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SpotState {
+    /// Not looked at yet
+    Unknown,
+    Empty,
+    Sapling,
+    Tree,
+    /// Something else is in the way, the spot is left alone
+    Blocked,
+}
+
+impl SpotState {
+    pub fn code(&self) -> &'static str {
+        match self {
+            SpotState::Unknown => "unknown",
+            SpotState::Empty => "empty",
+            SpotState::Sapling => "sapling",
+            SpotState::Tree => "tree",
+            SpotState::Blocked => "blocked",
+        }
+    }
+
+    fn of_block(block: Option<&KnownBlock>) -> Self {
+        match block {
+            None | Some(KnownBlock::Air) => SpotState::Empty,
+            Some(KnownBlock::Named(name)) if name.ends_with("_log") => SpotState::Tree,
+            Some(KnownBlock::Named(name)) if name.ends_with("_sapling") => SpotState::Sapling,
+            Some(_) => SpotState::Blocked,
+        }
+    }
+}
+
+impl TryFrom<&JsonValue> for SpotState {
+    type Error = DecodeError;
+
+    fn try_from(jv: &JsonValue) -> Result<Self, Self::Error> {
+        match expect_str(jv)? {
+            "unknown" => Ok(SpotState::Unknown),
+            "empty" => Ok(SpotState::Empty),
+            "sapling" => Ok(SpotState::Sapling),
+            "tree" => Ok(SpotState::Tree),
+            "blocked" => Ok(SpotState::Blocked),
+            _ => Err(DecodeError::expected("spot state", jv)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Spot {
+    pub coordinate: Coordinate,
+    pub state: SpotState,
+    /// When we last planted a sapling here
+    pub planted: Option<SystemTime>,
+    pub harvests: u32,
+}
+
+impl Spot {
+    fn new(coordinate: Coordinate) -> Self {
+        Self { coordinate, state: SpotState::Unknown, planted: None, harvests: 0 }
+    }
+}
+
+impl From<&Spot> for JsonValue {
+    fn from(spot: &Spot) -> Self {
+        let planted = spot.planted.and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs());
+        json::object! {
+            coordinate: Into::<JsonValue>::into(spot.coordinate),
+            state: spot.state.code(),
+            planted: planted,
+            harvests: spot.harvests,
+        }
+    }
+}
+
+impl TryFrom<&JsonValue> for Spot {
+    type Error = DecodeError;
+
+    fn try_from(jv: &JsonValue) -> Result<Self, Self::Error> {
+        expect_object(jv)?;
+        let planted = match &jv["planted"] {
+            JsonValue::Null => None,
+            secs => Some(UNIX_EPOCH + Duration::from_secs(expect_u64(secs).map_err(|e| e.at("planted"))?)),
+        };
+        Ok(Self {
+            coordinate: field(jv, "coordinate")?,
+            state: field(jv, "state")?,
+            planted,
+            harvests: field_with(jv, "harvests", expect_u32)?,
+        })
+    }
+}
+
+/// A grid of planting spots in front of and to the right of a home position, with a chest for the
+/// logs right behind home. Spots are `TREE_FARM_SPACING` (default 3) apart in
+/// `TREE_FARM_ROWS` by `TREE_FARM_COLUMNS` (default 2 by 2), the first one right in front of home.
+/// The layout and the state of every spot are stored as json in the file at `TREE_FARM_FILE`
+/// (default `tree_farm_{id}.json`, with the id of the turtle), so the farm carries on after a
+/// restart of the server.
+pub struct TreeFarm {
+    /// Where the farm is saved, `None` keeps it in memory only
+    path: Option<String>,
+    /// Where rounds start, facing the first row
+    pub home: Position,
+    pub spots: Vec<Spot>,
+    /// How long to wait between rounds, from `TREE_FARM_INTERVAL` in seconds (default 120)
+    pub interval: Duration,
+    /// Logs kept for fuel instead of going into the chest, from `TREE_FARM_KEEP_LOGS` (default 16)
+    pub keep_logs: u32,
+}
+
+impl TreeFarm {
+    pub fn new(home: Position, rows: i64, columns: i64, spacing: i64) -> Self {
+        let mut spots = Vec::new();
+        for row in 0..rows {
+            for column in 0..columns {
+                let mut position = home.clone();
+                position.move_horizontal(1 + row * spacing);
+                position.turn(1);
+                position.move_horizontal(column * spacing);
+                spots.push(Spot::new(position.coordinate()));
+            }
+        }
+        Self { path: None, home, spots, interval: Duration::from_secs(120), keep_logs: 16 }
+    }
+
+    /// Loads the farm of the turtle, or lays out a new one around `home` if it has none yet
+    pub fn load(id: u32, home: &Position) -> io::Result<Self> {
+        let path = env::var("TREE_FARM_FILE").unwrap_or(String::from("tree_farm_{id}.json"))
+            .replace("{id}", id.to_string().as_str());
+        let mut farm = Self::open(path, home)?;
+        if let Some(interval) = env::var("TREE_FARM_INTERVAL").ok().and_then(|s| s.parse().ok()) {
+            farm.interval = Duration::from_secs(interval);
+        }
+        if let Some(keep_logs) = env::var("TREE_FARM_KEEP_LOGS").ok().and_then(|s| s.parse().ok()) {
+            farm.keep_logs = keep_logs;
+        }
+        Ok(farm)
+    }
+
+    /// Loads the farm saved in the file, or lays out a new one around `home` that is saved there
+    fn open(path: String, home: &Position) -> io::Result<Self> {
+        let mut farm = match fs::read_to_string(&path) {
+            Ok(s) => json::parse(s.as_str())
+                .map_err(|e| e.to_string())
+                .and_then(|jv| Self::decode(&jv).map_err(|e| e.to_string()))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Could not parse tree farm file {}: {}", path, e)))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let var = |name: &str, default: i64| env::var(name).ok().and_then(|s| s.parse().ok()).unwrap_or(default);
+                Self::new(home.clone(), var("TREE_FARM_ROWS", 2), var("TREE_FARM_COLUMNS", 2), var("TREE_FARM_SPACING", 3))
+            }
+            Err(e) => return Err(e),
+        };
+        farm.path = Some(path);
+        Ok(farm)
+    }
+
+    fn decode(jv: &JsonValue) -> Result<Self, DecodeError> {
+        expect_object(jv)?;
+        let spots = field_with(jv, "spots", expect_array)?.iter().enumerate()
+            .map(|(i, s)| Spot::try_from(s).map_err(|e| e.at_index(i).at("spots")))
+            .collect::<Result<Vec<Spot>, DecodeError>>()?;
+        Ok(Self { home: field(jv, "home")?, spots, ..Self::new(Position::default(), 0, 0, 0) })
+    }
+
+    /// Writes the layout and the state of the spots to disk
+    pub fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let jv = json::object! {
+            home: &self.home,
+            spots: self.spots.iter().map(JsonValue::from).collect::<Vec<JsonValue>>(),
+        };
+        fs::write(path, json::stringify(jv))
+    }
+
+    /// Tends the farm forever, serving requests between rounds
+    pub fn run(&mut self, executor: &mut TaskExecutor) -> Result<(), Box<dyn Error>> {
+        loop {
+            self.round(executor)?;
+            executor.serve_requests_for(self.interval)?;
+        }
+    }
+
+    /// Walks the grid once, felling grown trees and planting empty spots, then takes the surplus
+    /// logs to the chest
+    pub fn round(&mut self, executor: &mut TaskExecutor) -> Result<(), Box<dyn Error>> {
+        for index in 0..self.spots.len() {
+            if let Err(e) = self.tend(executor, index)? {
+                eprintln!("Could not tend spot {:?}: {}", self.spots[index].coordinate, e);
+            }
+            if let Err(e) = self.save() {
+                eprintln!("Could not save tree farm: {}", e);
+            }
+        }
+        if let Err(e) = self.deposit(executor)? {
+            eprintln!("Could not deposit logs: {}", e);
+        }
+        Ok(())
+    }
+
+    /// Saplings and trees of the farm, which routes should not dig through
+    fn avoid(&self) -> HashSet<Coordinate> {
+        self.spots.iter().map(|s| s.coordinate).collect()
+    }
+
+    fn tend(&mut self, executor: &mut TaskExecutor, index: usize) -> Result<Result<(), String>, Box<dyn Error>> {
+        let spot = self.spots[index].coordinate;
+        let facing = self.home.direction();
+        let approach = Position::new(spot, facing).ahead(-1);
+        if let Err(e) = executor.go_to_avoiding(approach, Some(facing), self.avoid())? {
+            return Ok(Err(e.to_string()));
+        }
+        if !executor.execute(Task::inspect(), TaskExecutor::default_event_handler, TaskExecutor::null_question_handler)? {
+            return Ok(Err("Could not inspect the spot".to_string()));
+        }
+        let state = SpotState::of_block(executor.world().get(spot));
+        self.spots[index].state = state;
+
+        if state == SpotState::Tree {
+            // We plant ourselves, fell would put the sapling wherever it ends up
+            let skip_replant = |q: &TaskQuestion, _: &mut TaskExecutor| (q.code == "replant").then(ReplantAnswer::skip);
+            if !executor.execute(Task::fell(), TaskExecutor::default_event_handler, skip_replant)? {
+                return Ok(Err("Could not fell the tree".to_string()));
+            }
+            println!("Felled the tree at {:?}", spot);
+            self.spots[index].state = SpotState::Empty;
+            self.spots[index].harvests += 1;
+            if let Err(e) = executor.go_to_avoiding(approach, Some(facing), self.avoid())? {
+                return Ok(Err(e.to_string()));
+            }
+        }
+
+        if self.spots[index].state == SpotState::Empty {
+            let sapling_slot = match executor.turtle.inventory.find(|i| i.name.ends_with("_sapling") && i.count > 0) {
+                Some((_, slot)) => slot,
+                None => return Ok(Err("No saplings to plant".to_string())),
+            };
+            if !executor.execute(Task::plant(sapling_slot), TaskExecutor::default_event_handler, TaskExecutor::null_question_handler)? {
+                return Ok(Err("Could not plant".to_string()));
+            }
+            let state = SpotState::of_block(executor.world().get(spot));
+            if state == SpotState::Empty {
+                return Ok(Err("The sapling did not stay".to_string()));
+            }
+            self.spots[index].state = state;
+            self.spots[index].planted = Some(SystemTime::now());
+        }
+        Ok(Ok(()))
+    }
+
+    /// Drops the logs beyond `keep_logs` into the chest behind home, taking from the last slots first
+    fn deposit(&mut self, executor: &mut TaskExecutor) -> Result<Result<(), String>, Box<dyn Error>> {
+        let logs: Vec<(usize, u8)> = executor.turtle.inventory.find_all(|i| i.name.ends_with("_log"))
+            .map(|(i, slot)| (slot, i.count))
+            .collect();
+        let mut surplus = logs.iter().map(|(_, count)| *count as u32).sum::<u32>().saturating_sub(self.keep_logs);
+        if surplus == 0 {
+            return Ok(Ok(()));
+        }
+        let mut items = Vec::new();
+        for (slot, count) in logs.into_iter().rev() {
+            let take = (count as u32).min(surplus);
+            if take > 0 {
+                items.push((slot, take as u8));
+                surplus -= take;
+            }
+        }
+
+        if let Err(e) = executor.go_to_avoiding(self.home.coordinate(), Some(self.home.direction().turn(2)), self.avoid())? {
+            return Ok(Err(e.to_string()));
+        }
+        if !executor.execute(Task::deposit(&items), TaskExecutor::default_event_handler, TaskExecutor::null_question_handler)? {
+            return Ok(Err("Deposit task failed".to_string()));
+        }
+        Ok(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simulator::{Block, TREE_HEIGHT, World};
+    use crate::simulator::testing::{finish, start_with};
+    use crate::turtle::{Coordinate, Direction, Item, Position};
+
+    use super::*;
+
+    #[test]
+    fn saved_farm_loads_back() {
+        let path = env::temp_dir().join(format!("tree_farm_{}.json", std::process::id()));
+        let path = path.to_str().unwrap().to_owned();
+        let _ = fs::remove_file(&path);
+
+        let home = Position::new(Coordinate::new(10, 64, -3), Direction::East);
+        let mut farm = TreeFarm::open(path.clone(), &home).unwrap();
+        assert_eq!(farm.spots.len(), 4);
+        assert!(farm.spots.iter().all(|s| s.state == SpotState::Unknown));
+        farm.spots[0].state = SpotState::Sapling;
+        farm.spots[0].planted = Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        farm.spots[1].state = SpotState::Tree;
+        farm.spots[1].harvests = 3;
+        farm.spots[3].state = SpotState::Blocked;
+        farm.save().unwrap();
+
+        // The saved layout wins over the home the farm is loaded with
+        let loaded = TreeFarm::open(path.clone(), &Position::default()).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.home.coordinate(), home.coordinate());
+        assert_eq!(loaded.home.direction(), home.direction());
+        assert_eq!(loaded.spots, farm.spots);
+    }
+
+    #[test]
+    fn bad_spots_are_errors() {
+        let spot = |state: &str| json::parse(&format!(r#"{{"home": {{"coordinate": {{"x": 0, "y": 0, "z": 0}}, "direction": "N"}},
+            "spots": [{{"coordinate": {{"x": 0, "y": 0, "z": -1}}, "state": {}, "planted": null, "harvests": 0}}]}}"#, state)).unwrap();
+        assert!(TreeFarm::decode(&spot(r#""tree""#)).is_ok());
+        let error = TreeFarm::decode(&spot(r#""stump""#)).err().unwrap();
+        assert!(error.to_string().contains("spots[0].state"), "{}", error);
+    }
+
+    #[test]
+    fn tree_farm_fells_replants_and_deposits() {
+        let mut world = World::lumberjack();
+        world.set_block(Coordinate::new(0, 0, 1), Some(Block::new("minecraft:chest")));
+        let (mut executor, handle) = start_with(world, 1000, |t| {
+            t.inventory[1] = Some(Item { count: 4, name: "minecraft:oak_sapling".to_owned() });
+        });
+        // The tree of the lumberjack world and an empty spot to its right
+        let mut farm = TreeFarm::new(Position::default(), 1, 2, 3);
+        farm.keep_logs = 2;
+
+        farm.round(&mut executor).unwrap();
+        assert_eq!(farm.spots[0].harvests, 1);
+        assert_eq!(farm.spots[1].harvests, 0);
+        // Saplings grow right away in the simulator
+        assert!(farm.spots.iter().all(|s| s.state == SpotState::Tree && s.planted.is_some()));
+
+        farm.round(&mut executor).unwrap();
+        assert_eq!(farm.spots.iter().map(|s| s.harvests).collect::<Vec<u32>>(), [2, 1]);
+
+        let turtle = finish(executor, handle);
+        let chest = turtle.world.block(Coordinate::new(0, 0, 1)).unwrap();
+        let deposited: u32 = chest.contents.iter().filter(|i| i.name == "minecraft:oak_log").map(|i| i.count as u32).sum();
+        assert_eq!(turtle.item_count("minecraft:oak_log"), 2);
+        assert_eq!(deposited, 3 * TREE_HEIGHT as u32 - 2);
+    }
+}
@@ -1,10 +1,11 @@
-use std::convert::TryInto;
+use std::convert::TryFrom;
 
 use json::JsonValue;
 
+use crate::decode::{DecodeError, expect_i64, expect_object, expect_str, expect_u8, field, field_with, slots};
 use crate::maneuver::Move;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Coordinate {
     x: i64,
     y: i64,
@@ -29,6 +30,18 @@ impl Coordinate {
     pub fn delta(&self, x: i64, y: i64, z: i64) -> Self {
         Coordinate::new(self.x + x, self.y + y, self.z + z)
     }
+
+    pub fn x(&self) -> i64 {
+        self.x
+    }
+
+    pub fn y(&self) -> i64 {
+        self.y
+    }
+
+    pub fn z(&self) -> i64 {
+        self.z
+    }
 }
 
 impl Default for Coordinate {
@@ -47,26 +60,21 @@ impl Into<JsonValue> for Coordinate {
     }
 }
 
-fn i64_from_json(v: &JsonValue) -> i64 {
-    v.as_i64().expect("Expected json number")
-}
+impl TryFrom<&JsonValue> for Coordinate {
+    type Error = DecodeError;
 
-impl From<&JsonValue> for Coordinate {
-    fn from(jv: &JsonValue) -> Self {
-        if let JsonValue::Object(o) = jv {
-            Self {
-                x: i64_from_json(&o["x"]),
-                y: i64_from_json(&o["y"]),
-                z: i64_from_json(&o["z"]),
-            }
-        } else {
-            panic!("Expected to get json object, got {}", jv)
-        }
+    fn try_from(jv: &JsonValue) -> Result<Self, Self::Error> {
+        expect_object(jv)?;
+        Ok(Self {
+            x: field_with(jv, "x", expect_i64)?,
+            y: field_with(jv, "y", expect_i64)?,
+            z: field_with(jv, "z", expect_i64)?,
+        })
     }
 }
 
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Direction {
     North,
     East,
@@ -112,29 +120,46 @@ impl Into<JsonValue> for Direction {
     }
 }
 
-impl From<&JsonValue> for Direction {
-    fn from(jv: &JsonValue) -> Self {
-        if jv.is_string() {
-            match jv.as_str().unwrap() {
-                "N" | "n" => Direction::North,
-                "E" | "e" => Direction::East,
-                "S" | "s" => Direction::South,
-                "W" | "w" => Direction::West,
-                s => panic!("Expected N, E, S or W, got {}", s)
-            }
-        } else {
-            panic!("Expected json string for direction enum, got {}", jv)
+impl TryFrom<&JsonValue> for Direction {
+    type Error = DecodeError;
+
+    fn try_from(jv: &JsonValue) -> Result<Self, Self::Error> {
+        match expect_str(jv)? {
+            "N" | "n" => Ok(Direction::North),
+            "E" | "e" => Ok(Direction::East),
+            "S" | "s" => Ok(Direction::South),
+            "W" | "w" => Ok(Direction::West),
+            _ => Err(DecodeError::expected("N, E, S or W", jv))
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Position {
     coordinate: Coordinate,
     direction: Direction,
 }
 
 impl Position {
+    pub fn new(coordinate: Coordinate, direction: Direction) -> Self {
+        Self { coordinate, direction }
+    }
+
+    pub fn coordinate(&self) -> Coordinate {
+        self.coordinate
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// The coordinate `count` blocks in front of this position
+    pub fn ahead(&self, count: i64) -> Coordinate {
+        let mut position = self.clone();
+        position.move_horizontal(count);
+        position.coordinate
+    }
+
     pub fn turn(&mut self, count: i8) {
         self.direction = self.direction.turn(count);
     }
@@ -151,6 +176,17 @@ impl Position {
     pub fn move_vertical(&mut self, count: i64) {
         self.coordinate.delta_mut(0, count, 0);
     }
+
+    pub fn apply_move(&mut self, move_type: &Move, count: i64) {
+        match move_type {
+            Move::Forward => self.move_horizontal(count),
+            Move::Backward => self.move_horizontal(-count),
+            Move::Up => self.move_vertical(count),
+            Move::Down => self.move_vertical(-count),
+            Move::Left => self.turn((-(count % 4)) as i8),
+            Move::Right => self.turn((count % 4) as i8),
+        }
+    }
 }
 
 impl Default for Position {
@@ -171,20 +207,19 @@ impl Into<JsonValue> for &Position {
     }
 }
 
-impl From<&JsonValue> for Position {
-    fn from(jv: &JsonValue) -> Self {
-        if let JsonValue::Object(o) = jv {
-            Self {
-                coordinate: (&o["coordinate"]).into(),
-                direction: (&o["direction"]).into(),
-            }
-        } else {
-            panic!("Expected json object for position, got {}", jv)
-        }
+impl TryFrom<&JsonValue> for Position {
+    type Error = DecodeError;
+
+    fn try_from(jv: &JsonValue) -> Result<Self, Self::Error> {
+        expect_object(jv)?;
+        Ok(Self {
+            coordinate: field(jv, "coordinate")?,
+            direction: field(jv, "direction")?,
+        })
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Item {
     pub count: u8,
     pub name: String,
@@ -199,20 +234,19 @@ impl Into<JsonValue> for &Item {
     }
 }
 
-impl From<&JsonValue> for Item {
-    fn from(jv: &JsonValue) -> Self {
-        if let JsonValue::Object(o) = jv {
-            Self {
-                count: o["c"].as_u8().expect("Expected number"),
-                name: o["n"].as_str().expect("Expected string").to_string(),
-            }
-        } else {
-            panic!("Expect json object got {}", jv)
-        }
+impl TryFrom<&JsonValue> for Item {
+    type Error = DecodeError;
+
+    fn try_from(jv: &JsonValue) -> Result<Self, Self::Error> {
+        expect_object(jv)?;
+        Ok(Self {
+            count: field_with(jv, "c", expect_u8)?,
+            name: field_with(jv, "n", expect_str)?.to_string(),
+        })
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Inventory {
     slots: [Option<Item>; 16]
 }
@@ -260,22 +294,21 @@ impl Into<JsonValue> for &Inventory {
     }
 }
 
-impl From<&JsonValue> for Inventory {
-    fn from(jv: &JsonValue) -> Self {
-        if let JsonValue::Array(v) = jv {
-            let slots: Vec<Option<Item>> = v.iter().map(|s| {
+impl TryFrom<&JsonValue> for Inventory {
+    type Error = DecodeError;
+
+    fn try_from(jv: &JsonValue) -> Result<Self, Self::Error> {
+        Ok(Self {
+            slots: slots(jv, |s| {
                 match s {
-                    JsonValue::Null => None,
-                    JsonValue::Object(_) => Some(s.into()),
-                    _ => panic!("Expected null or object, got {}", s),
+                    JsonValue::Null => Ok(None),
+                    // Lua can't put nil in an array, so empty slots may also be empty objects
+                    JsonValue::Object(o) if o.is_empty() => Ok(None),
+                    JsonValue::Object(_) => Item::try_from(s).map(Some),
+                    _ => Err(DecodeError::expected("null or object", s)),
                 }
-            }).collect();
-            Self {
-                slots: slots.try_into().expect("Expected 16 entries")
-            }
-        } else {
-            panic!("Expected json array, got {}", jv)
-        }
+            })?
+        })
     }
 }
 
@@ -312,19 +345,21 @@ impl Into<JsonValue> for &DeltaItem {
     }
 }
 
-impl From<&JsonValue> for DeltaItem {
-    fn from(jv: &JsonValue) -> Self {
+impl TryFrom<&JsonValue> for DeltaItem {
+    type Error = DecodeError;
+
+    fn try_from(jv: &JsonValue) -> Result<Self, Self::Error> {
         match jv {
-            JsonValue::Null => DeltaItem::Clear,
-            JsonValue::Number(_) => DeltaItem::CountChange(jv.as_u8().unwrap()),
+            JsonValue::Null => Ok(DeltaItem::Clear),
+            JsonValue::Number(_) => Ok(DeltaItem::CountChange(expect_u8(jv)?)),
             JsonValue::Object(_) => {
                 if jv.has_key("n") {
-                    DeltaItem::FullChange(Item::from(jv))
+                    Ok(DeltaItem::FullChange(Item::try_from(jv)?))
                 } else {
-                    DeltaItem::NoChange
+                    Ok(DeltaItem::NoChange)
                 }
             }
-            _ => panic!("Expected null, number or object, got {}", jv)
+            _ => Err(DecodeError::expected("null, number or object", jv))
         }
     }
 }
@@ -366,20 +401,17 @@ impl Into<JsonValue> for &DeltaInventory {
     }
 }
 
-impl From<&JsonValue> for DeltaInventory {
-    fn from(jv: &JsonValue) -> Self {
-        if let JsonValue::Array(v) = jv {
-            let slots: Vec<DeltaItem> = v.iter().map(Into::<DeltaItem>::into).collect();
-            Self {
-                slots: slots.try_into().expect("Expected 16 entries")
-            }
-        } else {
-            panic!("Expected json array, got {}", jv)
-        }
+impl TryFrom<&JsonValue> for DeltaInventory {
+    type Error = DecodeError;
+
+    fn try_from(jv: &JsonValue) -> Result<Self, Self::Error> {
+        Ok(Self {
+            slots: slots(jv, DeltaItem::try_from)?
+        })
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct TurtleState {
     pub label: String,
     pub position: Position,
@@ -389,14 +421,7 @@ pub struct TurtleState {
 
 impl TurtleState {
     pub fn move_turtle(&mut self, move_type: &Move, count: i64) {
-        match move_type {
-            Move::Forward => self.position.move_horizontal(count),
-            Move::Backward => self.position.move_horizontal(-count),
-            Move::Up => self.position.move_vertical(count),
-            Move::Down => self.position.move_vertical(-count),
-            Move::Left => self.position.turn((-count) as i8),
-            Move::Right => self.position.turn(count as i8),
-        }
+        self.position.apply_move(move_type, count);
     }
 }
 
@@ -422,18 +447,17 @@ impl Into<JsonValue> for &TurtleState {
     }
 }
 
-impl From<&JsonValue> for TurtleState {
-    fn from(jv: &JsonValue) -> Self {
-        if let JsonValue::Object(o) = jv {
-            Self {
-                label: o["label"].as_str().expect("Expected string").to_string(),
-                position: (&o["position"]).into(),
-                fuel_level: o["fuel_level"].as_i64().expect("Expected number"),
-                inventory: (&o["inventory"]).into(),
-            }
-        } else {
-            panic!("Expected json object got {}", jv)
-        }
+impl TryFrom<&JsonValue> for TurtleState {
+    type Error = DecodeError;
+
+    fn try_from(jv: &JsonValue) -> Result<Self, Self::Error> {
+        expect_object(jv)?;
+        Ok(Self {
+            label: field_with(jv, "label", expect_str)?.to_string(),
+            position: field(jv, "position")?,
+            fuel_level: field_with(jv, "fuel_level", expect_i64)?,
+            inventory: field(jv, "inventory")?,
+        })
     }
 }
 
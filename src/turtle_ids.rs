@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::{env, fs, io};
+
+use json::JsonValue;
+use rand::RngCore;
+use rand::rngs::OsRng;
+
+/// Hands out turtle IDs, each with a secret the turtle has to present when it connects. Remembers
+/// the last id given out and the secrets, so IDs stay unique and turtles can reconnect across
+/// server restarts. The state is stored as json in the file at `ID_FILE` (default
+/// `turtle_ids.json`).
+pub struct TurtleIds {
+    /// Where the ids are saved, `None` keeps them in memory only
+    path: Option<String>,
+    next_id: u32,
+    secrets: HashMap<u32, String>,
+}
+
+impl Default for TurtleIds {
+    fn default() -> Self {
+        Self { path: None, next_id: 1, secrets: HashMap::new() }
+    }
+}
+
+impl TurtleIds {
+    pub fn load() -> io::Result<Self> {
+        let path = env::var("ID_FILE").unwrap_or(String::from("turtle_ids.json"));
+        let (next_id, secrets) = match fs::read_to_string(&path) {
+            Ok(s) => json::parse(s.as_str()).ok()
+                .and_then(|jv| Some((jv["next_id"].as_u32()?, Self::decode_secrets(&jv["secrets"])?)))
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Could not parse id file {}", path)))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (1, HashMap::new()),
+            Err(e) => return Err(e),
+        };
+        Ok(Self { path: Some(path), next_id, secrets })
+    }
+
+    /// Reserves a new id with a fresh secret and persists both before handing them out
+    pub fn new_id(&mut self) -> io::Result<(u32, String)> {
+        let id = self.next_id;
+        let secret = new_secret();
+        self.next_id += 1;
+        self.secrets.insert(id, secret.clone());
+        if let Err(e) = self.save() {
+            self.next_id = id;
+            self.secrets.remove(&id);
+            return Err(e);
+        }
+        Ok((id, secret))
+    }
+
+    /// The secret of an id that is not handed out by `new_id`, like the ones of simulated turtles.
+    /// Makes one up if the id has none yet.
+    #[cfg(any(test, feature = "simulator"))]
+    pub fn secret(&mut self, id: u32) -> io::Result<String> {
+        if let Some(secret) = self.secrets.get(&id) {
+            return Ok(secret.clone());
+        }
+        let secret = new_secret();
+        self.secrets.insert(id, secret.clone());
+        if let Err(e) = self.save() {
+            self.secrets.remove(&id);
+            return Err(e);
+        }
+        Ok(secret)
+    }
+
+    /// Whether the secret belongs to the id. Ids we never handed out have no valid secret.
+    pub fn verify(&self, id: u32, secret: &str) -> bool {
+        match self.secrets.get(&id) {
+            Some(expected) => same_secret(expected, secret),
+            None => false,
+        }
+    }
+
+    fn decode_secrets(jv: &JsonValue) -> Option<HashMap<u32, String>> {
+        match jv {
+            // Written before turtles had secrets
+            JsonValue::Null => Some(HashMap::new()),
+            JsonValue::Object(o) => o.iter()
+                .map(|(id, secret)| Some((id.parse().ok()?, secret.as_str()?.to_owned())))
+                .collect(),
+            _ => None,
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut secrets = JsonValue::new_object();
+        for (id, secret) in &self.secrets {
+            secrets[id.to_string()] = secret.as_str().into();
+        }
+        let jv: JsonValue = json::object! {
+            next_id: self.next_id,
+            secrets: secrets,
+        };
+        fs::write(path, json::stringify_pretty(jv, 4))
+    }
+}
+
+/// The token operators need for the REST api, the event stream and the dashboard. Read from
+/// `OPERATOR_TOKEN`, without it a new one is made up and printed at startup. It goes into the link
+/// of the dashboard as is, so it should be letters and digits only.
+#[derive(Clone)]
+pub struct OperatorToken(String);
+
+impl OperatorToken {
+    #[cfg(test)]
+    pub fn new(token: String) -> Self {
+        Self(token)
+    }
+
+    pub fn from_env() -> Self {
+        match env::var("OPERATOR_TOKEN") {
+            Ok(token) if !token.is_empty() => Self(token),
+            _ => {
+                let token = new_secret();
+                println!("No OPERATOR_TOKEN set, open the dashboard at /?token={}", token);
+                Self(token)
+            }
+        }
+    }
+
+    pub fn verify(&self, token: &str) -> bool {
+        same_secret(&self.0, token)
+    }
+}
+
+/// Compares every byte, so the time taken does not tell how much of the secret was right
+fn same_secret(expected: &str, secret: &str) -> bool {
+    expected.len() == secret.len()
+        && expected.bytes().zip(secret.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn new_secret() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,481 @@
+use std::{env, fs};
+use std::convert::TryFrom;
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use std::sync::mpsc;
+
+use hyper::{Body, Method, Request, Response, StatusCode};
+use hyper::header::{AUTHORIZATION, CACHE_CONTROL, CONTENT_TYPE};
+use json::JsonValue;
+
+use crate::crafting::Recipe;
+use crate::executor::{ExecutorCommand, ExecutorRequest, ExecutorResponse, TaskExecutor};
+use crate::fleet::Fleet;
+use crate::maneuver::Maneuver;
+use crate::turtle_ids::{OperatorToken, TurtleIds};
+
+const FILES_PREFIX: &str = "/files/replicca/";
+const BLOCKS_PATH: &str = "/blocks";
+const TASKS_PATH: &str = "/tasks";
+const TURTLES_PREFIX: &str = "/turtles";
+const NEW_ID_PATH: &str = "/newId";
+const TEXT: &str = "text/plain; charset=utf-8";
+const JSON: &str = "application/json";
+const HTML: &str = "text/html; charset=utf-8";
+/// The dashboard is built into the server, so it works wherever the server runs
+const DASHBOARD: &str = include_str!("dashboard.html");
+const EVENTS_PATH: &str = "/events";
+const EVENT_STREAM: &str = "text/event-stream";
+/// How often an idle event stream gets a comment, so proxies keep it open and closed streams are noticed
+const EVENTS_KEEP_ALIVE: Duration = Duration::from_secs(15);
+/// How long a move or craft waits for the executor, which may first finish the task it is running
+const REPLY_TIMEOUT: Duration = Duration::from_secs(300);
+
+fn text(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, TEXT)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn not_found() -> Response<Body> {
+    text(StatusCode::NOT_FOUND, String::from("Not found"))
+}
+
+fn json_response(status: StatusCode, body: JsonValue) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, JSON)
+        .body(Body::from(json::stringify(body)))
+        .unwrap()
+}
+
+fn json_error(status: StatusCode, message: String) -> Response<Body> {
+    json_response(status, json::object! { error: message })
+}
+
+/// Serves a plain (non websocket) http request that arrived at the websocket listener. Only what
+/// turtles fetch to install themselves is open, everything else needs the operator token.
+pub async fn handle_http_request(request: Request<Body>, ids: Arc<Mutex<TurtleIds>>, operator: &OperatorToken, fleet: Fleet) -> Response<Body> {
+    let method = request.method().clone();
+    let uri = request.uri().clone();
+    let for_turtles = uri.path() == NEW_ID_PATH || uri.path().starts_with(FILES_PREFIX);
+    let response = if !for_turtles && !operator_token(&request).is_some_and(|t| operator.verify(t)) {
+        json_error(StatusCode::UNAUTHORIZED, String::from("Missing or wrong operator token"))
+    } else if uri.path().starts_with(TURTLES_PREFIX) {
+        match read_json(request).await {
+            // Requests to the fleet can block until the turtle answers
+            Ok(body) => {
+                let (method, uri) = (method.clone(), uri.clone());
+                tokio::task::spawn_blocking(move || route_fleet(&method, uri.path(), body, &fleet)).await
+                    .unwrap_or_else(|e| json_error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+            }
+            Err(response) => response,
+        }
+    } else if uri.path() == EVENTS_PATH {
+        event_stream(&method, uri.query(), &fleet)
+    } else {
+        route(&method, uri.path(), &ids, &fleet)
+    };
+    println!("{} {} -> {}", method, uri, response.status().as_u16());
+    response
+}
+
+/// The token in the `Authorization: Bearer` header, or in `?token=` for the dashboard link and its
+/// event stream, which can not set headers
+fn operator_token(request: &Request<Body>) -> Option<&str> {
+    let header = request.headers().get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+    header.or_else(|| request.uri().query()?.split('&').find_map(|p| p.strip_prefix("token=")))
+}
+
+/// Streams the events of the turtles in `?turtles=1,2`, or of all turtles, as server-sent events.
+/// Each event is named after its kind (`position_update`, `task_start`...) and has the json from
+/// `Fleet::publish` as data.
+fn event_stream(method: &Method, query: Option<&str>, fleet: &Fleet) -> Response<Body> {
+    if method != Method::GET {
+        return text(StatusCode::METHOD_NOT_ALLOWED, format!("Method {} not allowed", method));
+    }
+    let turtles = query.unwrap_or("").split('&')
+        .find_map(|p| p.strip_prefix("turtles="))
+        .map(|ids| ids.split(',').map(|id| id.parse::<u32>()).collect::<Result<HashSet<u32>, _>>());
+    let turtles = match turtles {
+        Some(Ok(turtles)) => Some(turtles),
+        Some(Err(_)) => return json_error(StatusCode::BAD_REQUEST, String::from("turtles must be a comma separated list of ids")),
+        None => None,
+    };
+    let mut events = fleet.subscribe(turtles);
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut keep_alive = tokio::time::interval(EVENTS_KEEP_ALIVE);
+        loop {
+            let chunk = tokio::select! {
+                event = events.recv() => match event {
+                    Some(event) => format!("event: {}\ndata: {}\n\n", event["event"].as_str().unwrap_or(""), event.dump()),
+                    None => break,
+                },
+                _ = keep_alive.tick() => String::from(": keep-alive\n\n"),
+            };
+            // Fails once the client is gone, dropping `events` unsubscribes
+            if sender.send_data(chunk.into()).await.is_err() {
+                break;
+            }
+        }
+    });
+    Response::builder()
+        .header(CONTENT_TYPE, EVENT_STREAM)
+        .header(CACHE_CONTROL, "no-cache")
+        .body(body)
+        .unwrap()
+}
+
+/// Reads the body as json, an empty body is `null`
+async fn read_json(request: Request<Body>) -> Result<JsonValue, Response<Body>> {
+    let bytes = hyper::body::to_bytes(request.into_body()).await
+        .map_err(|e| json_error(StatusCode::BAD_REQUEST, format!("Could not read body: {}", e)))?;
+    if bytes.is_empty() {
+        return Ok(JsonValue::Null);
+    }
+    std::str::from_utf8(&bytes).ok()
+        .and_then(|s| json::parse(s).ok())
+        .ok_or_else(|| json_error(StatusCode::BAD_REQUEST, String::from("Body is not valid json")))
+}
+
+fn route(method: &Method, path: &str, ids: &Arc<Mutex<TurtleIds>>, fleet: &Fleet) -> Response<Body> {
+    if method != Method::GET {
+        return text(StatusCode::METHOD_NOT_ALLOWED, format!("Method {} not allowed", method));
+    }
+    if path == "/" {
+        Response::builder()
+            .header(CONTENT_TYPE, HTML)
+            .body(Body::from(DASHBOARD))
+            .unwrap()
+    } else if path == BLOCKS_PATH {
+        // Everything the turtles explored, for the map of the dashboard
+        json_response(StatusCode::OK, fleet.world().blocks_json())
+    } else if path == TASKS_PATH {
+        // The tasks with their arguments and questions, so clients can build requests for them
+        json_response(StatusCode::OK, fleet.tasks().list().map(JsonValue::from).collect::<Vec<JsonValue>>().into())
+    } else if path == NEW_ID_PATH {
+        match ids.lock().unwrap().new_id() {
+            // The turtle keeps both, it needs the secret to connect
+            Ok((id, secret)) => text(StatusCode::OK, format!("{}\n{}", id, secret)),
+            Err(e) => text(StatusCode::INTERNAL_SERVER_ERROR, format!("Could not allocate id: {}", e)),
+        }
+    } else if let Some(file) = path.strip_prefix(FILES_PREFIX) {
+        serve_file(file)
+    } else {
+        not_found()
+    }
+}
+
+/// The fleet API, everything takes and returns json:
+///
+/// - `GET /turtles` and `GET /turtles/{id}`: the turtles with their state
+/// - `POST /turtles/{id}/task` `{"task": "refuel_logs", "arguments": {"slot": 1, "count": 2}, "preempt": false}`:
+///   queues a task, the arguments are checked against the task as `GET /tasks` lists it
+/// - `POST /turtles/{id}/move` `{"moves": "f3r", "preempt": false}`: moves and waits for the result. A
+///   failed move has the `error`, its `reason` (`fuel`, `obstacle` or `other`) and the `index` of the failed step
+/// - `POST /turtles/{id}/craft` `{"recipe": {"output": "minecraft:chest", "pattern": ["PPP", "P P", "PPP"],
+///   "key": {"P": "minecraft:oak_planks"}}, "crafts": 1, "preempt": false}`: lays out the crafting grid,
+///   crafts and waits for the result. Without `crafts` it crafts as often as it can.
+/// - `POST /turtles/{id}/cancel`: cancels the running task
+/// - `GET /turtles/{id}/questions`: questions of the running task no handler could answer, with
+///   their payload and the type of answer they expect
+/// - `POST /turtles/{id}/questions/{question}` `{"answer": ...}`: answers a question
+///
+/// Moves and crafts answer 504 when the executor did not get to them within `REPLY_TIMEOUT`.
+fn route_fleet(method: &Method, path: &str, body: JsonValue, fleet: &Fleet) -> Response<Body> {
+    let segments: Vec<&str> = path[TURTLES_PREFIX.len()..].split('/').filter(|s| !s.is_empty()).collect();
+    if segments.is_empty() {
+        return match *method {
+            Method::GET => json_response(StatusCode::OK, fleet.list().iter().map(Into::into).collect::<Vec<JsonValue>>().into()),
+            _ => method_not_allowed(method),
+        };
+    }
+    let id = match segments[0].parse::<u32>() {
+        Ok(id) if fleet.get(id).is_some() => id,
+        _ => return json_error(StatusCode::NOT_FOUND, format!("No turtle {}", segments[0])),
+    };
+    let preempt = body["preempt"].as_bool().unwrap_or(false);
+
+    match (method, &segments[1..]) {
+        (&Method::GET, []) => json_response(StatusCode::OK, fleet.get(id).as_ref().unwrap().into()),
+        (&Method::POST, ["task"]) => {
+            let task = match body["task"].as_str().map(|code| fleet.tasks().build(code, body["arguments"].clone())) {
+                Some(Ok(task)) => task,
+                Some(Err(e)) => return json_error(StatusCode::BAD_REQUEST, e),
+                None => return json_error(StatusCode::BAD_REQUEST, String::from("Expected {\"task\": name}")),
+            };
+            // Tasks run for a long time, the result shows in the state of the turtle
+            match send(fleet, id, ExecutorCommand::Task(task, TaskExecutor::null_question_handler), preempt) {
+                Ok(_) => json_response(StatusCode::ACCEPTED, json::object! { queued: true }),
+                Err(e) => json_error(StatusCode::CONFLICT, e),
+            }
+        }
+        (&Method::POST, ["move"]) => {
+            let maneuver = match body["moves"].as_str().map(str::parse::<Maneuver>) {
+                Some(Ok(maneuver)) => maneuver,
+                Some(Err(e)) => return json_error(StatusCode::BAD_REQUEST, e.to_string()),
+                None => return json_error(StatusCode::BAD_REQUEST, String::from("Expected {\"moves\": move string}")),
+            };
+            match request(fleet, id, ExecutorCommand::Move(maneuver), preempt, REPLY_TIMEOUT) {
+                Ok(ExecutorResponse::Move(Ok(()))) => json_response(StatusCode::OK, json::object! { ok: true }),
+                Ok(ExecutorResponse::Move(Err((error, index)))) => json_response(StatusCode::OK, json::object! {
+                    ok: false,
+                    error: error.message,
+                    reason: error.failure.code(),
+                    index: index,
+                }),
+                Ok(_) => json_error(StatusCode::INTERNAL_SERVER_ERROR, String::from("Unexpected response to move")),
+                Err((status, e)) => json_error(status, e),
+            }
+        }
+        (&Method::POST, ["craft"]) => {
+            let recipe = match Recipe::try_from(&body["recipe"]) {
+                Ok(recipe) => recipe,
+                Err(e) => return json_error(StatusCode::BAD_REQUEST, format!("Invalid recipe: {}", e)),
+            };
+            let crafts = match &body["crafts"] {
+                JsonValue::Null => None,
+                crafts => match crafts.as_u8() {
+                    Some(crafts) => Some(crafts),
+                    None => return json_error(StatusCode::BAD_REQUEST, String::from("Expected crafts to be a number")),
+                },
+            };
+            match request(fleet, id, ExecutorCommand::Craft(recipe, crafts), preempt, REPLY_TIMEOUT) {
+                Ok(ExecutorResponse::Craft(Ok(crafted))) => json_response(StatusCode::OK, json::object! { ok: true, crafted: crafted }),
+                Ok(ExecutorResponse::Craft(Err(error))) => json_response(StatusCode::OK, json::object! { ok: false, error: error }),
+                Ok(_) => json_error(StatusCode::INTERNAL_SERVER_ERROR, String::from("Unexpected response to craft")),
+                Err((status, e)) => json_error(status, e),
+            }
+        }
+        (&Method::POST, ["cancel"]) => {
+            fleet.drop_questions(id);
+            match send(fleet, id, ExecutorCommand::Cancel, true) {
+                Ok(_) => json_response(StatusCode::ACCEPTED, json::object! { cancelling: fleet.get(id).and_then(|r| r.current_task) }),
+                Err(e) => json_error(StatusCode::CONFLICT, e),
+            }
+        }
+        (&Method::GET, ["tasks"]) => {
+            let record = fleet.get(id).unwrap();
+            json_response(StatusCode::OK, json::object! {
+                stack: record.task_stack.iter().map(JsonValue::from).collect::<Vec<JsonValue>>(),
+                history: record.task_history.iter().map(JsonValue::from).collect::<Vec<JsonValue>>(),
+            })
+        }
+        (&Method::GET, ["questions"]) => {
+            let task = fleet.get(id).and_then(|r| r.current_task);
+            let questions: Vec<JsonValue> = fleet.questions(id).into_iter()
+                .map(|(id, question)| {
+                    let answer = task.as_deref().and_then(|t| fleet.tasks().question(t, question.code.as_str()))
+                        .map(|q| JsonValue::from(&q.answer));
+                    json::object! { id: id, question: question.code, payload: question.payload, answer: answer }
+                })
+                .collect();
+            json_response(StatusCode::OK, questions.into())
+        }
+        (&Method::POST, ["questions", question]) => {
+            let question = match question.parse::<u32>() {
+                Ok(question) => question,
+                Err(_) => return json_error(StatusCode::NOT_FOUND, format!("No question {}", question)),
+            };
+            if let Err(e) = fleet.check_answer(id, question, &body["answer"]) {
+                return json_error(StatusCode::BAD_REQUEST, e);
+            }
+            match fleet.answer(id, question, body["answer"].clone()) {
+                Ok(()) => json_response(StatusCode::OK, json::object! { answered: true }),
+                Err(e) => json_error(StatusCode::NOT_FOUND, e),
+            }
+        }
+        (_, ["task"]) | (_, ["tasks"]) | (_, ["move"]) | (_, ["craft"]) | (_, ["cancel"]) | (_, ["questions"]) | (_, ["questions", _]) | (_, []) => method_not_allowed(method),
+        _ => json_error(StatusCode::NOT_FOUND, format!("Not found: {}", path)),
+    }
+}
+
+fn method_not_allowed(method: &Method) -> Response<Body> {
+    json_error(StatusCode::METHOD_NOT_ALLOWED, format!("Method {} not allowed", method))
+}
+
+/// Queues a request on the executor of the turtle, the receiver gets the response
+fn send(fleet: &Fleet, id: u32, command: ExecutorCommand, preempt: bool) -> Result<mpsc::Receiver<Result<ExecutorResponse, String>>, String> {
+    let (reply, response) = mpsc::channel();
+    fleet.request(id, ExecutorRequest { command, preempt, reply })?;
+    Ok(response)
+}
+
+/// Queues a request and waits for the response. Gives up with 504 after `timeout`, the request
+/// stays queued and may still run.
+fn request(fleet: &Fleet, id: u32, command: ExecutorCommand, preempt: bool, timeout: Duration) -> Result<ExecutorResponse, (StatusCode, String)> {
+    let response = send(fleet, id, command, preempt).map_err(|e| (StatusCode::CONFLICT, e))?;
+    match response.recv_timeout(timeout) {
+        Ok(Ok(response)) => Ok(response),
+        Ok(Err(e)) => Err((StatusCode::CONFLICT, e)),
+        Err(mpsc::RecvTimeoutError::Timeout) => Err((StatusCode::GATEWAY_TIMEOUT, format!("Turtle {} did not respond within {} seconds", id, timeout.as_secs()))),
+        Err(mpsc::RecvTimeoutError::Disconnected) => Err((StatusCode::CONFLICT, format!("Executor of turtle {} stopped before responding", id))),
+    }
+}
+
+/// Serves a file from the lua scripts directory (`FILES_DIR`, default `lua-scripts`)
+fn serve_file(file: &str) -> Response<Body> {
+    let root = env::var("FILES_DIR").unwrap_or(String::from("lua-scripts"));
+    match resolve_file(Path::new(&root), file).and_then(|path| fs::read(path).ok()) {
+        Some(body) => Response::builder()
+            .header(CONTENT_TYPE, TEXT)
+            .body(Body::from(body))
+            .unwrap(),
+        None => not_found(),
+    }
+}
+
+/// Joins a request path onto the root, refusing anything that could escape it
+fn resolve_file(root: &Path, file: &str) -> Option<PathBuf> {
+    let relative = Path::new(file);
+    if file.is_empty() || !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+        return None;
+    }
+    let path = root.join(relative);
+    if path.is_file() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpStream;
+    use std::thread;
+
+    use crate::fleet::Fleet;
+    use crate::simulator::World;
+    use crate::simulator::testing::{http, http_with, start_listening, OPERATOR};
+    use crate::task_registry::ReplantAnswer;
+
+    use super::*;
+
+    #[test]
+    fn busy_executor_times_out() {
+        let fleet = Fleet::default();
+        let (requests, queued) = mpsc::channel();
+        fleet.attach(1, requests);
+
+        let command = ExecutorCommand::Move("f".parse::<Maneuver>().unwrap());
+        match request(&fleet, 1, command, false, Duration::from_millis(50)) {
+            Err((status, _)) => assert_eq!(status, StatusCode::GATEWAY_TIMEOUT),
+            Ok(_) => panic!("Nobody serves the executor, expected a timeout"),
+        }
+        // The request is still queued for when the executor gets to it
+        assert!(queued.try_recv().is_ok());
+    }
+
+    #[test]
+    fn stopped_executor_is_a_conflict() {
+        let fleet = Fleet::default();
+        let (requests, queued) = mpsc::channel();
+        fleet.attach(1, requests);
+        drop(queued);
+
+        match request(&fleet, 1, ExecutorCommand::Cancel, false, Duration::from_millis(50)) {
+            Err((status, _)) => assert_eq!(status, StatusCode::CONFLICT),
+            Ok(_) => panic!("The executor is gone, expected an error"),
+        }
+    }
+
+    #[test]
+    fn rest_api_lists_and_moves_turtles() {
+        let (mut executor, _handle, address) = start_listening(World::new(), 100, |_| {});
+        thread::spawn(move || { let _ = executor.serve_requests(); });
+
+        let (status, moved) = http(address, "POST", "/turtles/1/move", json::object! { moves: "f2" });
+        assert_eq!((status, moved), (200, json::object! { ok: true }));
+        let (status, turtles) = http(address, "GET", "/turtles", JsonValue::Null);
+        assert_eq!(status, 200);
+        assert_eq!(turtles[0]["id"], 1);
+        assert_eq!(turtles[0]["state"]["position"]["coordinate"]["z"], -2);
+        let (status, blocks) = http(address, "GET", "/blocks", JsonValue::Null);
+        assert_eq!(status, 200);
+        assert!(blocks.members().any(|b| b["z"] == -1 && b["block"] == "minecraft:air"));
+
+        assert_eq!(http(address, "POST", "/turtles/1/move", json::object! { moves: "x" }).0, 400);
+        assert_eq!(http(address, "POST", "/turtles/1/task", json::object! { task: "dance" }).0, 400);
+        assert_eq!(http(address, "GET", "/turtles/2", JsonValue::Null).0, 404);
+    }
+
+    #[test]
+    fn only_turtle_routes_work_without_the_operator_token() {
+        let (mut executor, _handle, address) = start_listening(World::new(), 100, |_| {});
+        thread::spawn(move || { let _ = executor.serve_requests(); });
+
+        for path in ["/", "/events", "/blocks", "/tasks", "/turtles", "/turtles/1/tasks"] {
+            assert_eq!(http_with(address, None, "GET", path, JsonValue::Null).0, 401, "{}", path);
+            assert_eq!(http_with(address, Some("operato"), "GET", path, JsonValue::Null).0, 401, "{}", path);
+        }
+        assert_eq!(http_with(address, None, "POST", "/turtles/1/move", json::object! { moves: "f" }).0, 401);
+        // The dashboard is opened with the token in its link
+        assert_eq!(http_with(address, None, "GET", &format!("/?token={}", OPERATOR), JsonValue::Null).0, 200);
+        assert_eq!(http(address, "GET", "/", JsonValue::Null).0, 200);
+        // What turtles fetch to install themselves stays open
+        assert_eq!(http_with(address, None, "GET", "/newId", JsonValue::Null).0, 200);
+        assert_eq!(http_with(address, None, "GET", "/files/replicca/websocket.lua", JsonValue::Null).0, 200);
+    }
+
+    #[test]
+    fn event_stream_follows_the_chosen_turtles() {
+        let (mut executor, _handle, address) = start_listening(World::new(), 100, |_| {});
+        thread::spawn(move || { let _ = executor.serve_requests(); });
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET /events?turtles=1&token={} HTTP/1.1\r\nHost: {}\r\n\r\n", OPERATOR, address).unwrap();
+        let mut events = BufReader::new(stream);
+        let mut line = String::new();
+        // Subscribed once the headers arrived
+        while line != "\r\n" {
+            line.clear();
+            events.read_line(&mut line).unwrap();
+        }
+        assert_eq!(http(address, "GET", "/events?turtles=x", JsonValue::Null).0, 400);
+
+        assert_eq!(http(address, "POST", "/turtles/1/move", json::object! { moves: "f" }).0, 200);
+        let event = loop {
+            line.clear();
+            events.read_line(&mut line).unwrap();
+            if let Some(data) = line.trim_end().strip_prefix("data: ") {
+                let event = json::parse(data).unwrap();
+                if event["event"] == "position_update" {
+                    break event;
+                }
+            }
+        };
+        assert_eq!(event["turtle"], 1);
+        assert_eq!(event["b"]["coordinate"]["z"], -1);
+    }
+
+    #[test]
+    fn questions_without_handler_go_to_the_operator() {
+        let (mut executor, _handle, address) = start_listening(World::lumberjack(), 100, |_| {});
+        thread::spawn(move || { let _ = executor.serve_requests(); });
+
+        assert_eq!(http(address, "POST", "/turtles/1/task", json::object! { task: "fell" }).0, 202);
+        let question = loop {
+            let (_, questions) = http(address, "GET", "/turtles/1/questions", JsonValue::Null);
+            if !questions.is_empty() {
+                break questions[0].clone();
+            }
+            thread::sleep(Duration::from_millis(50));
+        };
+        assert_eq!(question["question"], "replant");
+        assert!(question["payload"]["position"]["coordinate"].is_object());
+        let path = format!("/turtles/1/questions/{}", question["id"]);
+        assert_eq!(http(address, "POST", path.as_str(), json::object! { answer: json::array![true, "yes"] }).0, 400);
+        assert_eq!(http(address, "POST", path.as_str(), json::object! { answer: ReplantAnswer::skip() }).0, 200);
+        while !http(address, "GET", "/turtles/1", JsonValue::Null).1["task"].is_null() {
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
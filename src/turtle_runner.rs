@@ -1,35 +1,113 @@
-use json::JsonValue;
-
-use crate::executor::{Task, TaskExecutor};
-use crate::turtle_websocket::{TaskError, UpEvent};
+use crate::builder::Build;
+use crate::executor::TaskExecutor;
+use crate::quarry::Quarry;
+use crate::strip_mine::StripMine;
+use crate::task_registry::{ReplantAnswer, Task};
+use crate::tree_farm::TreeFarm;
+use crate::turtle_websocket::{TaskError, TaskQuestion, UpEvent};
+use std::env;
 use std::error::Error;
+use std::str::FromStr;
+
+/// What a runner has its turtle do, chosen with `RUNNER` (default `lumberjack`)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RunnerKind {
+    /// Fells the tree in front until it has 16 logs
+    Lumberjack,
+    /// Tends a `TreeFarm` around where the turtle starts, forever
+    TreeFarm,
+    /// Digs out a `Quarry` in front of where the turtle starts
+    Quarry,
+    /// Digs a `StripMine` straight ahead of where the turtle starts, mining every vein it finds
+    StripMine,
+    /// Builds the schematic at `BUILD_SCHEMATIC` in front of where the turtle starts
+    Build,
+}
+
+impl RunnerKind {
+    pub fn from_env() -> Result<Self, String> {
+        env::var("RUNNER").map_or(Ok(RunnerKind::Lumberjack), |s| s.parse())
+    }
+}
+
+impl FromStr for RunnerKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lumberjack" => Ok(RunnerKind::Lumberjack),
+            "tree_farm" => Ok(RunnerKind::TreeFarm),
+            "quarry" => Ok(RunnerKind::Quarry),
+            "strip_mine" => Ok(RunnerKind::StripMine),
+            "build" => Ok(RunnerKind::Build),
+            _ => Err(format!("Unknown runner {}, expected lumberjack, tree_farm, quarry, strip_mine or build", s)),
+        }
+    }
+}
 
 pub struct Runner {
     pub executor: TaskExecutor,
+    pub kind: RunnerKind,
 }
 
 impl Runner {
     pub fn run(&mut self) -> Result<(), Box<dyn Error>> {
-        let question_handler = |q: String, e: &mut TaskExecutor| {
-            match q.as_str() {
-                "replant" => {
-                    if let Some((_, sapling_slot)) = e.turtle.inventory.find(|i| i.name.ends_with("sapling") && i.count > 0) {
-                        json::array![
-                            true,
-                            true,
-                            sapling_slot
-                        ]
-                    } else {
-                        json::array![
-                            false
-                        ]
-                    }
-                }
-                _ => JsonValue::from(false),
+        match self.kind {
+            RunnerKind::Lumberjack => self.lumberjack(),
+            RunnerKind::TreeFarm => self.tree_farm(),
+            RunnerKind::Quarry => self.quarry(),
+            RunnerKind::StripMine => self.strip_mine(),
+            RunnerKind::Build => self.build(),
+        }
+    }
+
+    /// Starts the build in front of the current position the first time, and resumes it after that
+    fn build(&mut self) -> Result<(), Box<dyn Error>> {
+        self.executor.refresh_state()??;
+        let anchor = self.executor.turtle.position.clone();
+        let mut build = Build::load(self.executor.connection.id(), &anchor)?;
+        build.run(&mut self.executor)
+    }
+
+    fn strip_mine(&mut self) -> Result<(), Box<dyn Error>> {
+        self.executor.refresh_state()??;
+        let mut mine = StripMine::from_env(self.executor.turtle.position.clone());
+        mine.run(&mut self.executor)?;
+        println!("Strip mine yielded {:?}", mine.yields);
+        Ok(())
+    }
+
+    /// Starts the quarry in front of the current position the first time, and resumes it after that
+    fn quarry(&mut self) -> Result<(), Box<dyn Error>> {
+        self.executor.refresh_state()??;
+        let start = self.executor.turtle.position.clone();
+        let mut quarry = Quarry::load(self.executor.connection.id(), &start)?;
+        quarry.run(&mut self.executor)
+    }
+
+    /// Lays out the farm around the current position the first time, and picks up where it left
+    /// off after that
+    fn tree_farm(&mut self) -> Result<(), Box<dyn Error>> {
+        self.executor.refresh_state()??;
+        let home = self.executor.turtle.position.clone();
+        let mut farm = TreeFarm::load(self.executor.connection.id(), &home)?;
+        farm.run(&mut self.executor)
+    }
+
+    fn lumberjack(&mut self) -> Result<(), Box<dyn Error>> {
+        let question_handler = |q: &TaskQuestion, e: &mut TaskExecutor| {
+            match q.code.as_str() {
+                "replant" => Some(match e.turtle.inventory.find(|i| i.name.ends_with("sapling") && i.count > 0) {
+                    Some((_, sapling_slot)) => (&ReplantAnswer { wait: true, sapling_slot }).into(),
+                    None => ReplantAnswer::skip(),
+                }),
+                _ => None,
             }
         };
-        if !self.executor.execute(Task::FirstTree, TaskExecutor::default_event_handler, question_handler)? {
-            return Ok(())
+        while !self.executor.execute(Task::first_tree(), TaskExecutor::default_event_handler, question_handler)? {
+            if !self.interrupted() {
+                return Ok(())
+            }
         }
 
         let event_handler = |e: UpEvent, exc: &mut TaskExecutor| {
@@ -37,7 +115,7 @@ impl Runner {
                 UpEvent::TaskError(e) => match e {
                     TaskError::FuelLow => {
                         if let Some((_, log_slot)) = exc.turtle.inventory.find(|i| i.name.ends_with("log") && i.count > 0) {
-                            exc.execute(Task::RefuelLogs(log_slot as u8, 2), TaskExecutor::default_event_handler, TaskExecutor::null_question_handler).unwrap()
+                            exc.execute(Task::refuel_logs(log_slot as u8, 2), TaskExecutor::default_event_handler, TaskExecutor::null_question_handler).unwrap()
                         } else {
                             false
                         }
@@ -58,10 +136,51 @@ impl Runner {
                 break;
             }
 
-            if !self.executor.execute(Task::Fell, event_handler, question_handler)? {
+            if !self.executor.execute(Task::fell(), event_handler, question_handler)? && !self.interrupted() {
                 return Ok(())
             }
         };
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Whether the last task only failed because the turtle rebooted, so it can be started again
+    fn interrupted(&mut self) -> bool {
+        match self.executor.take_interrupted().last() {
+            Some(run) => {
+                println!("Starting task {} again after a reboot", run.task.code());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simulator::World;
+    use crate::simulator::testing::{finish, start};
+    use crate::world_map::KnownBlock;
+
+    use super::*;
+
+    #[test]
+    fn runner_fells_trees_until_it_has_enough_logs() {
+        // Enough fuel to skip refuel_logs, which only burns what ends up in the first slot
+        let (executor, handle) = start(World::lumberjack(), 1000, None);
+        let mut runner = Runner { executor, kind: RunnerKind::Lumberjack };
+
+        runner.run().unwrap();
+
+        let logs: u16 = runner.executor.turtle.inventory.find_all(|i| i.name.ends_with("log"))
+            .map(|(i, _)| i.count as u16)
+            .sum();
+        assert!(logs >= 16, "only collected {} logs", logs);
+        {
+            let world = runner.executor.world();
+            assert!(world.find(|b| b.name().is_some_and(|n| n.ends_with("_log"))).count() > 0);
+            assert_eq!(world.get(runner.executor.turtle.position.coordinate()), Some(&KnownBlock::Air));
+        }
+        let turtle = finish(runner.executor, handle);
+        assert_eq!(turtle.item_count("minecraft:oak_log") as u16, logs);
+    }
+}
@@ -1,35 +1,51 @@
 use std::{env, thread};
-use std::error::Error;
+use std::collections::{HashMap, VecDeque};
+use std::convert::{Infallible, TryFrom};
+use std::fmt;
+use std::net::TcpListener;
 use std::num::Wrapping;
-use std::sync::mpsc;
+use std::sync::{Arc, mpsc, Mutex};
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use hyper::http::uri;
+use futures_util::{SinkExt, StreamExt};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use hyper::header::{CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::upgrade::Upgraded;
 use json::JsonValue;
-use websocket::{Message, OwnedMessage, WebSocketError, WebSocketResult};
-use websocket::server::NoTlsAcceptor;
-use websocket::server::sync::AcceptResult;
-use websocket::stream::sync::TcpStream;
-use websocket::sync::{Client, Server};
-use websocket::sync::server::Upgrade;
-
-use crate::executor::Task;
+use rand::Rng;
+use tokio::sync::mpsc as async_mpsc;
+use tokio_tungstenite::WebSocketStream;
+use tungstenite::handshake::derive_accept_key;
+use tungstenite::protocol::Role;
+use tungstenite::Message;
+
+use crate::decode::{DecodeError, expect_bool, expect_object, expect_str, expect_usize, field, field_with};
+use crate::task_registry::Task;
+use crate::fleet::{ConnectionStatus, Fleet};
+use crate::maneuver::{MoveError, MoveFailure};
 use crate::turtle::{DeltaInventory, Position, TurtleState};
-use std::collections::HashMap;
+use crate::turtle_ids::{OperatorToken, TurtleIds};
+use crate::turtle_rest;
+use crate::world_map::BlockUpdate;
 
 pub enum Command {
-    Eval(String),
-    AnonTask(JsonValue),
+    /// Lua code and the APIs it may use
+    Eval(String, Vec<String>),
     Task(Task),
     Move(String),
+    /// Asks the turtle to report its full state with a `state_update`
+    State,
 }
 
 impl Command {
     pub fn code(&self) -> &'static str {
         match self {
-            Command::Eval(_) => "EVAL",
-            Command::AnonTask(_) | Command::Task(_) => "TASK",
-            Command::Move(_) => "MOVE"
+            Command::Eval(_, _) => "EVAL",
+            Command::Task(_) => "TASK",
+            Command::Move(_) => "MOVE",
+            Command::State => "STATE",
         }
     }
 
@@ -38,9 +54,13 @@ impl Command {
         json::object! {
                     c: code,
                     b: match self {
-                        Command::Eval(s) | Command::Move(s) => JsonValue::from(s),
-                        Command::AnonTask(jv) => jv,
+                        Command::Eval(code, allow) => json::object! {
+                            code: code,
+                            allow: allow,
+                        },
+                        Command::Move(s) => JsonValue::from(s),
                         Command::Task(t) => (&t).into(),
+                        Command::State => JsonValue::Null,
                     },
         }
     }
@@ -49,46 +69,76 @@ impl Command {
 #[derive(Debug)]
 pub enum UpEvent {
     TaskError(TaskError),
-    TaskQuestion(String),
-    EvalResponse(JsonValue),
-    MoveResponse(Result<(), (String, usize)>),
+    TaskQuestion(TaskQuestion),
+    EvalResponse(EvalResponse),
+    /// A failed move has the 1-based string index of the step that failed
+    MoveResponse(Result<(), (MoveError, usize)>),
     TaskFinish,
     TaskCancelled,
     StateUpdate(TurtleState),
     PositionUpdate(Position),
     InventoryUpdate(DeltaInventory),
+    BlockUpdate(BlockUpdate),
     Error,
 }
 
-impl From<&JsonValue> for UpEvent {
-    fn from(jv: &JsonValue) -> Self {
-        if let JsonValue::Object(o) = jv {
-            if let Some(code) = o["c"].as_str() {
-                match code {
-                    "task_error" => UpEvent::TaskError(TaskError::from(&o["b"])),
-                    "task_question" => UpEvent::TaskQuestion(o["b"].as_str().unwrap().to_owned()),
-                    "eval_response" => UpEvent::EvalResponse(o["b"].clone()),
-                    "move_response" => {
-                        if jv.has_key("b") {
-                            UpEvent::MoveResponse(Err((o["b"]["e"].as_str().unwrap().to_owned(), o["b"]["c"].as_usize().unwrap())))
-                        } else {
-                            UpEvent::MoveResponse(Ok(()))
-                        }
-                    }
-                    "task_finish" => UpEvent::TaskFinish,
-                    "task_cancelled" => UpEvent::TaskCancelled,
-                    "state_update" => UpEvent::StateUpdate(TurtleState::from(&o["b"])),
-                    "position_update" => UpEvent::PositionUpdate(Position::from(&o["b"])),
-                    "inventory_update" => UpEvent::InventoryUpdate(DeltaInventory::from(&o["b"])),
-                    "error" => UpEvent::Error,
-                    _ => panic!("Unknown event code {}", code)
+impl UpEvent {
+    pub fn code(&self) -> &'static str {
+        match self {
+            UpEvent::TaskError(_) => "task_error",
+            UpEvent::TaskQuestion(_) => "task_question",
+            UpEvent::EvalResponse(_) => "eval_response",
+            UpEvent::MoveResponse(_) => "move_response",
+            UpEvent::TaskFinish => "task_finish",
+            UpEvent::TaskCancelled => "task_cancelled",
+            UpEvent::StateUpdate(_) => "state_update",
+            UpEvent::PositionUpdate(_) => "position_update",
+            UpEvent::InventoryUpdate(_) => "inventory_update",
+            UpEvent::BlockUpdate(_) => "block_update",
+            UpEvent::Error => "error",
+        }
+    }
+
+    /// Whether the event only tells us something changed about the turtle or the world
+    pub fn is_update(&self) -> bool {
+        matches!(self, UpEvent::StateUpdate(_) | UpEvent::PositionUpdate(_) | UpEvent::InventoryUpdate(_) | UpEvent::BlockUpdate(_))
+    }
+}
+
+impl TryFrom<&JsonValue> for UpEvent {
+    type Error = DecodeError;
+
+    fn try_from(jv: &JsonValue) -> Result<Self, DecodeError> {
+        expect_object(jv)?;
+        let code = field_with(jv, "c", expect_str)?;
+        Ok(match code {
+            "task_error" => UpEvent::TaskError(field(jv, "b")?),
+            "task_question" => UpEvent::TaskQuestion(field(jv, "b")?),
+            "eval_response" => UpEvent::EvalResponse(field(jv, "b")?),
+            "move_response" => {
+                if jv.has_key("b") {
+                    let b = &jv["b"];
+                    let message = field_with(b, "e", expect_str).map_err(|e| e.at("b"))?.to_owned();
+                    let completed = field_with(b, "c", expect_usize).map_err(|e| e.at("b"))?;
+                    // Unknown commands have no reason
+                    let failure = match &b["r"] {
+                        JsonValue::Null => MoveFailure::Other,
+                        r => MoveFailure::from_code(expect_str(r).map_err(|e| e.at("r").at("b"))?),
+                    };
+                    UpEvent::MoveResponse(Err((MoveError::new(failure, message), completed)))
+                } else {
+                    UpEvent::MoveResponse(Ok(()))
                 }
-            } else {
-                panic!("Expect event code string, got {}", o["c"])
             }
-        } else {
-            panic!("Expected json object, got {}", jv)
-        }
+            "task_finish" => UpEvent::TaskFinish,
+            "task_cancelled" => UpEvent::TaskCancelled,
+            "state_update" => UpEvent::StateUpdate(field(jv, "b")?),
+            "position_update" => UpEvent::PositionUpdate(field(jv, "b")?),
+            "inventory_update" => UpEvent::InventoryUpdate(field(jv, "b")?),
+            "block_update" => UpEvent::BlockUpdate(field(jv, "b")?),
+            "error" => UpEvent::Error,
+            _ => return Err(DecodeError::unknown_code("event code", code).at("c"))
+        })
     }
 }
 
@@ -122,6 +172,80 @@ impl Into<JsonValue> for &TaskCommand {
     }
 }
 
+/// What a `Command::Eval` returned: whether the code ran without error, and the values it
+/// returned or the error
+#[derive(Debug)]
+pub struct EvalResponse {
+    pub success: bool,
+    pub values: Vec<JsonValue>,
+}
+
+impl TryFrom<&JsonValue> for EvalResponse {
+    type Error = DecodeError;
+
+    /// Decodes the result of `table.pack(pcall(code))`, which json.lua sends as an array, or the
+    /// error message if the code could not be loaded
+    fn try_from(jv: &JsonValue) -> Result<Self, Self::Error> {
+        match jv {
+            JsonValue::String(_) | JsonValue::Short(_) => Ok(Self { success: false, values: vec![jv.clone()] }),
+            JsonValue::Array(a) if !a.is_empty() => Ok(Self {
+                success: expect_bool(&a[0]).map_err(|e| e.at_index(0))?,
+                values: a[1..].to_vec(),
+            }),
+            _ => Err(DecodeError::expected("packed pcall result or error message", jv)),
+        }
+    }
+}
+
+impl fmt::Display for EvalResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", if self.success { "ok" } else { "error" })?;
+        self.values.iter().try_for_each(|v| write!(f, " {}", v))
+    }
+}
+
+/// A question of a running task: which question it is and what the task tells about it, sent as
+/// `{"q": "replant", "b": payload}`
+#[derive(Clone, Debug)]
+pub struct TaskQuestion {
+    pub code: String,
+    pub payload: JsonValue,
+}
+
+impl TryFrom<&JsonValue> for TaskQuestion {
+    type Error = DecodeError;
+
+    fn try_from(jv: &JsonValue) -> Result<Self, Self::Error> {
+        match jv {
+            // Scripts from before questions had payloads only send the code
+            JsonValue::String(_) | JsonValue::Short(_) => Ok(Self { code: expect_str(jv)?.to_owned(), payload: JsonValue::Null }),
+            _ => Ok(Self {
+                code: field_with(jv, "q", expect_str)?.to_owned(),
+                payload: jv["b"].clone(),
+            }),
+        }
+    }
+}
+
+impl From<&TaskQuestion> for JsonValue {
+    fn from(question: &TaskQuestion) -> Self {
+        json::object! {
+            q: question.code.as_str(),
+            b: question.payload.clone(),
+        }
+    }
+}
+
+impl fmt::Display for TaskQuestion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.code)?;
+        if !self.payload.is_null() {
+            write!(f, " {}", self.payload)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub enum TaskError {
     FuelLow,
@@ -129,123 +253,525 @@ pub enum TaskError {
 }
 
 impl TaskError {
-    pub fn from_code(s: &str) -> Self {
+    pub fn from_code(s: &str) -> Option<Self> {
         match s {
-            "fuel" => Self::FuelLow,
-            "obstacle" => Self::Obstacle,
-            _ => panic!("Unknown task error code {}", s),
+            "fuel" => Some(Self::FuelLow),
+            "obstacle" => Some(Self::Obstacle),
+            _ => None,
         }
     }
-}
 
-impl From<&JsonValue> for TaskError {
-    fn from(jv: &JsonValue) -> Self {
-        if jv.is_string() {
-            Self::from_code(jv.as_str().unwrap())
-        } else {
-            panic!("Expected json string, got {}", jv)
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::FuelLow => "fuel",
+            Self::Obstacle => "obstacle",
         }
     }
 }
 
-pub fn spawn_websocket_listener() -> Result<(mpsc::Receiver<TurtleConnection>, JoinHandle<()>), Box<dyn std::error::Error>> {
+impl TryFrom<&JsonValue> for TaskError {
+    type Error = DecodeError;
+
+    fn try_from(jv: &JsonValue) -> Result<Self, Self::Error> {
+        let code = expect_str(jv)?;
+        Self::from_code(code).ok_or_else(|| DecodeError::unknown_code("task error code", code))
+    }
+}
+
+pub fn spawn_websocket_listener(fleet: Fleet, ids: Arc<Mutex<TurtleIds>>) -> Result<(mpsc::Receiver<TurtleConnection>, JoinHandle<()>), Box<dyn std::error::Error>> {
     let address = env::var("ADDRESS").unwrap_or(String::from("localhost"));
     let port = env::var("PORT").unwrap_or(String::from("17576"));
 
-    let server: Server<NoTlsAcceptor> = Server::bind(format!("{}:{}", address, port))?;
+    let listener = TcpListener::bind(format!("{}:{}", address, port))?;
+    spawn_listener(listener, fleet, ids, OperatorToken::from_env(), Timeouts::from_env())
+}
+
+/// How long the blocking side waits for a message before giving the caller a chance to do other work
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// How many sent messages are kept for resending until the turtle acknowledges them
+const RESEND_BUFFER: usize = 256;
+
+/// Header with the secret that was handed out with the id of the turtle
+pub const SECRET_HEADER: &str = "x-turtle-secret";
 
+/// When the listener gives up on a turtle. Read from `HEARTBEAT_INTERVAL`, `HEARTBEAT_TIMEOUT` and
+/// `RECONNECT_TIMEOUT` in seconds, the defaults are 15, 45 and 600.
+#[derive(Copy, Clone, Debug)]
+pub struct Timeouts {
+    /// How often we ping a connected turtle
+    pub heartbeat_interval: Duration,
+    /// A connection that sent nothing, not even a pong, for this long is considered dead
+    pub heartbeat_timeout: Duration,
+    /// How long a turtle may be disconnected (e.g. in an unloaded chunk) before we give up on it
+    pub reconnect_timeout: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval: Duration::from_secs(15),
+            heartbeat_timeout: Duration::from_secs(45),
+            reconnect_timeout: Duration::from_secs(600),
+        }
+    }
+}
+
+impl Timeouts {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let var = |name: &str, default: Duration| env::var(name).ok().and_then(|s| s.parse().ok()).map_or(default, Duration::from_secs);
+        Self {
+            heartbeat_interval: var("HEARTBEAT_INTERVAL", defaults.heartbeat_interval),
+            heartbeat_timeout: var("HEARTBEAT_TIMEOUT", defaults.heartbeat_timeout),
+            reconnect_timeout: var("RECONNECT_TIMEOUT", defaults.reconnect_timeout),
+        }
+    }
+}
+
+type Socket = WebSocketStream<Upgraded>;
+
+/// State shared by all requests to the listener
+struct Listener {
+    fleet: Fleet,
+    ids: Arc<Mutex<TurtleIds>>,
+    operator: OperatorToken,
+    /// Where to hand over the socket when a turtle with a live connection reconnects
+    reconnects: Mutex<HashMap<u32, async_mpsc::UnboundedSender<Socket>>>,
+    connections: Mutex<mpsc::Sender<TurtleConnection>>,
+    timeouts: Timeouts,
+}
+
+/// Accepts turtles and http requests on an already bound listener. The sockets of all turtles are
+/// served by an async runtime on a separate thread, so a dead turtle only costs a task there. Each
+/// new turtle comes out of the receiver as a blocking `TurtleConnection`, for a runner on its own
+/// thread.
+pub fn spawn_listener(listener: TcpListener, fleet: Fleet, ids: Arc<Mutex<TurtleIds>>, operator: OperatorToken, timeouts: Timeouts) -> Result<(mpsc::Receiver<TurtleConnection>, JoinHandle<()>), Box<dyn std::error::Error>> {
     let (tx, rx) = mpsc::channel();
+    let state = Arc::new(Listener {
+        fleet,
+        ids,
+        operator,
+        reconnects: Mutex::new(HashMap::new()),
+        connections: Mutex::new(tx),
+        timeouts,
+    });
 
+    let runtime = tokio::runtime::Runtime::new()?;
+    listener.set_nonblocking(true)?;
+    let server = {
+        let _guard = runtime.enter();
+        Server::from_tcp(listener)?
+    };
     let handle = thread::spawn(move || {
-        let mut reconnect_map: HashMap<u32, mpsc::Sender<Client<TcpStream>>> = HashMap::new();
-
-        for connection in server.filter_map(Result::ok) {
-            let connection: Upgrade<TcpStream> = connection;
-
-            let req_uri = connection.request.subject.1.clone();
-            let hyper_uri = req_uri.to_string().parse::<uri::Uri>().unwrap();
-            let mut path_iter = hyper_uri.path().split("/");
-            let id =
-                path_iter.next().filter(|s| (*s).eq(""))
-                    .and_then(|_| path_iter.next()).filter(|s| (*s).eq("ws"))
-                    .and_then(|_| path_iter.next())
-                    .and_then(|s| s.parse::<u32>().ok())
-                    .ok_or(format!("Expected ws request on /ws/{{id}}, got {}", req_uri));
-            if let Ok(id) = id {
-                let client: Client<TcpStream> = connection.accept().unwrap();
-                if reconnect_map.contains_key(&id) {
-                    reconnect_map.get(&id).unwrap().send(client);
-                } else {
-                    let (recon_tx, recon_rx) = mpsc::channel();
-                    reconnect_map.insert(id, recon_tx);
-                    tx.send(TurtleConnection::new(client, recon_rx)).unwrap();
+        runtime.block_on(async move {
+            let make_service = make_service_fn(move |_| {
+                let state = Arc::clone(&state);
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |request| handle_request(request, Arc::clone(&state))))
                 }
-            } else {
-                eprintln!("{}", id.unwrap_err())
+            });
+            if let Err(e) = server.serve(make_service).await {
+                eprintln!("Listener stopped: {}", e);
+            }
+        });
+    });
+
+    Ok((rx, handle))
+}
+
+async fn handle_request(request: Request<Body>, state: Arc<Listener>) -> Result<Response<Body>, Infallible> {
+    let mut path_iter = request.uri().path().split('/');
+    let id = path_iter.next().filter(|s| s.is_empty())
+        .and_then(|_| path_iter.next()).filter(|s| *s == "ws")
+        .and_then(|_| path_iter.next());
+    let id = match id {
+        // Not a websocket upgrade, serve it as a plain http request
+        None => return Ok(turtle_rest::handle_http_request(request, Arc::clone(&state.ids), &state.operator, state.fleet.clone()).await),
+        Some(id) => id.parse::<u32>(),
+    };
+    let key = request.headers().get(SEC_WEBSOCKET_KEY).map(|k| derive_accept_key(k.as_bytes()));
+    let (id, key) = match (id, key) {
+        (Ok(id), Some(key)) => (id, key),
+        _ => {
+            eprintln!("Expected ws request on /ws/{{id}}, got {}", request.uri());
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from("Expected websocket upgrade on /ws/{id}"))
+                .unwrap());
+        }
+    };
+
+    let secret = request.headers().get(SECRET_HEADER).and_then(|s| s.to_str().ok());
+    if !secret.is_some_and(|s| state.ids.lock().unwrap().verify(id, s)) {
+        eprintln!("Rejected connection for turtle {} without its secret", id);
+        return Ok(Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(Body::from("Missing or wrong secret for this id"))
+            .unwrap());
+    }
+
+    tokio::spawn(async move {
+        match hyper::upgrade::on(request).await {
+            Ok(upgraded) => {
+                let socket = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+                state.accept(id, socket);
             }
+            Err(e) => eprintln!("Websocket upgrade for turtle {} failed: {}", id, e),
         }
     });
+    Ok(Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(UPGRADE, "websocket")
+        .header(CONNECTION, "Upgrade")
+        .header(SEC_WEBSOCKET_ACCEPT, key)
+        .body(Body::empty())
+        .unwrap())
+}
+
+impl Listener {
+    /// Hands the socket to the connection of the turtle, or starts a new connection
+    fn accept(&self, id: u32, socket: Socket) {
+        self.fleet.connected(id);
+        let mut reconnects = self.reconnects.lock().unwrap();
+        let socket = match reconnects.get(&id) {
+            Some(reconnect) => match reconnect.send(socket) {
+                Ok(()) => return,
+                // The old connection gave up, start over
+                Err(async_mpsc::error::SendError(socket)) => socket,
+            },
+            None => socket,
+        };
+
+        let (reconnect_tx, reconnect_rx) = async_mpsc::unbounded_channel();
+        let (outbound_tx, outbound_rx) = async_mpsc::unbounded_channel();
+        let (inbound_tx, inbound_rx) = mpsc::channel();
+        reconnects.insert(id, reconnect_tx);
+        tokio::spawn(serve_connection(id, socket, reconnect_rx, outbound_rx, inbound_tx, self.fleet.clone(), self.timeouts));
+        let connection = TurtleConnection {
+            id,
+            outbound: outbound_tx,
+            inbound: inbound_rx,
+            // Commands of a new connection must not be taken for those of a server that ran before
+            last_id: Wrapping(rand::thread_rng().gen_range(1..1 << 31)),
+            pending: HashMap::new(),
+            replies: HashMap::new(),
+            buffered: VecDeque::new(),
+        };
+        if self.connections.lock().unwrap().send(connection).is_err() {
+            eprintln!("Nobody is accepting turtle connections, dropping turtle {}", id);
+        }
+    }
+}
+
+/// Waits for the next frame, or forever if there is no socket
+async fn next_frame(socket: &mut Option<Socket>) -> Option<tungstenite::Result<Message>> {
+    match socket {
+        Some(socket) => socket.next().await,
+        None => futures_util::future::pending().await,
+    }
+}
+
+/// What to do with a message that came in over the socket
+enum Received {
+    /// A new message for the `TurtleConnection`
+    New(String),
+    /// A message we already passed on before a reconnect, only needs to be acknowledged again
+    Duplicate,
+    /// An `ack`, which is handled by the connection itself
+    Control,
+    /// A `hello` of the session we know, or the first one: what the turtle missed can be resent
+    Resumed,
+    /// A `hello` with a new session, the turtle rebooted and lost whatever task it was running.
+    /// What it did not acknowledge is dropped, it belongs to the old session.
+    Restarted,
+}
+
+/// Keeps both sides in sync across reconnects. Every message carries a `mid` that counts up, the
+/// receiver acknowledges it with an `ACK` and drops messages it has seen before. After a
+/// reconnect we send a `HELLO` that tells the turtle which of its messages we already have, and
+/// wait for its `hello` before sending anything. If that has the session we know, whatever was not
+/// acknowledged is sent again. A different session means the turtle rebooted and counts from the
+/// start again, so our unacknowledged messages are dropped instead of run a second time. The
+/// turtle does the same when our `HELLO` has a new session, so a restarted server does not get
+/// the messages meant for the one before. Each `hello` names the session its `ack` belongs to, an
+/// ack of an older session is ignored.
+struct Sequencing {
+    id: u32,
+    session: u64,
+    /// Sent messages the turtle did not acknowledge yet, oldest first
+    unacked: VecDeque<(u32, String)>,
+    last_received: Option<u32>,
+    turtle_session: Option<u64>,
+    /// Whether the turtle said `hello` on the current socket, until then nothing is sent
+    greeted: bool,
+}
+
+impl Sequencing {
+    fn new(id: u32) -> Self {
+        let session = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+        Self { id, session, unacked: VecDeque::new(), last_received: None, turtle_session: None, greeted: false }
+    }
+
+    fn hello(&self) -> Message {
+        Message::Text(json::stringify(json::object! {
+            c: "HELLO",
+            session: self.session,
+            ack: self.last_received,
+            ack_session: self.turtle_session,
+        }))
+    }
+
+    fn ack(&self) -> Message {
+        Message::Text(json::stringify(json::object! {
+            c: "ACK",
+            ack: self.last_received,
+        }))
+    }
+
+    /// Keeps a message until it is acknowledged, dropping the oldest one when the buffer is full
+    fn sent(&mut self, mid: u32, message: String) {
+        if self.unacked.len() >= RESEND_BUFFER {
+            if let Some((dropped, _)) = self.unacked.pop_front() {
+                eprintln!("Resend buffer of turtle {} is full, message {} will not be resent", self.id, dropped);
+            }
+        }
+        self.unacked.push_back((mid, message));
+    }
+
+    fn acknowledged(&mut self, ack: Option<u32>) {
+        if let Some(ack) = ack {
+            self.unacked.retain(|(mid, _)| *mid > ack);
+        }
+    }
+
+    fn received(&mut self, message: String) -> Received {
+        let jv = match json::parse(message.as_str()) {
+            Ok(jv) => jv,
+            // Not ours to judge, the `TurtleConnection` reports it
+            Err(_) => return Received::New(message),
+        };
+        match jv["c"].as_str() {
+            Some("ack") => {
+                self.acknowledged(jv["ack"].as_u32());
+                return Received::Control;
+            }
+            Some("hello") => {
+                let session = jv["session"].as_u64();
+                let restarted = self.turtle_session.is_some() && session != self.turtle_session;
+                self.greeted = true;
+                if session != self.turtle_session {
+                    self.turtle_session = session;
+                    self.last_received = None;
+                }
+                if restarted {
+                    println!("Turtle {} restarted, dropping {} unacknowledged messages", self.id, self.unacked.len());
+                    self.unacked.clear();
+                    return Received::Restarted;
+                }
+                if jv["ack_session"].as_u64() == Some(self.session) {
+                    self.acknowledged(jv["ack"].as_u32());
+                }
+                return Received::Resumed;
+            }
+            _ => {}
+        }
+        match jv["mid"].as_u32() {
+            Some(mid) if self.last_received.is_some_and(|last| mid <= last) => Received::Duplicate,
+            Some(mid) => {
+                self.last_received = Some(mid);
+                Received::New(message)
+            }
+            None => Received::New(message),
+        }
+    }
+
+    /// Greets a new socket, the turtle answers with its own `hello`
+    async fn greet(&mut self, socket: &mut Socket) -> tungstenite::Result<()> {
+        self.greeted = false;
+        socket.send(self.hello()).await
+    }
+
+    /// Sends everything the turtle has not acknowledged yet
+    async fn resend(&self, socket: &mut Socket) -> tungstenite::Result<()> {
+        for (_, message) in &self.unacked {
+            socket.feed(Message::Text(message.clone())).await?;
+        }
+        socket.flush().await
+    }
+}
+
+/// Moves messages between the socket and the `TurtleConnection` of a turtle. Messages sent while
+/// the turtle is disconnected are kept with the unacknowledged ones until it reconnects. Ends
+/// when the `TurtleConnection` is dropped, or when the turtle has not reconnected within the
+/// reconnect timeout.
+async fn serve_connection(id: u32, socket: Socket, mut reconnects: async_mpsc::UnboundedReceiver<Socket>,
+                          mut outbound: async_mpsc::UnboundedReceiver<(u32, String)>, inbound: mpsc::Sender<String>,
+                          fleet: Fleet, timeouts: Timeouts) {
+    let mut sequencing = Sequencing::new(id);
+    let mut socket = Some(socket);
+    // Whether the socket is new and has to be greeted
+    let mut greet = true;
+    let mut online = true;
+    let mut heartbeat = tokio::time::interval(timeouts.heartbeat_interval);
+    let mut last_heard = Instant::now();
+    let mut disconnected_at = Instant::now();
+
+    loop {
+        if greet {
+            if let Some(s) = socket.as_mut() {
+                if let Err(e) = sequencing.greet(s).await {
+                    eprintln!("Could not send to turtle {}: {}", id, e);
+                    socket = None;
+                }
+            }
+            greet = false;
+        }
+
+        tokio::select! {
+            message = outbound.recv() => match message {
+                Some((mid, message)) => {
+                    sequencing.sent(mid, message.clone());
+                    // Until the turtle said hello it is not clear whether the message is still
+                    // for this turtle, it is sent with the rest once it is
+                    if let Some(s) = socket.as_mut().filter(|_| sequencing.greeted) {
+                        if let Err(e) = s.send(Message::Text(message)).await {
+                            eprintln!("Could not send to turtle {}: {}", id, e);
+                            socket = None;
+                        }
+                    }
+                }
+                None => break,
+            },
+            frame = next_frame(&mut socket) => {
+                last_heard = Instant::now();
+                match frame {
+                    Some(Ok(Message::Text(message))) => {
+                        fleet.seen(id);
+                        let message = match sequencing.received(message) {
+                            Received::New(message) => Some(message),
+                            Received::Duplicate => None,
+                            Received::Control => continue,
+                            Received::Resumed => {
+                                if let Some(s) = socket.as_mut() {
+                                    if let Err(e) = sequencing.resend(s).await {
+                                        eprintln!("Could not send to turtle {}: {}", id, e);
+                                        socket = None;
+                                    }
+                                }
+                                None
+                            }
+                            Received::Restarted => {
+                                fleet.restarted(id);
+                                continue;
+                            }
+                        };
+                        if let Some(s) = socket.as_mut() {
+                            let _ = s.send(sequencing.ack()).await;
+                        }
+                        if message.is_some_and(|m| inbound.send(m).is_err()) {
+                            break;
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | None => socket = None,
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => {
+                        eprintln!("Websocket of turtle {} got error: {}", id, e);
+                        socket = None;
+                    }
+                }
+            },
+            Some(new_socket) = reconnects.recv() => {
+                println!("Turtle {} reconnected", id);
+                socket = Some(new_socket);
+                greet = true;
+                online = true;
+                last_heard = Instant::now();
+            },
+            _ = heartbeat.tick() => match socket.as_mut() {
+                Some(_) if last_heard.elapsed() > timeouts.heartbeat_timeout => {
+                    eprintln!("Turtle {} stopped responding", id);
+                    socket = None;
+                }
+                Some(s) => {
+                    let _ = s.send(Message::Ping(Vec::new())).await;
+                }
+                None if disconnected_at.elapsed() > timeouts.reconnect_timeout => {
+                    eprintln!("Turtle {} did not reconnect, giving up", id);
+                    break;
+                }
+                None => {}
+            },
+        }
 
-    return Ok((rx, handle));
+        if socket.is_none() && online {
+            online = false;
+            fleet.set_status(id, ConnectionStatus::Reconnecting);
+            disconnected_at = Instant::now();
+        }
+    }
+
+    if let Some(mut s) = socket {
+        let _ = s.close(None).await;
+    }
+    fleet.set_status(id, ConnectionStatus::Gone);
 }
 
 #[derive(Debug)]
 pub enum ReceiveError {
-    WebsocketError(WebSocketError),
+    /// The turtle is gone and will not come back on this connection
+    Disconnected,
     MessageError(String),
+    /// Nothing was received within the poll interval, gives the caller a chance to do other work
+    Timeout,
+    /// The command with this mid was not answered before its deadline
+    CommandTimeout(u32),
 }
 
+/// Blocking handle to the connection of a turtle, for the executor. Reconnects are handled by the
+/// listener, so messages just wait until the turtle is back. Only the sockets are async: the
+/// executor and its runner keep one OS thread per turtle, which `receive` wakes up every
+/// `POLL_INTERVAL` and which learns from `ReceiveError::Disconnected` that the turtle is gone.
 pub struct TurtleConnection {
-    ws_client: Client<TcpStream>,
-    reconnect_receiver: mpsc::Receiver<Client<TcpStream>>,
+    id: u32,
+    outbound: async_mpsc::UnboundedSender<(u32, String)>,
+    inbound: mpsc::Receiver<String>,
     last_id: Wrapping<u32>,
+    /// Deadlines of the commands that await a reply, by mid
+    pending: HashMap<u32, Instant>,
+    /// Replies that arrived while awaiting another command, by the mid they answer
+    replies: HashMap<u32, UpEvent>,
+    /// Events that are not a reply, kept for `receive_event` in the order they arrived
+    buffered: VecDeque<(UpEvent, u32, u32)>,
 }
 
 
 impl TurtleConnection {
-    pub fn new(client: Client<TcpStream>, reconnect_receiver: mpsc::Receiver<Client<TcpStream>>) -> Self {
-        Self {
-            ws_client: client,
-            reconnect_receiver,
-            last_id: Wrapping(1u32),
-        }
+    pub fn id(&self) -> u32 {
+        self.id
     }
 
-    pub fn send(&mut self, message: String) {
+    fn send(&mut self, mid: u32, message: String) {
         println!("Sending: {}", message);
-        loop {
-            match self.ws_client.send_message(&Message::text(&message)) {
-                Ok(()) => return,
-                Err(e) => {
-                    eprintln!("Websocket receive got error: {}", e);
-                    self.ws_client = self.reconnect_receiver.recv().unwrap();
-                }
-            }
+        if self.outbound.send((mid, message)).is_err() {
+            eprintln!("Turtle {} is gone, message was not sent", self.id);
         }
-
     }
 
     pub fn receive(&mut self) -> Result<String, ReceiveError> {
-        loop {
-            match self.ws_client.recv_message() {
-                Ok(OwnedMessage::Text(m)) => {
-                    println!("Received: {}", m);
-                    return Ok(m)
-                },
-                Ok(_) => return Err(ReceiveError::MessageError("Got unexpected non text message".into())),
-                Err(e) => {
-                    eprintln!("Websocket receive got error: {}", e);
-                    self.ws_client = self.reconnect_receiver.recv().unwrap();
-                    println!("Reconnected");
-                }
+        match self.inbound.recv_timeout(POLL_INTERVAL) {
+            Ok(m) => {
+                println!("Received: {}", m);
+                Ok(m)
             }
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(ReceiveError::Timeout),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(ReceiveError::Disconnected),
         }
     }
 
     pub fn send_command(&mut self, command: Command) -> u32 {
         let mid = self.last_id.0;
         self.last_id += Wrapping(1u32);
-        self.send(json::stringify(
+        self.send(mid, json::stringify(
             json::object! {
                 mid: mid,
                 cid: 0,
@@ -259,7 +785,7 @@ impl TurtleConnection {
         let mid = self.last_id.0;
         self.last_id += Wrapping(1u32);
 
-        self.send(json::stringify(
+        self.send(mid, json::stringify(
             json::object! {
                 mid: mid,
                 cid: cid,
@@ -269,17 +795,210 @@ impl TurtleConnection {
         mid
     }
 
+    /// Sends a command that is answered with a single event carrying its mid as `cid`, like
+    /// `eval_response`. The answer is picked up with `receive_reply`.
+    pub fn send_request(&mut self, command: Command, timeout: Duration) -> u32 {
+        let mid = self.send_command(command);
+        self.pending.insert(mid, Instant::now() + timeout);
+        mid
+    }
+
+    /// Waits for the reply to a command sent with `send_request`. Other events that arrive in the
+    /// meantime are buffered for `receive_event`, replies to other commands for their own
+    /// `receive_reply`. Gives up with `Timeout` after the poll interval, so it has to be called
+    /// in a loop.
+    pub fn receive_reply(&mut self, mid: u32) -> Result<UpEvent, ReceiveError> {
+        loop {
+            if let Some(event) = self.replies.remove(&mid) {
+                return Ok(event);
+            }
+            match self.pending.get(&mid) {
+                Some(deadline) if Instant::now() < *deadline => {}
+                _ => {
+                    self.pending.remove(&mid);
+                    return Err(ReceiveError::CommandTimeout(mid));
+                }
+            }
+            let (event, event_mid, cid) = self.receive_decoded()?;
+            if self.pending.remove(&cid).is_some() {
+                self.replies.insert(cid, event);
+            } else {
+                self.buffered.push_back((event, event_mid, cid));
+            }
+        }
+    }
+
+    /// Removes the buffered events matching the predicate and returns them, oldest first
+    pub fn take_buffered<P>(&mut self, predicate: P) -> Vec<UpEvent>
+        where P: Fn(&UpEvent) -> bool {
+        let (taken, kept) = self.buffered.drain(..).partition(|(e, _, _)| predicate(e));
+        self.buffered = kept;
+        taken.into_iter().map(|(e, _, _)| e).collect()
+    }
+
+    /// Receives the next event that is not the reply to a pending command, buffered events first
     pub fn receive_event(&mut self) -> Result<(UpEvent, u32, u32), ReceiveError> {
+        if let Some(event) = self.buffered.pop_front() {
+            return Ok(event);
+        }
+        let now = Instant::now();
+        self.pending.retain(|_, deadline| *deadline > now);
+        loop {
+            let (event, mid, cid) = self.receive_decoded()?;
+            if self.pending.remove(&cid).is_some() {
+                self.replies.insert(cid, event);
+            } else {
+                return Ok((event, mid, cid));
+            }
+        }
+    }
+
+    fn receive_decoded(&mut self) -> Result<(UpEvent, u32, u32), ReceiveError> {
         let s = self.receive()?;
         let jv = json::parse(s.as_str())
             .map_err(|_| ReceiveError::MessageError(format!("Could not parse json string {}", s)))?;
         if let JsonValue::Object(_) = &jv {
             let mid: u32 = jv["mid"].as_u32().unwrap_or(0);
             let cid: u32 = jv["cid"].as_u32().unwrap_or(0);
-            // TODO parse from Object i/o JsonValue
-            return Ok((UpEvent::from(&jv), mid, cid));
+            let event = UpEvent::try_from(&jv)
+                .map_err(|e| ReceiveError::MessageError(format!("Could not decode event {}: {}", s, e)))?;
+            Ok((event, mid, cid))
         } else {
-            return Err(ReceiveError::MessageError(format!("Expected json object, got {:?}", jv)));
+            Err(ReceiveError::MessageError(format!("Expected json object, got {:?}", jv)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpStream;
+
+    use tungstenite::client::IntoClientRequest;
+    use tungstenite::stream::MaybeTlsStream;
+    use tungstenite::WebSocket;
+
+    use super::*;
+
+    fn listen(timeouts: Timeouts) -> (String, String, Fleet, mpsc::Receiver<TurtleConnection>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/ws/1", listener.local_addr().unwrap());
+        let fleet = Fleet::default();
+        let mut ids = TurtleIds::default();
+        let secret = ids.secret(1).unwrap();
+        let (connections, _) = spawn_listener(listener, fleet.clone(), Arc::new(Mutex::new(ids)), OperatorToken::new(String::from("operator")), timeouts).unwrap();
+        (url, secret, fleet, connections)
+    }
+
+    fn connect(url: &str, secret: &str) -> WebSocket<MaybeTlsStream<TcpStream>> {
+        let mut request = url.into_client_request().unwrap();
+        request.headers_mut().insert(SECRET_HEADER, secret.parse().unwrap());
+        tungstenite::connect(request).unwrap().0
+    }
+
+    fn short_timeouts() -> Timeouts {
+        Timeouts {
+            heartbeat_interval: Duration::from_millis(50),
+            heartbeat_timeout: Duration::from_millis(300),
+            reconnect_timeout: Duration::from_millis(500),
+        }
+    }
+
+    /// Reads from the socket for a while, which also answers pings, and returns how many came in
+    fn read_for(client: &mut WebSocket<MaybeTlsStream<TcpStream>>, duration: Duration) -> usize {
+        if let MaybeTlsStream::Plain(stream) = client.get_mut() {
+            stream.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+        }
+        let deadline = Instant::now() + duration;
+        let mut pings = 0;
+        while Instant::now() < deadline {
+            match client.read_message() {
+                Ok(Message::Ping(_)) => pings += 1,
+                Ok(_) => {}
+                Err(tungstenite::Error::Io(e)) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => {}
+                Err(e) => panic!("Connection failed: {}", e),
+            }
+        }
+        pings
+    }
+
+    /// Waits until the fleet shows the turtle with the status, panics after a few seconds
+    fn wait_for_status(fleet: &Fleet, id: u32, status: ConnectionStatus) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while fleet.get(id).map(|r| r.status) != Some(status) {
+            assert!(Instant::now() < deadline, "Turtle {} is {:?}, expected {:?}", id, fleet.get(id).map(|r| r.status), status);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn move_responses_carry_why_they_failed() {
+        let cases = [
+            (r#"{"c": "move_response"}"#, None),
+            (r#"{"c": "move_response", "b": {"e": "Could not complete move f3 due to \"fuel\"", "c": 2, "r": "fuel"}}"#, Some((MoveFailure::Fuel, 2))),
+            (r#"{"c": "move_response", "b": {"e": "Could not complete move u due to \"obstacle\"", "c": 1, "r": "obstacle"}}"#, Some((MoveFailure::Obstacle, 1))),
+            (r#"{"c": "move_response", "b": {"e": "Unknown command x", "c": 3}}"#, Some((MoveFailure::Other, 3))),
+        ];
+        for (input, expected) in cases.iter() {
+            let result = match UpEvent::try_from(&json::parse(input).unwrap()) {
+                Ok(UpEvent::MoveResponse(r)) => r,
+                other => panic!("Expected a move response for {}, got {:?}", input, other),
+            };
+            assert_eq!(result.err().map(|(e, index)| (e.failure, index)), *expected, "{}", input);
         }
     }
+
+    #[test]
+    fn status_follows_the_connection() {
+        let (url, secret, fleet, connections) = listen(Timeouts::default());
+        let client = connect(&url, &secret);
+        let connection = connections.recv().unwrap();
+        wait_for_status(&fleet, 1, ConnectionStatus::Connected);
+
+        drop(client);
+        wait_for_status(&fleet, 1, ConnectionStatus::Reconnecting);
+
+        let _client = connect(&url, &secret);
+        wait_for_status(&fleet, 1, ConnectionStatus::Connected);
+        assert!(connections.try_recv().is_err(), "A reconnect must not start a new connection");
+
+        drop(connection);
+        wait_for_status(&fleet, 1, ConnectionStatus::Gone);
+    }
+
+    #[test]
+    fn heartbeat_keeps_a_turtle_that_answers() {
+        let (url, secret, fleet, connections) = listen(short_timeouts());
+        let mut client = connect(&url, &secret);
+        let _connection = connections.recv().unwrap();
+
+        // Answering the pings outlasts the heartbeat timeout a few times over
+        let pings = read_for(&mut client, Duration::from_millis(1000));
+        assert!(pings >= 5, "Expected a ping every 50ms, got {}", pings);
+        assert_eq!(fleet.get(1).unwrap().status, ConnectionStatus::Connected);
+
+        // A turtle that stops answering is dropped, but may still come back
+        wait_for_status(&fleet, 1, ConnectionStatus::Reconnecting);
+        let _client = connect(&url, &secret);
+        wait_for_status(&fleet, 1, ConnectionStatus::Connected);
+    }
+
+    #[test]
+    fn turtle_that_does_not_reconnect_is_gone() {
+        let (url, secret, fleet, connections) = listen(short_timeouts());
+        let client = connect(&url, &secret);
+        let mut connection = connections.recv().unwrap();
+
+        drop(client);
+        wait_for_status(&fleet, 1, ConnectionStatus::Reconnecting);
+        let reconnecting = Instant::now();
+        wait_for_status(&fleet, 1, ConnectionStatus::Gone);
+        assert!(reconnecting.elapsed() >= Duration::from_millis(400), "Gave up after {:?}", reconnecting.elapsed());
+        // The runner hears about it instead of waiting forever
+        assert!(matches!(connection.receive(), Err(ReceiveError::Disconnected)));
+
+        // Coming back later starts a new connection
+        let _client = connect(&url, &secret);
+        assert!(connections.recv_timeout(Duration::from_secs(5)).is_ok());
+        wait_for_status(&fleet, 1, ConnectionStatus::Connected);
+    }
 }
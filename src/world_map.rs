@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::{env, fs, io};
+
+use json::JsonValue;
+
+use crate::decode::{DecodeError, expect_array, expect_object, expect_str, field, field_with};
+use crate::turtle::Coordinate;
+
+/// What we know about a single block in the world
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KnownBlock {
+    Air,
+    /// Something is there, but we don't know what (e.g. a move ran into it)
+    Solid,
+    Named(String),
+}
+
+impl KnownBlock {
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "minecraft:air" => KnownBlock::Air,
+            name => KnownBlock::Named(name.to_owned()),
+        }
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            KnownBlock::Air => Some("minecraft:air"),
+            KnownBlock::Solid => None,
+            KnownBlock::Named(name) => Some(name.as_str()),
+        }
+    }
+}
+
+/// Block map built from what the turtles saw and where they went. Coordinates are the ones the
+/// turtles report, so all turtles are assumed to share the same origin.
+/// The map is stored as json in the file at `WORLD_FILE` (default `world_map.json`).
+#[derive(Default)]
+pub struct WorldMap {
+    /// Where the map is saved, `None` keeps it in memory only
+    path: Option<String>,
+    blocks: HashMap<Coordinate, KnownBlock>,
+    changed: bool,
+}
+
+impl WorldMap {
+    pub fn load() -> io::Result<Self> {
+        Self::open(env::var("WORLD_FILE").unwrap_or(String::from("world_map.json")))
+    }
+
+    /// Loads the map from the file, or starts an empty one that is saved there
+    fn open(path: String) -> io::Result<Self> {
+        let blocks = match fs::read_to_string(&path) {
+            Ok(s) => json::parse(s.as_str())
+                .map_err(|e| e.to_string())
+                .and_then(|jv| Self::decode_blocks(&jv).map_err(|e| e.to_string()))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Could not parse world file {}: {}", path, e)))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self { path: Some(path), blocks, changed: false })
+    }
+
+    pub fn get(&self, coordinate: Coordinate) -> Option<&KnownBlock> {
+        self.blocks.get(&coordinate)
+    }
+
+    pub fn set(&mut self, coordinate: Coordinate, block: KnownBlock) {
+        if self.blocks.get(&coordinate) != Some(&block) {
+            self.blocks.insert(coordinate, block);
+            self.changed = true;
+        }
+    }
+
+    /// A turtle was at the coordinate, so there is nothing there to dig
+    pub fn visited(&mut self, coordinate: Coordinate) {
+        self.set(coordinate, KnownBlock::Air);
+    }
+
+    /// All known blocks matching the predicate, e.g. to find logs or ores seen before
+    pub fn find<P>(&self, mut predicate: P) -> impl Iterator<Item=(Coordinate, &KnownBlock)>
+        where P: FnMut(&KnownBlock) -> bool {
+        self.blocks.iter()
+            .filter(move |(_, b)| predicate(b))
+            .map(|(c, b)| (*c, b))
+    }
+
+    /// Every known block as `{"x": 0, "y": 0, "z": 0, "block": name}`, the block is `null` when
+    /// only known to be solid
+    pub fn blocks_json(&self) -> JsonValue {
+        let blocks: Vec<JsonValue> = self.blocks.iter().map(|(coordinate, block)| {
+            let mut jv: JsonValue = (*coordinate).into();
+            jv["block"] = block.name().into();
+            jv
+        }).collect();
+        JsonValue::Array(blocks)
+    }
+
+    /// Writes the map to disk if it changed since the last save
+    pub fn save(&mut self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) if self.changed => path,
+            _ => return Ok(()),
+        };
+        let jv = json::object! {
+            blocks: self.blocks_json(),
+        };
+        fs::write(path, json::stringify(jv))?;
+        self.changed = false;
+        Ok(())
+    }
+
+    fn decode_blocks(jv: &JsonValue) -> Result<HashMap<Coordinate, KnownBlock>, DecodeError> {
+        field_with(jv, "blocks", expect_array)?.iter().enumerate()
+            .map(|(i, b)| Self::decode_block(b).map_err(|e| e.at_index(i).at("blocks")))
+            .collect()
+    }
+
+    fn decode_block(jv: &JsonValue) -> Result<(Coordinate, KnownBlock), DecodeError> {
+        expect_object(jv)?;
+        let block = match &jv["block"] {
+            JsonValue::Null => KnownBlock::Solid,
+            name => KnownBlock::from_name(expect_str(name).map_err(|e| e.at("block"))?),
+        };
+        Ok((Coordinate::try_from(jv)?, block))
+    }
+}
+
+/// A block a turtle inspected, reported with a `block_update` event
+#[derive(Debug)]
+pub struct BlockUpdate {
+    pub coordinate: Coordinate,
+    pub block: KnownBlock,
+}
+
+impl TryFrom<&JsonValue> for BlockUpdate {
+    type Error = DecodeError;
+
+    fn try_from(jv: &JsonValue) -> Result<Self, Self::Error> {
+        expect_object(jv)?;
+        let block = if jv.has_key("name") {
+            KnownBlock::from_name(field_with(jv, "name", expect_str)?)
+        } else {
+            KnownBlock::Air
+        };
+        Ok(Self {
+            coordinate: field(jv, "coordinate")?,
+            block,
+        })
+    }
+}
+
+impl From<&BlockUpdate> for JsonValue {
+    fn from(update: &BlockUpdate) -> Self {
+        json::object! {
+            coordinate: Into::<JsonValue>::into(update.coordinate),
+            name: update.block.name(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saved_map_loads_back() {
+        let path = env::temp_dir().join(format!("world_map_{}.json", std::process::id()));
+        let path = path.to_str().unwrap().to_owned();
+        let _ = fs::remove_file(&path);
+
+        let mut map = WorldMap::open(path.clone()).unwrap();
+        map.set(Coordinate::new(0, 0, 0), KnownBlock::Air);
+        map.set(Coordinate::new(1, -2, 3), KnownBlock::Solid);
+        map.set(Coordinate::new(-4, 5, 6), KnownBlock::from_name("minecraft:oak_log"));
+        map.save().unwrap();
+
+        let loaded = WorldMap::open(path.clone()).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.blocks, map.blocks);
+        assert_eq!(loaded.get(Coordinate::new(1, -2, 3)), Some(&KnownBlock::Solid));
+        assert!(!loaded.changed);
+    }
+
+    #[test]
+    fn missing_file_is_an_empty_map() {
+        let path = env::temp_dir().join(format!("world_map_missing_{}.json", std::process::id()));
+        let map = WorldMap::open(path.to_str().unwrap().to_owned()).unwrap();
+        assert!(map.blocks.is_empty());
+    }
+
+    #[test]
+    fn bad_blocks_are_errors() {
+        let cases = [
+            (r#"{}"#, "blocks"),
+            (r#"{"blocks": [{"x": 0, "y": 0, "z": 0, "block": 1}]}"#, "blocks[0].block"),
+            (r#"{"blocks": [{"x": 0, "y": 0, "block": null}]}"#, "blocks[0].z"),
+        ];
+        for (input, path) in cases.iter() {
+            let error = WorldMap::decode_blocks(&json::parse(input).unwrap()).unwrap_err();
+            assert!(error.to_string().contains(path), "{} should fail at {}, got {}", input, path, error);
+        }
+    }
+}